@@ -0,0 +1,6657 @@
+mod web;
+
+pub use self::web::{
+    DownloadFileConfig, HttpRequestConfig, SearchProvider, WebBrowseConfig, WebSearchConfig,
+};
+
+use crate::function::FunctionDeclaration;
+use crate::utils::{
+    apply_file_patch, html_to_md, parse_unified_diff, to_absolute_path, unified_diff, Hunk,
+    HunkLine, HunkResult, SHELL,
+};
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use encoding_rs::{Encoding, UTF_8};
+use globset::{GlobBuilder, GlobMatcher};
+use ignore::{WalkBuilder, WalkState};
+use indexmap::IndexMap;
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use serde_json_path::JsonPath;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+const COMMAND_RUN_TIMEOUT: u64 = 60;
+const COMMAND_RUN_MAX_OUTPUT: usize = 200 * 1024;
+const COMMAND_RUN_MAX_PROGRESS_LINE: usize = 4096;
+const COMMAND_SPAWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+/// The shells a tool call may pick; only the config may give a custom command line.
+const COMMAND_RUN_SHELLS: [&str; 10] = [
+    "none",
+    "auto",
+    "sh",
+    "bash",
+    "zsh",
+    "fish",
+    "nu",
+    "pwsh",
+    "powershell",
+    "cmd",
+];
+const FS_CAT_MAX_BYTES: usize = 200 * 1024;
+const FS_HEAD_TAIL_LINES: usize = 50;
+const FS_TAIL_CHUNK_SIZE: u64 = 64 * 1024;
+const FS_LS_MAX_DEPTH: usize = 3;
+const FS_LS_MAX_ENTRIES: usize = 500;
+const FS_TREE_MAX_DEPTH: usize = 3;
+const FS_TREE_MAX_ENTRIES: usize = 200;
+const FS_TREE_IGNORE: [&str; 3] = [".git", "node_modules", "target"];
+const FS_FIND_MAX_RESULTS: usize = 200;
+const FS_DIFF_MAX_BYTES: usize = 100_000;
+const FS_DIFF_MAX_INPUT_BYTES: u64 = 4 * 1024 * 1024;
+const GIT_LOG_LIMIT: usize = 20;
+const FS_SEARCH_MAX_RESULTS: usize = 200;
+const FS_SEARCH_MAX_FILE_SIZE: u64 = 1024 * 1024;
+const MAX_SEARCH_MATCHES_PER_FILE: usize = 20;
+const FS_PATCH_TAB_WIDTH: usize = 4;
+const CONFIRM_TOOLS: [&str; 12] = [
+    "command_run",
+    "command_spawn",
+    "fs_write",
+    "fs_append",
+    "fs_patch",
+    "fs_apply_patch",
+    "fs_rm",
+    "fs_mv",
+    "fs_cp",
+    "git_commit",
+    "http_request",
+    "download_file",
+];
+
+/// Settings for the builtin functions, read from the `builtin_functions` config section.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BuiltinConfig {
+    /// The default cap in bytes on the content `fs_cat` returns.
+    pub fs_cat_max_bytes: usize,
+    /// The default `command_run` timeout in seconds.
+    pub command_timeout: u64,
+    /// The default cap in bytes on each of `command_run`'s stdout and stderr.
+    pub command_max_output: usize,
+    /// Whether `command_run` shows its output line by line while it runs, when streaming.
+    pub stream_command_output: bool,
+    /// Confines the fs_* and git_* tools, and the working directory of `command_run` and
+    /// `command_spawn`, to this directory when set. Relative paths resolve against it, and
+    /// paths escaping it through `..` or symlinks are rejected. A command can still reach
+    /// files elsewhere by itself; use `command_run` rules or policy to restrict that.
+    pub fs_root: Option<PathBuf>,
+    /// The shell `command_run` runs commands through, such as `bash`, `pwsh`, `bash -lc`
+    /// or `auto` for the user's shell, preferring PowerShell on Windows. Without one,
+    /// commands run directly. Defaults to `auto` on Windows and to none elsewhere.
+    pub command_shell: Option<String>,
+    /// Restrictions on what `command_run` may execute.
+    pub command_run: CommandRules,
+    /// Per-tool confirmation policy for the tools that change files or run commands;
+    /// tools not listed here ask.
+    pub policy: HashMap<String, ToolPolicy>,
+    /// What `ask` becomes when there is no terminal to ask on, e.g. with piped stdin.
+    pub non_interactive_policy: ToolPolicy,
+    /// The provider `web_search` queries.
+    pub web_search: WebSearchConfig,
+    /// Limits on what `web_browse` returns.
+    pub web_browse: WebBrowseConfig,
+    /// The timeout and body cap of `http_request`.
+    pub http_request: HttpRequestConfig,
+    /// The size cap and timeout of `download_file`.
+    pub download_file: DownloadFileConfig,
+}
+
+impl Default for BuiltinConfig {
+    fn default() -> Self {
+        Self {
+            fs_cat_max_bytes: FS_CAT_MAX_BYTES,
+            command_timeout: COMMAND_RUN_TIMEOUT,
+            command_max_output: COMMAND_RUN_MAX_OUTPUT,
+            stream_command_output: true,
+            command_shell: cfg!(windows).then(|| "auto".into()),
+            fs_root: None,
+            command_run: Default::default(),
+            policy: HashMap::new(),
+            non_interactive_policy: ToolPolicy::Deny,
+            web_search: Default::default(),
+            web_browse: Default::default(),
+            http_request: Default::default(),
+            download_file: Default::default(),
+        }
+    }
+}
+
+/// Patterns matched against the `command_run` command string. A pattern is a glob, where `*`
+/// also matches spaces and slashes, or a regex when prefixed with `re:`.
+///
+/// When the command runs through a shell, it is also split at unquoted `;`, `&`, `|`,
+/// newlines and parentheses, and the rules are applied to each segment: `deny` refuses the
+/// command when any segment matches, and `allow` requires every segment to match. Command
+/// substitution such as `$(..)` or backticks cannot be checked this way, so it is refused
+/// while any rule is configured. This is a guard against mistakes, not a sandbox: a shell
+/// can still run arbitrary code through aliases, functions or scripts it reads.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CommandRules {
+    /// A command must match one of these to run; empty allows every command.
+    pub allow: Vec<String>,
+    /// A command matching any of these is refused, even when it is allowed.
+    pub deny: Vec<String>,
+    /// Command prefixes, such as `cargo` or `git status`, compared word by word with the
+    /// parsed command; when not empty, a command must start with one of them.
+    pub allow_programs: Vec<String>,
+    /// Command prefixes that block a command, checked before `allow_programs`.
+    pub deny_programs: Vec<String>,
+}
+
+impl CommandRules {
+    fn is_empty(&self) -> bool {
+        self.allow.is_empty()
+            && self.deny.is_empty()
+            && self.allow_programs.is_empty()
+            && self.deny_programs.is_empty()
+    }
+
+    /// Returns the refusal to hand back to the model when `command` may not run. `segments`
+    /// are the simple commands it runs, as returned by `command_segments`.
+    fn check(&self, command: &str, segments: &[String]) -> Result<Option<Value>> {
+        for pattern in &self.deny {
+            let mut matched = command_matches(pattern, command)?;
+            for segment in segments {
+                matched = matched || command_matches(pattern, segment)?;
+            }
+            if matched {
+                return Ok(Some(json!({
+                    "error": format!("Command blocked by deny rule `{pattern}`"),
+                    "blocked": true,
+                    "rule": { "list": "deny", "pattern": pattern },
+                })));
+            }
+        }
+        if self.allow.is_empty() {
+            return Ok(None);
+        }
+        for segment in segments {
+            let mut allowed = false;
+            for pattern in &self.allow {
+                allowed = allowed || command_matches(pattern, segment)?;
+            }
+            if !allowed {
+                return Ok(Some(json!({
+                    "error": "Command does not match any allow rule",
+                    "blocked": true,
+                    "rule": { "list": "allow", "pattern": null },
+                })));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Checks the parsed command against the program lists. The program is compared by its
+    /// file name, so `rm` also blocks `/bin/rm` but not `confirm`.
+    fn check_program(&self, words: &[String]) -> Option<Value> {
+        if let Some(refusal) = self.check_denied(words) {
+            return Some(refusal);
+        }
+        if self.allow_programs.is_empty()
+            || self
+                .allow_programs
+                .iter()
+                .any(|v| program_matches(v, words))
+        {
+            return None;
+        }
+        let program = words.first().map(|v| v.as_str()).unwrap_or_default();
+        Some(program_refusal(
+            &format!("`{program}` is not on the allowlist"),
+            json!({ "list": "allow_programs", "pattern": null }),
+        ))
+    }
+
+    /// Checks the parsed command against `deny_programs` only. The shell a command runs
+    /// through is checked this way, as `allow_programs` lists the programs run inside it.
+    fn check_denied(&self, words: &[String]) -> Option<Value> {
+        let prefix = self
+            .deny_programs
+            .iter()
+            .find(|v| program_matches(v, words))?;
+        Some(program_refusal(
+            &format!("`{prefix}` is denied"),
+            json!({ "list": "deny_programs", "pattern": prefix }),
+        ))
+    }
+}
+
+fn program_refusal(reason: &str, rule: Value) -> Value {
+    json!({
+        "error": format!("command blocked by policy: {reason}"),
+        "blocked": true,
+        "rule": rule,
+    })
+}
+
+/// Whether `word` is a `NAME=value` environment assignment preceding a shell command.
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=').is_some_and(|(name, _)| {
+        !name.is_empty()
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && name.chars().all(|c| c == '_' || c.is_ascii_alphanumeric())
+    })
+}
+
+fn program_matches(prefix: &str, words: &[String]) -> bool {
+    let Ok(prefix) = shell_words::split(prefix) else {
+        return false;
+    };
+    let Some((program, rest)) = prefix.split_first() else {
+        return false;
+    };
+    if words.len() < prefix.len() {
+        return false;
+    }
+    let name = |v: &str| {
+        let name = Path::new(v)
+            .file_name()
+            .map(|v| v.to_string_lossy().to_string())
+            .unwrap_or_default();
+        match cfg!(windows) {
+            true => name.to_lowercase().trim_end_matches(".exe").to_string(),
+            false => name,
+        }
+    };
+    name(&words[0]) == name(program) && words[1..].starts_with(rest)
+}
+
+fn command_matches(pattern: &str, command: &str) -> Result<bool> {
+    let command = command.trim();
+    match pattern.strip_prefix("re:") {
+        Some(re) => {
+            let re =
+                Regex::new(re).map_err(|e| anyhow!("Invalid command rule `{pattern}`: {e}"))?;
+            Ok(re.is_match(command))
+        }
+        None => {
+            let glob = GlobBuilder::new(pattern)
+                .literal_separator(false)
+                .build()
+                .map_err(|e| anyhow!("Invalid command rule `{pattern}`: {e}"))?;
+            Ok(glob.compile_matcher().is_match(command))
+        }
+    }
+}
+
+/// Splits a shell command into the simple commands it runs, at unquoted `;`, `&`, `|`,
+/// newlines and parentheses. `&` inside a redirection such as `2>&1` does not split. Returns
+/// `None` when the command uses command or process substitution, which cannot be split.
+fn command_segments(command: &str) -> Option<Vec<String>> {
+    let mut segments = vec![];
+    let mut current = String::new();
+    let (mut single, mut double) = (false, false);
+    let mut chars = command.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if !single => {
+                current.push(c);
+                if let Some(next) = chars.next() {
+                    current.push(next);
+                }
+                continue;
+            }
+            '\'' if !double => single = !single,
+            '"' if !single => double = !double,
+            '`' if !single => return None,
+            '$' | '<' | '>' if !single && chars.peek() == Some(&'(') => return None,
+            '&' if !single && !double && matches!(current.chars().last(), Some('>' | '<')) => {}
+            '&' if !single && !double && chars.peek() == Some(&'>') => {}
+            ';' | '&' | '|' | '\n' | '(' | ')' if !single && !double => {
+                segments.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    segments.push(current);
+    Some(
+        segments
+            .into_iter()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect(),
+    )
+}
+
+/// Whether a builtin tool call runs without asking, asks first, or is refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolPolicy {
+    Ask,
+    Allow,
+    Deny,
+}
+
+/// The user's answer when asked to confirm a tool call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Confirmation {
+    Yes,
+    No,
+    /// Allow this and every later call for the rest of the session.
+    All,
+}
+
+/// Resolves the policy for calling `name`, or the tool it is an alias of. `allow_all` is set
+/// once the user answered "all" earlier in the session.
+pub fn resolve_policy(
+    name: &str,
+    config: &BuiltinConfig,
+    interactive: bool,
+    allow_all: bool,
+) -> ToolPolicy {
+    let name = resolve_alias(name);
+    if !CONFIRM_TOOLS.contains(&name) {
+        return ToolPolicy::Allow;
+    }
+    match config.policy.get(name).copied().unwrap_or(ToolPolicy::Ask) {
+        ToolPolicy::Ask if !interactive => match config.non_interactive_policy {
+            ToolPolicy::Allow => ToolPolicy::Allow,
+            _ => ToolPolicy::Deny,
+        },
+        ToolPolicy::Ask if allow_all => ToolPolicy::Allow,
+        policy => policy,
+    }
+}
+
+/// Applies the confirmation policy to a call, using `ask` to prompt the user with a
+/// description of what the call will do. Returns whether the call may run.
+pub fn confirm_call(
+    name: &str,
+    args: &Value,
+    config: &BuiltinConfig,
+    interactive: bool,
+    allow_all: &mut bool,
+    ask: impl FnOnce(&str) -> Result<Confirmation>,
+) -> Result<bool> {
+    let confirmation = match resolve_policy(name, config, interactive, *allow_all) {
+        ToolPolicy::Allow => return Ok(true),
+        ToolPolicy::Deny => Confirmation::No,
+        ToolPolicy::Ask => ask(&describe_call(resolve_alias(name), args))?,
+    };
+    if confirmation == Confirmation::All {
+        *allow_all = true;
+    }
+    Ok(confirmation != Confirmation::No)
+}
+
+/// Whether a call changes files, runs commands or sends a request that may change
+/// something, and so needs the user's approval.
+fn needs_approval(name: &str, args: &Value) -> bool {
+    if !CONFIRM_TOOLS.contains(&name) {
+        return false;
+    }
+    match name {
+        "fs_patch" => args["preview"] != true,
+        "http_request" => {
+            let method = args["method"].as_str().unwrap_or("GET");
+            !["GET", "HEAD", "OPTIONS"].contains(&method.to_ascii_uppercase().as_str())
+        }
+        _ => true,
+    }
+}
+
+/// Describes what a call will do: the command line, or the path and the changes to it.
+fn describe_call(name: &str, args: &Value) -> String {
+    let path = args["path"].as_str().unwrap_or_default();
+    match name {
+        "command_run" | "command_spawn" => {
+            let command = args["command"].as_str().unwrap_or_default();
+            match args["working_directory"]
+                .as_str()
+                .or_else(|| args["cwd"].as_str())
+            {
+                Some(dir) => format!("$ {command}\n(in {dir})"),
+                None => format!("$ {command}"),
+            }
+        }
+        "fs_append" | "fs_write" if name == "fs_append" || args["append"] == true => {
+            let contents = args["contents"].as_str().unwrap_or_default();
+            format!("{name} {path} (append)\n{contents}")
+        }
+        "fs_write" => {
+            let contents = args["contents"].as_str().unwrap_or_default();
+            let old = fs::read_to_string(path).unwrap_or_default();
+            format!(
+                "{name} {path}\n{}",
+                unified_diff(&old, contents, path, path)
+            )
+        }
+        "fs_mv" | "fs_cp" => {
+            let from = args["from"].as_str().unwrap_or_default();
+            let to = args["to"].as_str().unwrap_or_default();
+            match fs::symlink_metadata(to) {
+                Ok(_) => format!("{name} {from}\nto {to} (replacing it)"),
+                Err(_) => format!("{name} {from}\nto {to}"),
+            }
+        }
+        "fs_patch" => {
+            let mut args = args.clone();
+            args["preview"] = true.into();
+            let preview = match fs_patch(&args) {
+                Ok(v) => match v["diff"].as_str() {
+                    Some(diff) => diff.to_string(),
+                    None => v["error"].as_str().unwrap_or_default().to_string(),
+                },
+                Err(err) => err.to_string(),
+            };
+            format!("{name} {path}\n{preview}")
+        }
+        "fs_apply_patch" => {
+            let patch = args["patch"].as_str().unwrap_or_default();
+            format!("{name}\n{patch}")
+        }
+        "git_commit" => {
+            let repo = git_repo(args);
+            let message = args["message"].as_str().unwrap_or_default();
+            let mut git_args = vec!["status", "--short", "--untracked-files=all", "--"];
+            git_args.extend(commit_paths(args));
+            let files = match git(repo, &git_args) {
+                Ok(Ok(v)) => v,
+                Ok(Err(err)) => err["error"].as_str().unwrap_or_default().to_string(),
+                Err(err) => err.to_string(),
+            };
+            format!("{name} in {}\n{message}\n\n{files}", repo.display())
+        }
+        "http_request" => {
+            let method = args["method"]
+                .as_str()
+                .unwrap_or("GET")
+                .to_ascii_uppercase();
+            let url = args["url"].as_str().unwrap_or_default();
+            let mut description = format!("{name} {method} {url}");
+            if let Some(headers) = args["headers"].as_object() {
+                for (key, value) in headers {
+                    description
+                        .push_str(&format!("\n{key}: {}", value.as_str().unwrap_or_default()));
+                }
+            }
+            if let Some(body) = args["body"].as_str() {
+                description.push_str(&format!("\n\n{body}"));
+            }
+            description
+        }
+        "download_file" => {
+            let url = args["url"].as_str().unwrap_or_default();
+            match fs::metadata(path) {
+                Ok(_) => format!("{name} {url}\nto {path} (replacing it)"),
+                Err(_) => format!("{name} {url}\nto {path}"),
+            }
+        }
+        "fs_rm" if args["recursive"].as_bool() == Some(true) => {
+            format!("{name} {path} (recursive)")
+        }
+        _ => format!("{name} {args}"),
+    }
+}
+
+/// Receives incremental output from a running builtin, one line at a time.
+pub type Progress<'a> = &'a (dyn Fn(&str) + Sync);
+
+/// Asked with the tool name and arguments before a call that changes files or runs
+/// commands; returning false declines the call.
+pub type Approve<'a> = &'a (dyn Fn(&str, &Value) -> Result<bool> + Sync);
+
+/// Callbacks from the chat loop into the builtins.
+#[derive(Clone, Copy, Default)]
+pub struct Hooks<'a> {
+    pub progress: Option<Progress<'a>>,
+    pub approve: Option<Approve<'a>>,
+}
+
+/// A builtin tool: its declaration for the model and the handler for its calls.
+#[async_trait]
+pub trait BuiltinTool: Send + Sync {
+    fn declaration(&self) -> FunctionDeclaration;
+
+    async fn run(&self, args: &Value, ctx: &Context<'_>) -> Result<Value>;
+
+    /// Whether `run` blocks its thread on file I/O or child processes, and so is moved
+    /// to tokio's blocking pool.
+    fn blocking(&self) -> bool {
+        true
+    }
+}
+
+/// What a builtin tool gets from the caller besides its arguments.
+pub struct Context<'a> {
+    pub config: &'a BuiltinConfig,
+    pub hooks: &'a Hooks<'a>,
+}
+
+/// Other names the model may use for a tool.
+const ALIASES: [(&str, &str); 2] = [("fs_move", "fs_mv"), ("fs_copy", "fs_cp")];
+
+/// Returns the tool `name` is an alias of, or `name` itself.
+fn resolve_alias(name: &str) -> &str {
+    ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map_or(name, |(_, name)| name)
+}
+
+static TOOLS: LazyLock<IndexMap<String, Box<dyn BuiltinTool>>> = LazyLock::new(|| {
+    all_tools()
+        .into_iter()
+        .map(|tool| (tool.declaration().name, tool))
+        .collect()
+});
+
+/// Every builtin tool, in the order they are declared to the model.
+fn all_tools() -> Vec<Box<dyn BuiltinTool>> {
+    vec![
+        Box::new(FsCat),
+        Box::new(FsHead),
+        Box::new(FsTail),
+        Box::new(FsLs),
+        Box::new(FsTree),
+        Box::new(FsFind),
+        Box::new(FsMkdir),
+        Box::new(FsRm),
+        Box::new(FsMv),
+        Box::new(FsCp),
+        Box::new(FsWrite),
+        Box::new(FsAppend),
+        Box::new(FsSearch),
+        Box::new(FsStat),
+        Box::new(FsFileExists),
+        Box::new(FsIsDir),
+        Box::new(FsIsFile),
+        Box::new(FsPatch),
+        Box::new(FsDiff),
+        Box::new(FsApplyPatch),
+        Box::new(JsonQuery),
+        Box::new(CommandRun),
+        Box::new(GitStatus),
+        Box::new(GitDiff),
+        Box::new(GitLog),
+        Box::new(GitCommit),
+        Box::new(CommandSpawn),
+        Box::new(CommandPoll),
+        Box::new(CommandKill),
+        Box::new(web::WebSearch),
+        Box::new(web::WebBrowse),
+        Box::new(web::HttpRequest),
+        Box::new(web::DownloadFile),
+    ]
+}
+
+/// Whether the config leaves the builtin `name` available to the model.
+pub fn enabled(name: &str, config: &BuiltinConfig) -> bool {
+    match name {
+        "http_request" => config.http_request.enabled,
+        _ => true,
+    }
+}
+
+pub fn declarations() -> Vec<FunctionDeclaration> {
+    TOOLS.values().map(|tool| tool.declaration()).collect()
+}
+
+pub async fn run(
+    name: &str,
+    args: &Value,
+    config: &BuiltinConfig,
+    hooks: &Hooks<'_>,
+) -> Result<Option<Value>> {
+    let name = resolve_alias(name);
+    let Some(tool) = TOOLS.get(name) else {
+        return Ok(None);
+    };
+    if !enabled(name, config) {
+        return Ok(Some(json!({ "error": format!("{name} is disabled") })));
+    }
+    let jailed;
+    let args = match &config.fs_root {
+        Some(root)
+            if name.starts_with("fs_")
+                || name.starts_with("git_")
+                || matches!(
+                    name,
+                    "json_query" | "download_file" | "command_run" | "command_spawn"
+                ) =>
+        {
+            jailed = jail_args(name, args, root)?;
+            &jailed
+        }
+        _ => args,
+    };
+    if let Some(approve) = hooks.approve {
+        if needs_approval(name, args) && !approve(name, args)? {
+            return Ok(Some(json!({
+                "denied": true,
+                "error": format!("The user declined the {name} call"),
+            })));
+        }
+    }
+    if tool.blocking() {
+        return run_blocking(tool.as_ref(), args, config, hooks)
+            .await
+            .map(Some);
+    }
+    let ctx = Context { config, hooks };
+    tool.run(args, &ctx).await.map(Some)
+}
+
+/// Runs a blocking tool on tokio's blocking pool, so the async workers stay free to render
+/// output, and forwards its progress lines to `hooks`. Dropping the returned future aborts
+/// the call if it has not started yet; one already running is left to finish.
+async fn run_blocking(
+    tool: &'static dyn BuiltinTool,
+    args: &Value,
+    config: &BuiltinConfig,
+    hooks: &Hooks<'_>,
+) -> Result<Value> {
+    let (args, config) = (args.clone(), config.clone());
+    let streams = hooks.progress.is_some();
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+    let runtime = tokio::runtime::Handle::current();
+    let mut task = AbortOnDrop(tokio::task::spawn_blocking(move || {
+        let send = move |line: &str| {
+            let _ = tx.send(line.to_string());
+        };
+        let hooks = Hooks {
+            progress: streams.then_some(&send as Progress),
+            approve: None,
+        };
+        let ctx = Context {
+            config: &config,
+            hooks: &hooks,
+        };
+        runtime.block_on(tool.run(&args, &ctx))
+    }));
+    while let Some(line) = rx.recv().await {
+        if let Some(progress) = hooks.progress {
+            progress(&line);
+        }
+    }
+    match (&mut task.0).await {
+        Ok(ret) => ret,
+        Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+        Err(err) => Err(anyhow!("The builtin call was cancelled: {err}")),
+    }
+}
+
+/// Aborts a spawned task when dropped, such as when the user cancels the chat turn.
+struct AbortOnDrop<T>(tokio::task::JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+struct FsCat;
+
+#[async_trait]
+impl BuiltinTool for FsCat {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "fs_cat".to_string(),
+            description: "Read the contents of a file. Returns `content`, `total_bytes`, `truncated` and `encoding`, plus `total_lines` when the whole file was read.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "The path to the file to read"
+                    },
+                    "start_line": {
+                        "type": "integer",
+                        "description": "The first line to read, 1-based and inclusive (defaults to the start of the file)"
+                    },
+                    "end_line": {
+                        "type": "integer",
+                        "description": "The last line to read, 1-based and inclusive (defaults to the end of the file)"
+                    },
+                    "max_bytes": {
+                        "type": "integer",
+                        "description": "The maximum number of bytes to return (defaults to 204800, or as configured)"
+                    },
+                    "encoding": {
+                        "type": "string",
+                        "description": "The text encoding of the file, e.g. utf-8, latin1, utf-16le or utf-16be (auto-detected by default)"
+                    }
+                },
+                "required": ["path"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, ctx: &Context<'_>) -> Result<Value> {
+        let path = args["path"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing path"))?;
+        let start_line = args["start_line"].as_u64().map(|v| v as usize);
+        let end_line = args["end_line"].as_u64().map(|v| v as usize);
+        let max_bytes = args["max_bytes"]
+            .as_u64()
+            .map(|v| v as usize)
+            .unwrap_or(ctx.config.fs_cat_max_bytes);
+        let file = File::open(path)?;
+        let total_bytes = file.metadata()?.len() as usize;
+        // Without a line range only the start of the file can be returned, so read just enough
+        // to fill `max_bytes` even when every two UTF-16 bytes decode to one.
+        let limit = match start_line.is_some() || end_line.is_some() {
+            true => u64::MAX,
+            false => (max_bytes as u64).saturating_add(4).saturating_mul(2),
+        };
+        let mut bytes = vec![];
+        file.take(limit).read_to_end(&mut bytes)?;
+        let partial = bytes.len() < total_bytes;
+        let encoding = match args["encoding"].as_str() {
+            Some(label) => match Encoding::for_label(label.as_bytes()) {
+                Some(v) => Some(v),
+                None => return Ok(json!({ "error": format!("Unsupported encoding: {label}") })),
+            },
+            None => None,
+        };
+        let Some((mut content, encoding)) = decode_text(&bytes, encoding, partial) else {
+            return Ok(json!({ "binary": true, "size": total_bytes }));
+        };
+        let total_lines = content.lines().count();
+        if start_line.is_some() || end_line.is_some() {
+            content = slice_lines(&content, start_line, end_line);
+        }
+        let truncated = partial || content.len() > max_bytes;
+        if truncated {
+            truncate_at_char_boundary(&mut content, max_bytes);
+        }
+        let mut result = json!({
+            "content": content,
+            "total_bytes": total_bytes,
+            "truncated": truncated,
+            "encoding": encoding.name(),
+        });
+        if !partial {
+            result["total_lines"] = total_lines.into();
+        }
+        Ok(result)
+    }
+}
+
+struct FsHead;
+
+#[async_trait]
+impl BuiltinTool for FsHead {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "fs_head".to_string(),
+            description: "Read the first lines of a file. Returns `content` and `lines`, plus `total_lines` when the whole file was read.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "The path to the file to read"
+                    },
+                    "lines": {
+                        "type": "integer",
+                        "description": "The number of lines to read (defaults to 50)"
+                    }
+                },
+                "required": ["path"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        read_lines(args, true)
+    }
+}
+
+struct FsTail;
+
+#[async_trait]
+impl BuiltinTool for FsTail {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "fs_tail".to_string(),
+            description: "Read the last lines of a file without loading all of it, e.g. for large logs. Returns `content` and `lines`, plus `total_lines` when the whole file was read.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "The path to the file to read"
+                    },
+                    "lines": {
+                        "type": "integer",
+                        "description": "The number of lines to read (defaults to 50)"
+                    }
+                },
+                "required": ["path"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        read_lines(args, false)
+    }
+}
+
+struct FsLs;
+
+#[async_trait]
+impl BuiltinTool for FsLs {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "fs_ls".to_string(),
+            description: "List files in a directory. Returns `files`, a list of `{name, type, size, modified}` objects where `type` is file, dir or symlink and `modified` is an RFC3339 timestamp. Recursive listings use relative paths with a trailing `/` for directories and are capped at 500 entries, setting `truncated` when cut off.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "The path to the directory to list (defaults to current directory)"
+                    },
+                    "recursive": {
+                        "type": "boolean",
+                        "description": "List subdirectories recursively, with names relative to `path` (defaults to false)"
+                    },
+                    "max_depth": {
+                        "type": "integer",
+                        "description": "The maximum depth to descend to when listing recursively (defaults to 3)"
+                    },
+                    "show_hidden": {
+                        "type": "boolean",
+                        "description": "Include hidden files and directories such as .git when listing recursively (defaults to false)"
+                    },
+                    "all": {
+                        "type": "boolean",
+                        "description": "Alias of `show_hidden`"
+                    },
+                    "pattern": {
+                        "type": "string",
+                        "description": "A glob such as `*.rs` or `src/**/*.toml` matched against paths relative to `path`; implies a recursive listing"
+                    }
+                }
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let path = args["path"].as_str().unwrap_or(".");
+        let glob = match args["pattern"].as_str() {
+            Some(pattern) => match GlobBuilder::new(pattern).literal_separator(true).build() {
+                Ok(glob) => Some(glob.compile_matcher()),
+                Err(err) => return Ok(json!({ "error": format!("Invalid glob pattern: {err}") })),
+            },
+            None => None,
+        };
+        let recursive = args["recursive"].as_bool().unwrap_or(false);
+        let mut files = vec![];
+        if recursive || glob.is_some() {
+            let max_depth = match args["max_depth"].as_u64() {
+                Some(v) => Some(v as usize),
+                None if recursive => Some(FS_LS_MAX_DEPTH),
+                None => None,
+            };
+            let show_hidden = args["show_hidden"]
+                .as_bool()
+                .or_else(|| args["all"].as_bool())
+                .unwrap_or(false);
+            let walker = WalkBuilder::new(path)
+                .standard_filters(false)
+                .hidden(!show_hidden)
+                .max_depth(max_depth)
+                .build();
+            for entry in walker.flatten() {
+                if entry.depth() == 0 {
+                    continue;
+                }
+                let name = entry.path().strip_prefix(path).unwrap_or(entry.path());
+                if glob.as_ref().is_some_and(|glob| !glob.is_match(name)) {
+                    continue;
+                }
+                let mut name = name.to_string_lossy().to_string();
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    name.push('/');
+                }
+                files.push(file_entry(&name, &metadata));
+            }
+        } else {
+            for entry in fs::read_dir(path)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                files.push(file_entry(&entry.file_name().to_string_lossy(), &metadata));
+            }
+        }
+        files.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+        let truncated = files.len() > FS_LS_MAX_ENTRIES;
+        files.truncate(FS_LS_MAX_ENTRIES);
+        Ok(json!({ "files": files, "truncated": truncated }))
+    }
+}
+
+struct FsTree;
+
+#[async_trait]
+impl BuiltinTool for FsTree {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "fs_tree".to_string(),
+            description: "Render the structure of a directory as a compact ASCII tree, directories first. Entries matched by .gitignore files are skipped.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "The root directory of the tree (defaults to current directory)"
+                    },
+                    "max_depth": {
+                        "type": "integer",
+                        "description": "The maximum depth to descend to (defaults to 3)"
+                    },
+                    "max_entries": {
+                        "type": "integer",
+                        "description": "The maximum number of entries to render (defaults to 200)"
+                    },
+                    "ignore": {
+                        "type": "array",
+                        "items": { "type": "string" },
+                        "description": "Names of files or directories to skip (defaults to [\".git\", \"node_modules\", \"target\"])"
+                    }
+                }
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let path = args["path"].as_str().unwrap_or(".");
+        if !Path::new(path).is_dir() {
+            return Ok(json!({ "error": format!("Not a directory: {path}") }));
+        }
+        let ignore = match args["ignore"].as_array() {
+            Some(list) => list
+                .iter()
+                .filter_map(|v| v.as_str().map(|v| v.to_string()))
+                .collect(),
+            None => FS_TREE_IGNORE.iter().map(|v| v.to_string()).collect(),
+        };
+        let options = TreeOptions {
+            max_depth: args["max_depth"]
+                .as_u64()
+                .map(|v| v as usize)
+                .unwrap_or(FS_TREE_MAX_DEPTH),
+            max_entries: args["max_entries"]
+                .as_u64()
+                .map(|v| v as usize)
+                .unwrap_or(FS_TREE_MAX_ENTRIES),
+            ignore,
+        };
+        let mut output = TreeOutput {
+            tree: format!("{path}\n"),
+            ..Default::default()
+        };
+        render_tree(Path::new(path), &options, &mut output);
+        Ok(json!({ "tree": output.tree, "truncated": output.truncated }))
+    }
+}
+
+struct FsFind;
+
+#[async_trait]
+impl BuiltinTool for FsFind {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "fs_find".to_string(),
+            description: "Find files and directories by name, skipping .git and anything excluded by .gitignore. Returns `files`, paths relative to `path` with a trailing `/` for directories, and `truncated` when `max_results` was hit.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "The directory to search in (defaults to current directory)"
+                    },
+                    "name_pattern": {
+                        "type": "string",
+                        "description": "A glob matched against file names, e.g. `*.toml` or `test_*`; a pattern containing `/` is matched against the relative path instead, e.g. `src/**/*.rs`"
+                    },
+                    "max_depth": {
+                        "type": "integer",
+                        "description": "The maximum depth to descend to (unlimited by default)"
+                    },
+                    "max_results": {
+                        "type": "integer",
+                        "description": "The maximum number of paths to return (defaults to 200)"
+                    }
+                },
+                "required": ["name_pattern"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let path = args["path"].as_str().unwrap_or(".");
+        let pattern = args["name_pattern"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing name_pattern"))?;
+        let glob = match GlobBuilder::new(pattern).literal_separator(true).build() {
+            Ok(glob) => glob.compile_matcher(),
+            Err(err) => return Ok(json!({ "error": format!("Invalid glob pattern: {err}") })),
+        };
+        let match_path = pattern.contains('/');
+        let max_results = args["max_results"]
+            .as_u64()
+            .map(|v| v as usize)
+            .unwrap_or(FS_FIND_MAX_RESULTS);
+        let walker = WalkBuilder::new(path)
+            .hidden(false)
+            .require_git(false)
+            .max_depth(args["max_depth"].as_u64().map(|v| v as usize))
+            .filter_entry(|v| v.file_name() != ".git")
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .build();
+        let mut files = vec![];
+        let mut truncated = false;
+        for entry in walker.flatten() {
+            if entry.depth() == 0 {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(path).unwrap_or(entry.path());
+            let is_match = if match_path {
+                glob.is_match(relative)
+            } else {
+                glob.is_match(entry.file_name())
+            };
+            if !is_match {
+                continue;
+            }
+            if files.len() >= max_results {
+                truncated = true;
+                break;
+            }
+            let mut name = relative.to_string_lossy().to_string();
+            if entry.file_type().is_some_and(|v| v.is_dir()) {
+                name.push('/');
+            }
+            files.push(name);
+        }
+        Ok(json!({ "files": files, "truncated": truncated }))
+    }
+}
+
+struct FsMkdir;
+
+#[async_trait]
+impl BuiltinTool for FsMkdir {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "fs_mkdir".to_string(),
+            description: "Create a directory.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "The path to the directory to create"
+                    }
+                },
+                "required": ["path"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let path = args["path"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing path"))?;
+        fs::create_dir_all(path)?;
+        Ok(json!({ "success": true }))
+    }
+}
+
+struct FsRm;
+
+#[async_trait]
+impl BuiltinTool for FsRm {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "fs_rm".to_string(),
+            description: "Remove a file or directory. The filesystem root, the home directory, the current directory and paths outside the allowed roots are refused.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "The path to the file or directory to remove"
+                    },
+                    "recursive": {
+                        "type": "boolean",
+                        "description": "Remove a non-empty directory and all of its contents (defaults to false)"
+                    }
+                },
+                "required": ["path"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, ctx: &Context<'_>) -> Result<Value> {
+        let path = args["path"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing path"))?;
+        let recursive = args["recursive"].as_bool().unwrap_or(false);
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            return Ok(json!({ "error": format!("Path not found: {path}") }));
+        };
+        if let Some(reason) = removal_refusal(Path::new(path), ctx.config.fs_root.as_deref()) {
+            return Ok(json!({ "error": reason }));
+        }
+        let was_dir = metadata.is_dir();
+        if !was_dir {
+            fs::remove_file(path)?;
+        } else if recursive {
+            let files = count_files(Path::new(path));
+            fs::remove_dir_all(path)?;
+            return Ok(json!({ "removed": true, "was_dir": true, "files": files }));
+        } else if fs::read_dir(path)?.next().is_some() {
+            return Ok(json!({
+                "error": "Directory is not empty, set `recursive` to remove it"
+            }));
+        } else {
+            fs::remove_dir(path)?;
+        }
+        Ok(json!({ "removed": true, "was_dir": was_dir }))
+    }
+}
+
+struct FsMv;
+
+#[async_trait]
+impl BuiltinTool for FsMv {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "fs_mv".to_string(),
+            description: "Move or rename a file or directory. Returns the resolved `from` and `to` paths.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "from": {
+                        "type": "string",
+                        "description": "The path to move"
+                    },
+                    "to": {
+                        "type": "string",
+                        "description": "The destination path; missing parent directories are created"
+                    },
+                    "overwrite": {
+                        "type": "boolean",
+                        "description": "Replace the destination if it already exists (defaults to false)"
+                    }
+                },
+                "required": ["from", "to"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let from = args["from"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing from"))?;
+        let to = args["to"].as_str().ok_or_else(|| anyhow!("Missing to"))?;
+        let overwrite = args["overwrite"].as_bool().unwrap_or(false);
+        if fs::symlink_metadata(from).is_err() {
+            return Ok(json!({ "error": format!("Path not found: {from}") }));
+        }
+        let source = resolve_location(Path::new(from))?;
+        if let Err(err) = prepare_destination(&source, to, overwrite)? {
+            return Ok(err);
+        }
+        if let Err(err) = fs::rename(from, to) {
+            if err.kind() != std::io::ErrorKind::CrossesDevices {
+                return Err(err.into());
+            }
+            copy_recursive(Path::new(from), Path::new(to))?;
+            remove_path(Path::new(from), &fs::symlink_metadata(from)?)?;
+        }
+        Ok(json!({
+            "moved": true,
+            "from": to_absolute_path(from)?,
+            "to": to_absolute_path(to)?,
+        }))
+    }
+}
+
+struct FsCp;
+
+#[async_trait]
+impl BuiltinTool for FsCp {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "fs_cp".to_string(),
+            description: "Copy a file, or a directory tree with `recursive`; symlinks inside the tree are copied as links. Returns the resolved `from` and `to` paths.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "from": {
+                        "type": "string",
+                        "description": "The path to copy"
+                    },
+                    "to": {
+                        "type": "string",
+                        "description": "The destination path; missing parent directories are created"
+                    },
+                    "recursive": {
+                        "type": "boolean",
+                        "description": "Copy a directory and all of its contents (defaults to false)"
+                    },
+                    "overwrite": {
+                        "type": "boolean",
+                        "description": "Replace the destination if it already exists (defaults to false)"
+                    }
+                },
+                "required": ["from", "to"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let from = args["from"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing from"))?;
+        let to = args["to"].as_str().ok_or_else(|| anyhow!("Missing to"))?;
+        let recursive = args["recursive"].as_bool().unwrap_or(false);
+        let overwrite = args["overwrite"].as_bool().unwrap_or(false);
+        let Ok(metadata) = fs::metadata(from) else {
+            return Ok(json!({ "error": format!("Path not found: {from}") }));
+        };
+        if metadata.is_dir() && !recursive {
+            return Ok(json!({
+                "error": format!("Source is a directory: {from}, set `recursive` to copy it")
+            }));
+        }
+        // A symlinked source is copied as what it points to; links inside a tree stay links.
+        let source = fs::canonicalize(from)?;
+        if let Err(err) = prepare_destination(&source, to, overwrite)? {
+            return Ok(err);
+        }
+        let (files, bytes) = copy_recursive(&source, Path::new(to))?;
+        Ok(json!({
+            "copied": true,
+            "from": to_absolute_path(from)?,
+            "to": to_absolute_path(to)?,
+            "files": files,
+            "bytes": bytes,
+        }))
+    }
+}
+
+struct FsWrite;
+
+#[async_trait]
+impl BuiltinTool for FsWrite {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "fs_write".to_string(),
+            description: "Write content to a file, creating missing parent directories. Writing to an existing file fails with `{error: \"file exists\", size}` unless `overwrite` or `append` is set; read the file first before replacing it.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "The path to the file to write"
+                    },
+                    "contents": {
+                        "type": "string",
+                        "description": "The content to write to the file"
+                    },
+                    "append": {
+                        "type": "boolean",
+                        "description": "Append to the end of the file instead of replacing it, creating it if missing (defaults to false)"
+                    },
+                    "overwrite": {
+                        "type": "boolean",
+                        "description": "Replace the file if it already exists (defaults to false)"
+                    }
+                },
+                "required": ["path", "contents"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let path = args["path"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing path"))?;
+        let contents = args["contents"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing contents"))?;
+        let append = args["append"].as_bool().unwrap_or(false);
+        let overwrite = args["overwrite"].as_bool().unwrap_or(false);
+        if !append && !overwrite {
+            if let Ok(metadata) = fs::metadata(path) {
+                return Ok(json!({ "error": "file exists", "size": metadata.len() }));
+            }
+        }
+        let created_dirs = match Path::new(path).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() && !parent.exists() => {
+                fs::create_dir_all(parent)?;
+                true
+            }
+            _ => false,
+        };
+        if append {
+            append_file(Path::new(path), contents.as_bytes())?;
+        } else {
+            write_atomic(Path::new(path), contents.as_bytes())?;
+        }
+        let size = fs::metadata(path)?.len();
+        Ok(json!({
+            "success": true,
+            "path": to_absolute_path(path)?,
+            "size": size,
+            "created_dirs": created_dirs,
+        }))
+    }
+}
+
+struct FsAppend;
+
+#[async_trait]
+impl BuiltinTool for FsAppend {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "fs_append".to_string(),
+            description: "Append content to the end of a file, creating it if missing.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "The path to the file to append to"
+                    },
+                    "contents": {
+                        "type": "string",
+                        "description": "The content to append"
+                    }
+                },
+                "required": ["path", "contents"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let path = args["path"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing path"))?;
+        let contents = args["contents"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing contents"))?;
+        let total_bytes = append_file(Path::new(path), contents.as_bytes())?;
+        Ok(json!({ "success": true, "total_bytes": total_bytes }))
+    }
+}
+
+struct FsSearch;
+
+#[async_trait]
+impl BuiltinTool for FsSearch {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "fs_search".to_string(),
+            description: "Search for text in files (substring search). Returns `results`, a list of `{path, line, text}` objects for each matching line (at most 20 per file), with a `context` list of `{line, text}` when context lines are requested (lines shared with a previous match in the same file are not repeated), `truncated` when `max_results` was hit and `skipped_files` for files over `max_file_size`.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "The path to the directory to search in"
+                    },
+                    "text": {
+                        "type": "string",
+                        "description": "The text to search for"
+                    },
+                    "file_pattern": {
+                        "type": "string",
+                        "description": "Only search matching files. A glob such as `*.rs` is matched against file names, one containing `/` such as `src/**/*.toml` against paths relative to `path`; a pattern without glob characters is a substring match on the path"
+                    },
+                    "regex": {
+                        "type": "boolean",
+                        "description": "Treat `text` as a regular expression matched against each line (defaults to false)"
+                    },
+                    "case_sensitive": {
+                        "type": "boolean",
+                        "description": "Match case-sensitively (defaults to true)"
+                    },
+                    "ignore_case": {
+                        "type": "boolean",
+                        "description": "Match case-insensitively; the inverse of `case_sensitive` (defaults to false)"
+                    },
+                    "whole_word": {
+                        "type": "boolean",
+                        "description": "Only match `text` as a whole word (defaults to false)"
+                    },
+                    "include_ignored": {
+                        "type": "boolean",
+                        "description": "Also search hidden files and files excluded by .gitignore/.ignore (defaults to false)"
+                    },
+                    "respect_gitignore": {
+                        "type": "boolean",
+                        "description": "Skip files excluded by .gitignore/.ignore; hidden files stay skipped when false (defaults to true)"
+                    },
+                    "max_results": {
+                        "type": "integer",
+                        "description": "The maximum number of matches to return (defaults to 200)"
+                    },
+                    "max_file_size": {
+                        "type": "integer",
+                        "description": "Skip files larger than this many bytes (defaults to 1048576)"
+                    },
+                    "context_before": {
+                        "type": "integer",
+                        "description": "Lines of context to include before each match, like `grep -B` (defaults to 0)"
+                    },
+                    "context_after": {
+                        "type": "integer",
+                        "description": "Lines of context to include after each match, like `grep -A` (defaults to 0)"
+                    }
+                },
+                "required": ["path", "text"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let path = args["path"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing path"))?;
+        let text = args["text"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing text"))?;
+        let ignore_case = args["ignore_case"]
+            .as_bool()
+            .or_else(|| args["case_sensitive"].as_bool().map(|v| !v))
+            .unwrap_or(false);
+        let whole_word = args["whole_word"].as_bool().unwrap_or(false);
+        let pattern = if args["regex"].as_bool().unwrap_or(false) {
+            let text = if whole_word {
+                format!(r"\b(?:{text})\b")
+            } else {
+                text.to_string()
+            };
+            match RegexBuilder::new(&text)
+                .case_insensitive(ignore_case)
+                .build()
+            {
+                Ok(re) => SearchPattern::Regex(re),
+                Err(err) => return Ok(json!({ "error": format!("Invalid regex: {err}") })),
+            }
+        } else {
+            SearchPattern::Literal {
+                text: if ignore_case {
+                    text.to_lowercase()
+                } else {
+                    text.to_string()
+                },
+                ignore_case,
+                whole_word,
+            }
+        };
+        let file_pattern = match args["file_pattern"].as_str() {
+            Some(v) if v.contains(['*', '?', '[', '{']) => {
+                match GlobBuilder::new(v).literal_separator(true).build() {
+                    Ok(glob) => Some(FilePattern::Glob {
+                        matcher: glob.compile_matcher(),
+                        match_path: v.contains('/'),
+                    }),
+                    Err(err) => {
+                        return Ok(json!({ "error": format!("Invalid glob pattern: {err}") }))
+                    }
+                }
+            }
+            Some(v) => Some(FilePattern::Substring(v.to_string())),
+            None => None,
+        };
+        let options = SearchOptions {
+            pattern,
+            file_pattern,
+            include_ignored: args["include_ignored"].as_bool().unwrap_or(false),
+            respect_gitignore: args["respect_gitignore"].as_bool().unwrap_or(true),
+            max_results: args["max_results"]
+                .as_u64()
+                .map(|v| v as usize)
+                .unwrap_or(FS_SEARCH_MAX_RESULTS),
+            max_file_size: args["max_file_size"]
+                .as_u64()
+                .unwrap_or(FS_SEARCH_MAX_FILE_SIZE),
+            context_before: args["context_before"].as_u64().unwrap_or(0) as usize,
+            context_after: args["context_after"].as_u64().unwrap_or(0) as usize,
+        };
+
+        let mut output = SearchOutput::default();
+        visit_dirs(Path::new(path), &options, &mut output);
+        Ok(json!({
+            "results": output.results,
+            "truncated": output.truncated,
+            "skipped_files": output.skipped_files,
+        }))
+    }
+}
+
+struct FsStat;
+
+#[async_trait]
+impl BuiltinTool for FsStat {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "fs_stat".to_string(),
+            description: "Get metadata for a file or directory without reading it. Returns `{exists, type, size, modified, readonly, permissions_octal, is_symlink}`, or `{exists: false}` for a missing path. For a symlink `type` is `symlink`, and `target` and `target_type` describe what it points to (`target_type` is null when the link is broken).".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "The path to the file or directory"
+                    }
+                },
+                "required": ["path"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let path = args["path"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing path"))?;
+        let Ok(link_metadata) = fs::symlink_metadata(path) else {
+            return Ok(json!({ "exists": false }));
+        };
+        let is_symlink = link_metadata.file_type().is_symlink();
+        let target_metadata = fs::metadata(path).ok();
+        let metadata = target_metadata.as_ref().unwrap_or(&link_metadata);
+        let mut result = json!({
+            "exists": true,
+            "type": file_type_name(&link_metadata.file_type()),
+            "size": metadata.len(),
+            "modified": metadata.modified().ok().map(format_time),
+            "readonly": metadata.permissions().readonly(),
+            "permissions_octal": permissions_octal(metadata),
+            "is_symlink": is_symlink,
+        });
+        if is_symlink {
+            result["target"] = fs::read_link(path)?.to_string_lossy().into();
+            result["target_type"] = target_metadata
+                .map(|v| file_type_name(&v.file_type()))
+                .into();
+        }
+        Ok(result)
+    }
+}
+
+struct FsFileExists;
+
+#[async_trait]
+impl BuiltinTool for FsFileExists {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "fs_file_exists".to_string(),
+            description: "Check if a file or directory exists.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "The path to check"
+                    }
+                },
+                "required": ["path"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let path = args["path"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing path"))?;
+        let exists = Path::new(path).exists();
+        Ok(json!({ "exists": exists }))
+    }
+}
+
+struct FsIsDir;
+
+#[async_trait]
+impl BuiltinTool for FsIsDir {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "fs_is_dir".to_string(),
+            description: "Check if a path is a directory.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "The path to check"
+                    }
+                },
+                "required": ["path"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let path = args["path"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing path"))?;
+        let is_dir = Path::new(path).is_dir();
+        Ok(json!({ "is_dir": is_dir }))
+    }
+}
+
+struct FsIsFile;
+
+#[async_trait]
+impl BuiltinTool for FsIsFile {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "fs_is_file".to_string(),
+            description: "Check if a path is a file.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "The path to check"
+                    }
+                },
+                "required": ["path"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let path = args["path"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing path"))?;
+        let is_file = Path::new(path).is_file();
+        Ok(json!({ "is_file": is_file }))
+    }
+}
+
+struct FsPatch;
+
+#[async_trait]
+impl BuiltinTool for FsPatch {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "fs_patch".to_string(),
+            description: "Patch a file by replacing a search block with a replace block. If `search` occurs more than once, set `occurrence` or `replace_all`, or include more surrounding context. Returns the number of replacements as `count`.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "The path to the file to patch"
+                    },
+                    "search": {
+                        "type": "string",
+                        "description": "The block of text to search for"
+                    },
+                    "replace": {
+                        "type": "string",
+                        "description": "The block of text to replace it with"
+                    },
+                    "occurrence": {
+                        "type": "integer",
+                        "description": "Replace only the nth occurrence of `search`, 1-based"
+                    },
+                    "replace_all": {
+                        "type": "boolean",
+                        "description": "Replace every occurrence of `search` (defaults to false)"
+                    },
+                    "all": {
+                        "type": "boolean",
+                        "description": "Alias of `replace_all`"
+                    },
+                    "regex": {
+                        "type": "boolean",
+                        "description": "Treat `search` as a regular expression; `replace` may reference capture groups as `$1` or `${name}` (defaults to false)"
+                    },
+                    "preview": {
+                        "type": "boolean",
+                        "description": "Do not write the file; return the change as a unified diff in `diff` instead (defaults to false)"
+                    },
+                    "edits": {
+                        "type": "array",
+                        "description": "Several search/replace edits applied in order, instead of `search` and `replace`. Each `search` must occur exactly once; if any edit fails nothing is written.",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "search": {
+                                    "type": "string",
+                                    "description": "The block of text to search for"
+                                },
+                                "replace": {
+                                    "type": "string",
+                                    "description": "The block of text to replace it with"
+                                }
+                            },
+                            "required": ["search", "replace"]
+                        }
+                    }
+                },
+                "required": ["path"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        fs_patch(args)
+    }
+}
+
+struct FsDiff;
+
+#[async_trait]
+impl BuiltinTool for FsDiff {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "fs_diff".to_string(),
+            description: "Show a unified diff between two files, or between a file and the given `content`. Returns `{identical: true}` when they are equal, otherwise `diff` and `truncated`. Inputs over 4 MiB are refused.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "The original file"
+                    },
+                    "other_path": {
+                        "type": "string",
+                        "description": "The file to compare against"
+                    },
+                    "content": {
+                        "type": "string",
+                        "description": "Text to compare against instead of `other_path`"
+                    },
+                    "max_bytes": {
+                        "type": "integer",
+                        "description": "The maximum size of the returned diff in bytes (defaults to 100000)"
+                    }
+                },
+                "required": ["path"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let path = args["path"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing path"))?;
+        let max_bytes = args["max_bytes"]
+            .as_u64()
+            .map(|v| v as usize)
+            .unwrap_or(FS_DIFF_MAX_BYTES);
+        let (new_path, new_size) = match (args["other_path"].as_str(), args["content"].as_str()) {
+            (Some(other_path), _) => (other_path, fs::metadata(other_path)?.len()),
+            (None, Some(content)) => (path, content.len() as u64),
+            (None, None) => return Err(anyhow!("Missing other_path or content")),
+        };
+        let size = fs::metadata(path)?.len().max(new_size);
+        if size > FS_DIFF_MAX_INPUT_BYTES {
+            return Ok(json!({ "error": "input too large to diff", "size": size }));
+        }
+        let old = fs::read(path)?;
+        let new = match args["other_path"].as_str() {
+            Some(other_path) => fs::read(other_path)?,
+            None => args["content"]
+                .as_str()
+                .unwrap_or_default()
+                .as_bytes()
+                .to_vec(),
+        };
+        if old == new {
+            return Ok(json!({ "identical": true }));
+        }
+        let (Ok(old), Ok(new)) = (std::str::from_utf8(&old), std::str::from_utf8(&new)) else {
+            return Ok(json!({ "identical": false, "error": "binary file" }));
+        };
+        if is_probably_binary(old.as_bytes()) || is_probably_binary(new.as_bytes()) {
+            return Ok(json!({ "identical": false, "error": "binary file" }));
+        }
+        let mut diff = unified_diff(old, new, path, new_path);
+        let truncated = diff.len() > max_bytes;
+        if truncated {
+            truncate_at_char_boundary(&mut diff, max_bytes);
+        }
+        Ok(json!({
+            "identical": false,
+            "diff": diff,
+            "truncated": truncated,
+        }))
+    }
+}
+
+struct FsApplyPatch;
+
+#[async_trait]
+impl BuiltinTool for FsApplyPatch {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "fs_apply_patch".to_string(),
+            description: "Apply a unified diff, which may touch several files, create files (`--- /dev/null`) or delete them (`+++ /dev/null`). Hunks are matched by their context, tolerating shifted line numbers and up to 2 mismatched context lines. A file is only changed when all of its hunks apply; applied hunks (with the line they matched at) and rejected hunks are reported per file.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "patch": {
+                        "type": "string",
+                        "description": "The unified diff to apply, with paths relative to the current directory"
+                    }
+                },
+                "required": ["patch"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, ctx: &Context<'_>) -> Result<Value> {
+        let patch = args["patch"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing patch"))?;
+        fs_apply_patch(patch, ctx.config.fs_root.as_deref())
+    }
+}
+
+struct JsonQuery;
+
+#[async_trait]
+impl BuiltinTool for JsonQuery {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "json_query".to_string(),
+            description: "Select parts of a JSON document with a JSONPath query, e.g. to read a few fields of a large API response or config file. Returns `matches`, a list of `{path, value}`, and `count`.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "query": {
+                        "type": "string",
+                        "description": "A JSONPath query, such as `$.items[*].name` or `$..id`; the leading `$` may be left out, as in `.items[0]`"
+                    },
+                    "json": {
+                        "type": "string",
+                        "description": "The JSON document to query"
+                    },
+                    "path": {
+                        "type": "string",
+                        "description": "A JSON file to query instead of `json`"
+                    }
+                },
+                "required": ["query"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let query = args["query"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing query"))?;
+        let text = match (args["json"].as_str(), args["path"].as_str()) {
+            (Some(text), _) => text.to_string(),
+            (None, Some(path)) => match fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(err) => return Ok(json!({ "error": format!("Failed to read {path}: {err}") })),
+            },
+            (None, None) => bail!("Missing json or path"),
+        };
+        json_query(&text, query)
+    }
+}
+
+/// Evaluates the JSONPath `query` against the document `text`.
+fn json_query(text: &str, query: &str) -> Result<Value> {
+    let query = query.trim();
+    let query = match query.starts_with('$') {
+        true => query.to_string(),
+        false => format!("${query}"),
+    };
+    let path = match JsonPath::parse(&query) {
+        Ok(path) => path,
+        Err(err) => return Ok(json!({ "error": format!("Invalid query `{query}`: {err}") })),
+    };
+    let document: Value = match serde_json::from_str(text) {
+        Ok(document) => document,
+        Err(err) => return Ok(json!({ "error": format!("Invalid JSON: {err}") })),
+    };
+    let matches: Vec<Value> = path
+        .query_located(&document)
+        .into_iter()
+        .map(|node| json!({ "path": node.location().to_string(), "value": node.node() }))
+        .collect();
+    Ok(json!({ "count": matches.len(), "matches": matches }))
+}
+
+struct CommandRun;
+
+#[async_trait]
+impl BuiltinTool for CommandRun {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "command_run".to_string(),
+            description: "Run a shell command. Returns `stdout`, `stderr`, `exit_code` and the `cwd` it ran in; `stdout_truncated`/`stderr_truncated` are set when the middle of long output was cut. A command still running after the timeout is killed together with its child processes, returning the output captured so far, `timed_out: true` and a null `exit_code`.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "command": {
+                        "type": "string",
+                        "description": "The command to run"
+                    },
+                    "shell": {
+                        "type": "string",
+                        "description": "Run the command through this shell: `sh`, `bash`, `zsh`, `fish`, `nu`, `pwsh`, `powershell`, `cmd` or `auto`; `none` runs it directly (defaults to the configured shell)"
+                    },
+                    "timeout_seconds": {
+                        "type": "integer",
+                        "description": "Kill the command after this many seconds (defaults to 60)"
+                    },
+                    "timeout_secs": {
+                        "type": "integer",
+                        "description": "Alias of `timeout_seconds`"
+                    },
+                    "working_directory": {
+                        "type": "string",
+                        "description": "The directory to run the command in, instead of prefixing it with `cd` (defaults to the current directory)"
+                    },
+                    "cwd": {
+                        "type": "string",
+                        "description": "Alias of `working_directory`"
+                    },
+                    "env": {
+                        "type": "object",
+                        "additionalProperties": { "type": "string" },
+                        "description": "Extra environment variables for the command; they override inherited variables of the same name"
+                    },
+                    "stdin": {
+                        "type": "string",
+                        "description": "Text to pass to the command on standard input"
+                    },
+                    "max_output_bytes": {
+                        "type": "integer",
+                        "description": "The maximum bytes kept from each of stdout and stderr (defaults to 204800)"
+                    },
+                    "max_output": {
+                        "type": "integer",
+                        "description": "Alias of `max_output_bytes`"
+                    },
+                    "keep_tail": {
+                        "type": "boolean",
+                        "description": "Whether truncated output keeps its end as well as its beginning, where errors usually are (defaults to true)"
+                    }
+                },
+                "required": ["command"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, ctx: &Context<'_>) -> Result<Value> {
+        command_run(args, ctx.config, ctx.hooks.progress)
+    }
+}
+
+struct GitStatus;
+
+#[async_trait]
+impl BuiltinTool for GitStatus {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "git_status".to_string(),
+            description: "List the changed, staged and untracked files of a git repository as `{path, status, code, staged}` entries, with the current `branch`.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "repo": {
+                        "type": "string",
+                        "description": "A directory inside the repository (defaults to the current directory)"
+                    },
+                    "cwd": {
+                        "type": "string",
+                        "description": "Alias of `repo`"
+                    }
+                }
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let repo = git_repo(args);
+        Ok(git_status(repo)?.unwrap_or_else(|err| err))
+    }
+}
+
+struct GitDiff;
+
+#[async_trait]
+impl BuiltinTool for GitDiff {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "git_diff".to_string(),
+            description: "Show the unstaged changes of a git repository, or the staged ones with `staged: true`, as a unified `diff`, with `files` listing each changed file and the line ranges of its hunks.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "repo": {
+                        "type": "string",
+                        "description": "A directory inside the repository (defaults to the current directory)"
+                    },
+                    "cwd": {
+                        "type": "string",
+                        "description": "Alias of `repo`"
+                    },
+                    "path": {
+                        "type": "string",
+                        "description": "Only show changes to this file or directory"
+                    },
+                    "staged": {
+                        "type": "boolean",
+                        "description": "Show the staged changes instead of the unstaged ones"
+                    },
+                    "max_bytes": {
+                        "type": "integer",
+                        "description": "The maximum size of the diff in bytes (defaults to 100000)"
+                    }
+                }
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let repo = git_repo(args);
+        Ok(git_diff(repo, args)?.unwrap_or_else(|err| err))
+    }
+}
+
+struct GitLog;
+
+#[async_trait]
+impl BuiltinTool for GitLog {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "git_log".to_string(),
+            description: "List the latest commits of a git repository as `{hash, author, date, subject}` entries, newest first.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "repo": {
+                        "type": "string",
+                        "description": "A directory inside the repository (defaults to the current directory)"
+                    },
+                    "cwd": {
+                        "type": "string",
+                        "description": "Alias of `repo`"
+                    },
+                    "path": {
+                        "type": "string",
+                        "description": "Only list commits touching this file or directory"
+                    },
+                    "limit": {
+                        "type": "integer",
+                        "description": "The maximum number of commits (defaults to 20)"
+                    },
+                    "count": {
+                        "type": "integer",
+                        "description": "Alias of `limit`"
+                    }
+                }
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let repo = git_repo(args);
+        Ok(git_log(repo, args)?.unwrap_or_else(|err| err))
+    }
+}
+
+struct GitCommit;
+
+#[async_trait]
+impl BuiltinTool for GitCommit {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "git_commit".to_string(),
+            description: "Stage and commit changes in a git repository, after the user reviews the files. Stages only `paths` when given, otherwise everything. Returns the new commit `hash`.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "repo": {
+                        "type": "string",
+                        "description": "A directory inside the repository (defaults to the current directory)"
+                    },
+                    "cwd": {
+                        "type": "string",
+                        "description": "Alias of `repo`"
+                    },
+                    "message": {
+                        "type": "string",
+                        "description": "The commit message"
+                    },
+                    "paths": {
+                        "type": "array",
+                        "items": { "type": "string" },
+                        "description": "The files or directories to commit (defaults to all changes)"
+                    }
+                },
+                "required": ["message"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let repo = git_repo(args);
+        Ok(git_commit(repo, args)?.unwrap_or_else(|err| err))
+    }
+}
+
+struct CommandSpawn;
+
+#[async_trait]
+impl BuiltinTool for CommandSpawn {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "command_spawn".to_string(),
+            description: "Start a long-running command, such as a dev server, in the background. Returns a `job_id` for `command_poll` and `command_kill`. Takes the same `command`, `working_directory`, `env`, `shell` and `max_output_bytes` as `command_run`.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "command": {
+                        "type": "string",
+                        "description": "The command to start"
+                    },
+                    "working_directory": {
+                        "type": "string",
+                        "description": "The directory to run the command in (defaults to the current directory)"
+                    },
+                    "env": {
+                        "type": "object",
+                        "additionalProperties": { "type": "string" },
+                        "description": "Extra environment variables for the command; they override inherited variables of the same name"
+                    },
+                    "shell": {
+                        "type": "string",
+                        "description": "Run the command through this shell: `sh`, `bash`, `zsh`, `fish`, `nu`, `pwsh`, `powershell`, `cmd` or `auto`; `none` runs it directly (defaults to the configured shell)"
+                    },
+                    "max_output_bytes": {
+                        "type": "integer",
+                        "description": "The maximum bytes kept from each of stdout and stderr, keeping their beginning and end (defaults to 204800)"
+                    }
+                },
+                "required": ["command"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, ctx: &Context<'_>) -> Result<Value> {
+        command_spawn(args, ctx.config)
+    }
+}
+
+struct CommandPoll;
+
+#[async_trait]
+impl BuiltinTool for CommandPoll {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "command_poll".to_string(),
+            description: "Check on a job started by `command_spawn`. Returns its `status` (`running` or `exited`), `exit_code` and the `stdout` and `stderr` captured so far. Once a job has exited, its final output is returned and the job id is released.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "job_id": {
+                        "type": "string",
+                        "description": "The job id returned by `command_spawn`"
+                    }
+                },
+                "required": ["job_id"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let job_id = args["job_id"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing job_id"))?;
+        Ok(job_status(job_id, false))
+    }
+}
+
+struct CommandKill;
+
+#[async_trait]
+impl BuiltinTool for CommandKill {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "command_kill".to_string(),
+            description: "Stop a job started by `command_spawn`, together with its child processes, and return its final output.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "job_id": {
+                        "type": "string",
+                        "description": "The job id returned by `command_spawn`"
+                    }
+                },
+                "required": ["job_id"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, _ctx: &Context<'_>) -> Result<Value> {
+        let job_id = args["job_id"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing job_id"))?;
+        Ok(job_status(job_id, true))
+    }
+}
+
+/// Reads the first or last lines of a file for `fs_head` and `fs_tail`.
+fn read_lines(args: &Value, head: bool) -> Result<Value> {
+    let path = args["path"]
+        .as_str()
+        .ok_or_else(|| anyhow!("Missing path"))?;
+    let count = args["lines"]
+        .as_u64()
+        .map(|v| v as usize)
+        .unwrap_or(FS_HEAD_TAIL_LINES);
+    let (bytes, complete) = if head {
+        read_head(Path::new(path), count)?
+    } else {
+        read_tail(Path::new(path), count)?
+    };
+    if is_probably_binary(&bytes) {
+        let size = fs::metadata(path)?.len();
+        return Ok(json!({ "error": "binary file", "size": size }));
+    }
+    let content = String::from_utf8_lossy(&bytes);
+    let lines = content.lines().count();
+    let mut result = json!({ "content": content, "lines": lines });
+    if complete {
+        result["total_lines"] = lines.into();
+    }
+    Ok(result)
+}
+
+/// Returns the 1-based inclusive line range `[start, end]` of `content`, clamped to its bounds.
+fn slice_lines(content: &str, start: Option<usize>, end: Option<usize>) -> String {
+    let start = start.unwrap_or(1).max(1);
+    let end = end.unwrap_or(usize::MAX);
+    if start > end {
+        return String::new();
+    }
+    content
+        .split_inclusive('\n')
+        .skip(start - 1)
+        .take(end - start + 1)
+        .collect()
+}
+
+/// Applies each `{search, replace}` edit in order. Every search string must occur exactly once
+/// in the content at that point; otherwise the per-edit diagnostics are returned instead.
+fn apply_edits(content: &str, edits: &[Value]) -> std::result::Result<String, Vec<Value>> {
+    let mut content = content.to_string();
+    let mut diagnostics = vec![];
+    let mut failed = false;
+    for (index, edit) in edits.iter().enumerate() {
+        let (Some(search), Some(replace)) = (edit["search"].as_str(), edit["replace"].as_str())
+        else {
+            failed = true;
+            diagnostics.push(json!({ "index": index, "error": "Missing search or replace" }));
+            continue;
+        };
+        let positions: Vec<usize> = if search.is_empty() {
+            vec![]
+        } else {
+            content.match_indices(search).map(|(i, _)| i).collect()
+        };
+        match positions.as_slice() {
+            [start] => {
+                content.replace_range(*start..*start + search.len(), replace);
+                diagnostics.push(json!({ "index": index, "status": "ok" }));
+            }
+            [] => {
+                failed = true;
+                diagnostics.push(json!({ "index": index, "error": "Search string not found" }));
+            }
+            _ => {
+                failed = true;
+                let lines: Vec<usize> = positions
+                    .iter()
+                    .map(|&i| line_number(&content, i))
+                    .collect();
+                diagnostics.push(json!({
+                    "index": index,
+                    "error": format!("Search string is ambiguous, found {} times", positions.len()),
+                    "lines": lines,
+                }));
+            }
+        }
+    }
+    if failed {
+        Err(diagnostics)
+    } else {
+        Ok(content)
+    }
+}
+
+fn fs_patch(args: &Value) -> Result<Value> {
+    let path = args["path"]
+        .as_str()
+        .ok_or_else(|| anyhow!("Missing path"))?;
+    let preview = args["preview"].as_bool().unwrap_or(false);
+    if let Some(edits) = args["edits"].as_array() {
+        let content = fs::read_to_string(path)?;
+        return match apply_edits(&content, edits) {
+            Ok(new_content) => finish_patch(
+                path,
+                &content,
+                &new_content,
+                preview,
+                json!({ "count": edits.len() }),
+            ),
+            Err(diagnostics) => Ok(json!({
+                "error": "Some edits could not be applied, the file was not modified",
+                "edits": diagnostics,
+            })),
+        };
+    }
+    let search = args["search"]
+        .as_str()
+        .ok_or_else(|| anyhow!("Missing search"))?;
+    let replace = args["replace"]
+        .as_str()
+        .ok_or_else(|| anyhow!("Missing replace"))?;
+    if search.is_empty() {
+        return Ok(json!({ "error": "Search string is empty" }));
+    }
+    let all = args["replace_all"]
+        .as_bool()
+        .or_else(|| args["all"].as_bool())
+        .unwrap_or(false);
+    let occurrence = args["occurrence"].as_u64().map(|v| v as usize);
+    let regex = args["regex"].as_bool().unwrap_or(false);
+    let content = fs::read_to_string(path)?;
+
+    // Each match is its byte range together with the text that replaces it.
+    let matches: Vec<(Range<usize>, String)> = if regex {
+        let re = match Regex::new(search) {
+            Ok(v) => v,
+            Err(err) => return Ok(json!({ "error": format!("Invalid regex: {err}") })),
+        };
+        re.captures_iter(&content)
+            .map(|caps| {
+                let mut dst = String::new();
+                caps.expand(replace, &mut dst);
+                (caps.get(0).unwrap().range(), dst)
+            })
+            .filter(|(range, _)| !range.is_empty())
+            .collect()
+    } else {
+        content
+            .match_indices(search)
+            .map(|(i, _)| (i..i + search.len(), replace.to_string()))
+            .collect()
+    };
+
+    if matches.is_empty() {
+        if regex {
+            return Ok(json!({ "error": "Search pattern not found in file" }));
+        }
+        let matches = fuzzy_find(&content, search);
+        return match matches.as_slice() {
+            [] => Ok(json!({ "error": "Search string not found in file" })),
+            [found] => {
+                let newline = if content.contains("\r\n") {
+                    "\r\n"
+                } else {
+                    "\n"
+                };
+                let replace = reindent(replace, &found.search_indent, &found.indent, newline);
+                let mut new_content = content.clone();
+                new_content.replace_range(found.start..found.end, &replace);
+                let result = json!({ "count": 1, "fuzzy": true });
+                finish_patch(path, &content, &new_content, preview, result)
+            }
+            _ => {
+                let lines: Vec<usize> = matches.iter().map(|v| v.line).collect();
+                Ok(json!({
+                    "error": format!(
+                        "Search string not found exactly and matches {} locations when ignoring whitespace; include more surrounding context",
+                        matches.len()
+                    ),
+                    "lines": lines,
+                }))
+            }
+        };
+    }
+
+    let selected = if all {
+        &matches[..]
+    } else {
+        match occurrence {
+            Some(n) if n == 0 || n > matches.len() => {
+                return Ok(json!({
+                    "error": format!(
+                        "Occurrence {n} is out of range, search string found {} times",
+                        matches.len()
+                    )
+                }));
+            }
+            Some(n) => &matches[n - 1..n],
+            None if matches.len() > 1 => {
+                let lines: Vec<usize> = matches
+                    .iter()
+                    .map(|(range, _)| line_number(&content, range.start))
+                    .collect();
+                return Ok(json!({
+                    "error": format!(
+                        "Search string is ambiguous, found {} times; set `occurrence` or `replace_all`, or include more surrounding context",
+                        matches.len()
+                    ),
+                    "matches": matches.len(),
+                    "lines": lines,
+                }));
+            }
+            None => &matches[..1],
+        }
+    };
+    let mut new_content = content.clone();
+    for (range, replacement) in selected.iter().rev() {
+        new_content.replace_range(range.clone(), replacement);
+    }
+    let result = json!({ "count": selected.len() });
+    finish_patch(path, &content, &new_content, preview, result)
+}
+
+/// Writes the patched content, or with `preview` leaves the file alone and returns the
+/// change as a unified diff.
+fn finish_patch(
+    path: &str,
+    content: &str,
+    new_content: &str,
+    preview: bool,
+    mut result: Value,
+) -> Result<Value> {
+    if preview {
+        result["diff"] = unified_diff(content, new_content, path, path).into();
+    } else {
+        write_atomic(Path::new(path), new_content.as_bytes())?;
+        result["success"] = true.into();
+    }
+    Ok(result)
+}
+
+struct FuzzyMatch {
+    start: usize,
+    end: usize,
+    line: usize,
+    indent: String,
+    search_indent: String,
+}
+
+/// Finds whole-line blocks of `content` equal to `search` when leading and trailing whitespace
+/// of every line is ignored. Matched ranges exclude the last line's terminator.
+fn fuzzy_find(content: &str, search: &str) -> Vec<FuzzyMatch> {
+    let search_lines: Vec<&str> = search.lines().collect();
+    let first = search_lines.iter().position(|v| !v.trim().is_empty());
+    let last = search_lines.iter().rposition(|v| !v.trim().is_empty());
+    let (Some(first), Some(last)) = (first, last) else {
+        return vec![];
+    };
+    let search_lines = &search_lines[first..=last];
+
+    let mut lines = vec![];
+    let mut offset = 0;
+    for line in content.split_inclusive('\n') {
+        let text = line.trim_end_matches(['\r', '\n']);
+        lines.push((offset, text));
+        offset += line.len();
+    }
+
+    let mut matches = vec![];
+    if search_lines.len() > lines.len() {
+        return matches;
+    }
+    for i in 0..=lines.len() - search_lines.len() {
+        let window = &lines[i..i + search_lines.len()];
+        if window
+            .iter()
+            .zip(search_lines)
+            .all(|((_, line), search)| line.trim() == search.trim())
+        {
+            let (start, text) = window[0];
+            let (last_start, last_text) = window[window.len() - 1];
+            matches.push(FuzzyMatch {
+                start,
+                end: last_start + last_text.len(),
+                line: i + 1,
+                indent: leading_whitespace(text).to_string(),
+                search_indent: leading_whitespace(search_lines[0]).to_string(),
+            });
+        }
+    }
+    matches
+}
+
+/// Re-indents `text`, whose first line is indented by `from`, to sit at the indentation `to`.
+/// Every line keeps its indentation width relative to the first line, written with tabs when
+/// `to` uses them and with spaces otherwise.
+fn reindent(text: &str, from: &str, to: &str, newline: &str) -> String {
+    let tabs = to.contains('\t');
+    let render = |width: usize| match tabs {
+        true => "\t".repeat(width / FS_PATCH_TAB_WIDTH) + &" ".repeat(width % FS_PATCH_TAB_WIDTH),
+        false => " ".repeat(width),
+    };
+    text.lines()
+        .map(|line| {
+            if line.trim().is_empty() {
+                return String::new();
+            }
+            let indent = leading_whitespace(line);
+            let indent = match indent.strip_prefix(from) {
+                Some(extra) => format!("{to}{}", render(indent_width(extra))),
+                None => render(
+                    (indent_width(to) + indent_width(indent)).saturating_sub(indent_width(from)),
+                ),
+            };
+            format!("{indent}{}", line.trim_start())
+        })
+        .collect::<Vec<_>>()
+        .join(newline)
+}
+
+/// The width of an indentation in columns, counting a tab as [`FS_PATCH_TAB_WIDTH`].
+fn indent_width(indent: &str) -> usize {
+    indent
+        .chars()
+        .map(|c| if c == '\t' { FS_PATCH_TAB_WIDTH } else { 1 })
+        .sum()
+}
+
+fn leading_whitespace(line: &str) -> &str {
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/// Returns the 1-based line number of the byte offset `index` in `content`.
+fn line_number(content: &str, index: usize) -> usize {
+    content[..index].matches('\n').count() + 1
+}
+
+/// Runs a `command_run` call to completion, killing it after its timeout, and reports its
+/// exit code and captured output.
+fn command_run(args: &Value, config: &BuiltinConfig, progress: Option<Progress>) -> Result<Value> {
+    let timeout = Duration::from_secs(
+        args["timeout_seconds"]
+            .as_u64()
+            .or_else(|| args["timeout_secs"].as_u64())
+            .unwrap_or(config.command_timeout),
+    );
+    let stdin = args["stdin"].as_str().map(|v| v.as_bytes().to_vec());
+    let max_output = args["max_output_bytes"]
+        .as_u64()
+        .or_else(|| args["max_output"].as_u64())
+        .map(|v| v as usize)
+        .unwrap_or(config.command_max_output);
+    let keep_tail = args["keep_tail"].as_bool().unwrap_or(true);
+    let (mut command, cwd) = match prepare_command(args, config)? {
+        Ok(v) => v,
+        Err(refusal) => return Ok(refusal),
+    };
+    command.stdin(if stdin.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    });
+    let mut child = command.spawn()?;
+    // Feed stdin from its own thread so a child that fills its output pipes before
+    // reading all of its input cannot deadlock us.
+    if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        std::thread::spawn(move || pipe.write_all(&input));
+    }
+    let (status, timed_out, stdout, stderr) = std::thread::scope(|scope| -> Result<_> {
+        let stdout = read_pipe(scope, child.stdout.take(), max_output, keep_tail, progress);
+        let stderr = read_pipe(scope, child.stderr.take(), max_output, keep_tail, progress);
+
+        let deadline = Instant::now() + timeout;
+        let mut timed_out = false;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                timed_out = true;
+                kill_process_group(&mut child);
+                break child.wait()?;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        };
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = stderr.join().unwrap_or_default();
+        Ok((status, timed_out, stdout, stderr))
+    })?;
+    let (stdout, stdout_truncated) = stdout.finish();
+    let (stderr, stderr_truncated) = stderr.finish();
+
+    let mut result = json!({
+        "stdout": stdout,
+        "stderr": stderr,
+        "exit_code": if timed_out { None } else { Some(status.code().unwrap_or(0)) },
+        "cwd": cwd.display().to_string(),
+        "stdout_truncated": stdout_truncated,
+        "stderr_truncated": stderr_truncated,
+    });
+    if timed_out {
+        result["timed_out"] = true.into();
+    }
+    Ok(result)
+}
+
+/// Builds the command described by `command_run` or `command_spawn` arguments, with its
+/// stdout and stderr piped, or returns the refusal to hand back to the model.
+fn prepare_command(
+    args: &Value,
+    config: &BuiltinConfig,
+) -> Result<Result<(Command, PathBuf), Value>> {
+    let command = args["command"]
+        .as_str()
+        .ok_or_else(|| anyhow!("Missing command"))?;
+    let shell = match (args["shell"].as_str(), config.command_shell.as_deref()) {
+        (Some(spec), _)
+            if !spec.trim().is_empty() && !COMMAND_RUN_SHELLS.contains(&spec.trim()) =>
+        {
+            return Ok(Err(json!({
+                "error": format!(
+                    "Unknown shell `{spec}`; use one of {}",
+                    COMMAND_RUN_SHELLS.join(", ")
+                )
+            })))
+        }
+        (Some(spec), _) | (None, Some(spec)) => shell_args(spec)?,
+        (None, None) => vec![],
+    };
+    let segments = match shell.is_empty() || config.command_run.is_empty() {
+        true => vec![command.to_string()],
+        false => match command_segments(command) {
+            Some(segments) => segments,
+            None => {
+                return Ok(Err(json!({
+                    "error": "Command substitution is not allowed while command rules are configured",
+                    "blocked": true,
+                })))
+            }
+        },
+    };
+    if let Some(refusal) = config.command_run.check(command, &segments)? {
+        return Ok(Err(refusal));
+    }
+    let working_directory = args["working_directory"]
+        .as_str()
+        .or_else(|| args["cwd"].as_str());
+    let cwd = match working_directory {
+        Some(dir) => match fs::canonicalize(dir) {
+            Ok(v) if v.is_dir() => v,
+            _ => {
+                return Ok(Err(
+                    json!({ "error": format!("Working directory not found: {dir}") }),
+                ))
+            }
+        },
+        None => env::current_dir()?,
+    };
+    let envs: Vec<(String, String)> = match args["env"].as_object() {
+        Some(env) => env
+            .iter()
+            .map(|(k, v)| match v {
+                Value::String(v) => (k.clone(), v.clone()),
+                v => (k.clone(), v.to_string()),
+            })
+            .collect(),
+        None => vec![],
+    };
+    let argv = if shell.is_empty() {
+        let words = shell_words::split(command).map_err(|e| anyhow!("Invalid command: {}", e))?;
+        if let Some(refusal) = config.command_run.check_program(&words) {
+            return Ok(Err(refusal));
+        }
+        words
+    } else {
+        if let Some(refusal) = config.command_run.check_denied(&shell) {
+            return Ok(Err(refusal));
+        }
+        if !config.command_run.is_empty() {
+            for segment in &segments {
+                let Ok(words) = shell_words::split(segment) else {
+                    return Ok(Err(json!({
+                        "error": format!("Cannot parse `{segment}` to check it against the command rules"),
+                        "blocked": true,
+                    })));
+                };
+                let start = words.iter().take_while(|v| is_assignment(v)).count();
+                if let Some(refusal) = config.command_run.check_program(&words[start..]) {
+                    return Ok(Err(refusal));
+                }
+            }
+        }
+        [shell, vec![command.to_string()]].concat()
+    };
+    let (cmd, cmd_args) = argv
+        .split_first()
+        .ok_or_else(|| anyhow!("Missing command"))?;
+    let mut command = Command::new(cmd);
+    command
+        .envs(envs)
+        .current_dir(&cwd)
+        .args(cmd_args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // Run in a process group of its own, so a timeout also kills whatever it spawned.
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    Ok(Ok((command, cwd)))
+}
+
+static JOBS: LazyLock<RwLock<HashMap<String, Job>>> = LazyLock::new(|| RwLock::new(HashMap::new()));
+static NEXT_JOB_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// A background process started by `command_spawn`.
+struct Job {
+    command: String,
+    child: Child,
+    stdout: Arc<Mutex<OutputCapture>>,
+    stderr: Arc<Mutex<OutputCapture>>,
+    readers: Vec<std::thread::JoinHandle<()>>,
+    started: Instant,
+}
+
+impl Job {
+    /// Waits up to `COMMAND_SPAWN_DRAIN_TIMEOUT` for the output of an exited job to be read to
+    /// the end. A process it left running in the background may keep the pipes open longer.
+    fn drain(&mut self) {
+        let deadline = Instant::now() + COMMAND_SPAWN_DRAIN_TIMEOUT;
+        while Instant::now() < deadline && self.readers.iter().any(|v| !v.is_finished()) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            kill_process_group(&mut self.child);
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// Kills every background job when dropped, including when aichat exits through an error or
+/// a panic.
+pub struct JobsGuard;
+
+impl Drop for JobsGuard {
+    fn drop(&mut self) {
+        kill_jobs();
+    }
+}
+
+fn command_spawn(args: &Value, config: &BuiltinConfig) -> Result<Value> {
+    let max_output = args["max_output_bytes"]
+        .as_u64()
+        .map(|v| v as usize)
+        .unwrap_or(config.command_max_output);
+    let (mut command, cwd) = match prepare_command(args, config)? {
+        Ok(v) => v,
+        Err(refusal) => return Ok(refusal),
+    };
+    let mut child = command.stdin(Stdio::null()).spawn()?;
+    let stdout = Arc::new(Mutex::new(OutputCapture::new(max_output, true)));
+    let stderr = Arc::new(Mutex::new(OutputCapture::new(max_output, true)));
+    let readers = [
+        capture_pipe(child.stdout.take(), stdout.clone()),
+        capture_pipe(child.stderr.take(), stderr.clone()),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    let job_id = format!("job-{}", NEXT_JOB_ID.fetch_add(1, Ordering::SeqCst));
+    let job = Job {
+        command: args["command"].as_str().unwrap_or_default().to_string(),
+        child,
+        stdout,
+        stderr,
+        readers,
+        started: Instant::now(),
+    };
+    let pid = job.child.id();
+    JOBS.write().unwrap().insert(job_id.clone(), job);
+    Ok(json!({
+        "job_id": job_id,
+        "pid": pid,
+        "cwd": cwd.display().to_string(),
+    }))
+}
+
+/// Reports on a background job, killing it first when `kill` is set. A job that has exited
+/// is dropped from the table once its final output is reported; other exited jobs are
+/// reaped along the way, so none is left a zombie.
+fn job_status(job_id: &str, kill: bool) -> Value {
+    let mut jobs = JOBS.write().unwrap();
+    for job in jobs.values_mut() {
+        let _ = job.child.try_wait();
+    }
+    let Some(job) = jobs.get_mut(job_id) else {
+        return json!({ "error": format!("No such job: {job_id}") });
+    };
+    let mut status = job.child.try_wait().ok().flatten();
+    if kill && status.is_none() {
+        kill_process_group(&mut job.child);
+        status = job.child.wait().ok();
+    }
+    if status.is_some() {
+        job.drain();
+    }
+    let (stdout, stdout_truncated) = job.stdout.lock().unwrap().finish();
+    let (stderr, stderr_truncated) = job.stderr.lock().unwrap().finish();
+    let result = json!({
+        "job_id": job_id,
+        "command": job.command,
+        "status": if status.is_some() { "exited" } else { "running" },
+        "exit_code": status.and_then(|v| v.code()),
+        "elapsed_seconds": job.started.elapsed().as_secs(),
+        "stdout": stdout,
+        "stderr": stderr,
+        "stdout_truncated": stdout_truncated,
+        "stderr_truncated": stderr_truncated,
+    });
+    if status.is_some() {
+        jobs.remove(job_id);
+    }
+    result
+}
+
+/// Kills every background job still running, so none outlives aichat.
+pub fn kill_jobs() {
+    JOBS.write().unwrap().clear();
+}
+
+/// Drains `pipe` on a background thread into a capture shared with its job.
+fn capture_pipe(
+    pipe: Option<impl Read + Send + 'static>,
+    output: Arc<Mutex<OutputCapture>>,
+) -> Option<std::thread::JoinHandle<()>> {
+    let mut pipe = pipe?;
+    Some(std::thread::spawn(move || {
+        let mut buf = [0; 8192];
+        while let Ok(n) = pipe.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            output.lock().unwrap().push(&buf[..n]);
+        }
+    }))
+}
+
+/// The repository directory of a git tool call: `repo`, or its alias `cwd`.
+fn git_repo(args: &Value) -> &Path {
+    Path::new(
+        args["repo"]
+            .as_str()
+            .or_else(|| args["cwd"].as_str())
+            .unwrap_or("."),
+    )
+}
+
+/// Runs git in `repo`, returning its stdout, or the error result to hand back to the model
+/// when git fails, such as outside of a repository. Git runs in the C locale, so its messages
+/// can be recognized whatever the user's language.
+fn git(repo: &Path, args: &[&str]) -> Result<Result<String, Value>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(args)
+        .env("LC_ALL", "C")
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| anyhow!("Failed to run git: {e}"))?;
+    if output.status.success() {
+        return Ok(Ok(String::from_utf8_lossy(&output.stdout).to_string()));
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    if stderr.contains("not a git repository") {
+        return Ok(Err(json!({
+            "error": "not a git repository",
+            "not_a_repository": true,
+            "repo": repo.display().to_string(),
+        })));
+    }
+    Ok(Err(json!({ "error": stderr })))
+}
+
+fn git_status(repo: &Path) -> Result<Result<Value, Value>> {
+    let output = match git(
+        repo,
+        &[
+            "status",
+            "--porcelain=v1",
+            "-z",
+            "--branch",
+            "--untracked-files=all",
+        ],
+    )? {
+        Ok(v) => v,
+        Err(err) => return Ok(Err(err)),
+    };
+    let mut branch = None;
+    let mut files = vec![];
+    let mut entries = output.split('\0').filter(|v| !v.is_empty());
+    while let Some(entry) = entries.next() {
+        if let Some(header) = entry.strip_prefix("## ") {
+            let name = header.split("...").next().unwrap_or(header);
+            let name = name.strip_prefix("No commits yet on ").unwrap_or(name);
+            branch = Some(name.to_string());
+            continue;
+        }
+        let (Some(code), Some(path)) = (entry.get(..2), entry.get(3..)) else {
+            continue;
+        };
+        let (index, worktree) = (code.as_bytes()[0], code.as_bytes()[1]);
+        let status = match (index, worktree) {
+            (b'?', b'?') => "untracked",
+            (b'!', b'!') => "ignored",
+            (b'U', _) | (_, b'U') | (b'A', b'A') | (b'D', b'D') => "conflicted",
+            (b' ', v) | (v, _) => match v {
+                b'M' => "modified",
+                b'A' => "added",
+                b'D' => "deleted",
+                b'R' => "renamed",
+                b'C' => "copied",
+                b'T' => "type_changed",
+                _ => "unknown",
+            },
+        };
+        let mut file = json!({
+            "path": path,
+            "status": status,
+            "code": code,
+            "staged": !matches!(index, b' ' | b'?' | b'!'),
+        });
+        // Renames and copies are followed by the original path.
+        if matches!(index, b'R' | b'C') {
+            file["orig_path"] = entries.next().unwrap_or_default().into();
+        }
+        files.push(file);
+    }
+    Ok(Ok(json!({ "branch": branch, "files": files })))
+}
+
+fn git_diff(repo: &Path, args: &Value) -> Result<Result<Value, Value>> {
+    let max_bytes = args["max_bytes"]
+        .as_u64()
+        .map(|v| v as usize)
+        .unwrap_or(FS_DIFF_MAX_BYTES);
+    let mut git_args = vec!["diff", "--no-color", "--no-ext-diff"];
+    if args["staged"].as_bool() == Some(true) {
+        git_args.push("--cached");
+    }
+    if let Some(path) = args["path"].as_str() {
+        git_args.extend(["--", path]);
+    }
+    let mut diff = match git(repo, &git_args)? {
+        Ok(v) => v,
+        Err(err) => return Ok(Err(err)),
+    };
+    let files = diff_files(&diff);
+    let truncated = diff.len() > max_bytes;
+    if truncated {
+        truncate_at_char_boundary(&mut diff, max_bytes);
+    }
+    Ok(Ok(
+        json!({ "diff": diff, "files": files, "truncated": truncated }),
+    ))
+}
+
+/// Summarizes each file of a git diff with the position and size of its hunks. Files
+/// without content changes, such as pure renames, are left out.
+fn diff_files(diff: &str) -> Vec<Value> {
+    let Ok(patches) = parse_unified_diff(diff) else {
+        return vec![];
+    };
+    patches
+        .iter()
+        .map(|patch| {
+            let count =
+                |hunk: &Hunk, f: fn(&HunkLine) -> bool| hunk.lines.iter().filter(|v| f(v)).count();
+            let hunks: Vec<Value> = patch
+                .hunks
+                .iter()
+                .map(|hunk| {
+                    let added = count(hunk, |v| matches!(v, HunkLine::Add(_)));
+                    let removed = count(hunk, |v| matches!(v, HunkLine::Remove(_)));
+                    let context = count(hunk, |v| matches!(v, HunkLine::Context(_)));
+                    json!({
+                        "old_start": hunk.old_start,
+                        "old_lines": context + removed,
+                        "new_start": hunk.new_start,
+                        "new_lines": context + added,
+                        "added": added,
+                        "removed": removed,
+                    })
+                })
+                .collect();
+            let status = match (&patch.old_path, &patch.new_path) {
+                (None, _) => "added",
+                (_, None) => "deleted",
+                (old, new) if old != new => "renamed",
+                _ => "modified",
+            };
+            let mut file = json!({
+                "path": patch.new_path.as_ref().or(patch.old_path.as_ref()),
+                "status": status,
+                "hunks": hunks,
+            });
+            if status == "renamed" {
+                file["orig_path"] = patch.old_path.clone().into();
+            }
+            file
+        })
+        .collect()
+}
+
+fn git_log(repo: &Path, args: &Value) -> Result<Result<Value, Value>> {
+    let limit = args["limit"]
+        .as_u64()
+        .or_else(|| args["count"].as_u64())
+        .map(|v| v as usize)
+        .unwrap_or(GIT_LOG_LIMIT)
+        .to_string();
+    let mut git_args = vec!["log", "-n", &limit, "--format=%H%x1f%an%x1f%aI%x1f%s%x1e"];
+    if let Some(path) = args["path"].as_str() {
+        git_args.extend(["--", path]);
+    }
+    let output = match git(repo, &git_args)? {
+        Ok(v) => v,
+        Err(err)
+            if err["error"]
+                .as_str()
+                .unwrap_or_default()
+                .contains("does not have any commits") =>
+        {
+            return Ok(Ok(json!({ "commits": [] })))
+        }
+        Err(err) => return Ok(Err(err)),
+    };
+    let commits: Vec<Value> = output
+        .split('\x1e')
+        .filter_map(|record| {
+            let mut fields = record.trim_start_matches('\n').split('\x1f');
+            let (hash, author, date, subject) = (
+                fields.next()?,
+                fields.next()?,
+                fields.next()?,
+                fields.next()?,
+            );
+            Some(json!({
+                "hash": hash,
+                "author": author,
+                "date": date,
+                "subject": subject,
+            }))
+        })
+        .collect();
+    Ok(Ok(json!({ "commits": commits })))
+}
+
+fn git_commit(repo: &Path, args: &Value) -> Result<Result<Value, Value>> {
+    let message = args["message"]
+        .as_str()
+        .ok_or_else(|| anyhow!("Missing message"))?;
+    if message.trim().is_empty() {
+        return Ok(Err(json!({ "error": "The commit message is empty" })));
+    }
+    if let Err(err) = git(repo, &["rev-parse", "--git-dir"])? {
+        return Ok(Err(err));
+    }
+    for key in ["user.name", "user.email"] {
+        match git(repo, &["config", key])? {
+            Ok(v) if !v.trim().is_empty() => {}
+            _ => {
+                return Ok(Err(json!({
+                    "error": format!("{key} is not set, configure it with `git config {key}`"),
+                    "missing_config": key,
+                })))
+            }
+        }
+    }
+    let paths = commit_paths(args);
+    let mut add_args = vec!["add"];
+    if paths.is_empty() {
+        add_args.push("-A");
+    }
+    add_args.push("--");
+    add_args.extend(&paths);
+    if let Err(err) = git(repo, &add_args)? {
+        return Ok(Err(err));
+    }
+    let mut diff_args = vec!["diff", "--cached", "--quiet", "--"];
+    diff_args.extend(&paths);
+    if git(repo, &diff_args)?.is_ok() {
+        return Ok(Err(json!({
+            "error": "nothing to commit",
+            "nothing_to_commit": true,
+        })));
+    }
+    // With paths, commit only those, leaving anything else that was staged for later.
+    let mut commit_args = vec!["commit", "-q", "-m", message, "--"];
+    commit_args.extend(&paths);
+    if let Err(err) = git(repo, &commit_args)? {
+        return Ok(Err(err));
+    }
+    let hash = match git(repo, &["rev-parse", "HEAD"])? {
+        Ok(v) => v.trim().to_string(),
+        Err(err) => return Ok(Err(err)),
+    };
+    let stat = git(repo, &["show", "--stat", "--format=", "HEAD"])?.unwrap_or_default();
+    Ok(Ok(json!({ "hash": hash, "stat": stat.trim() })))
+}
+
+fn commit_paths(args: &Value) -> Vec<&str> {
+    args["paths"]
+        .as_array()
+        .map(|v| v.iter().filter_map(|v| v.as_str()).collect())
+        .unwrap_or_default()
+}
+
+/// Resolves a shell setting to the program and arguments that precede the command. Known
+/// shell names get their usual arguments; anything else is taken as a full command line.
+/// On Windows, `auto` picks `pwsh`, then `powershell`, then `cmd`.
+fn shell_args(spec: &str) -> Result<Vec<String>> {
+    let args = |args: &[&str]| args.iter().map(|v| v.to_string()).collect();
+    let shell = match spec.trim() {
+        "none" | "" => vec![],
+        "auto" if cfg!(windows) => match ["pwsh", "powershell"]
+            .into_iter()
+            .find(|v| which::which(v).is_ok())
+        {
+            Some(name) => args(&[name, "-NoProfile", "-Command"]),
+            None => args(&["cmd", "/C"]),
+        },
+        "auto" => match SHELL.name.as_str() {
+            "pwsh" | "powershell" => {
+                vec![SHELL.cmd.clone(), "-NoProfile".into(), "-Command".into()]
+            }
+            _ => vec![SHELL.cmd.clone(), SHELL.arg.clone()],
+        },
+        name @ ("sh" | "bash" | "zsh" | "fish" | "nu") => args(&[name, "-c"]),
+        name @ ("pwsh" | "powershell") => args(&[name, "-NoProfile", "-Command"]),
+        "cmd" => args(&["cmd", "/C"]),
+        spec => shell_words::split(spec).map_err(|e| anyhow!("Invalid shell `{spec}`: {e}"))?,
+    };
+    Ok(shell)
+}
+
+/// Drains `pipe` on a scoped thread, passing each complete line to `progress` as it arrives.
+/// A line longer than `COMMAND_RUN_MAX_PROGRESS_LINE` bytes is passed on in pieces, so output
+/// without newlines, such as a progress bar redrawn with `\r`, cannot grow without bound.
+fn read_pipe<'scope>(
+    scope: &'scope std::thread::Scope<'scope, '_>,
+    pipe: Option<impl Read + Send + 'scope>,
+    max_bytes: usize,
+    keep_tail: bool,
+    progress: Option<Progress<'scope>>,
+) -> std::thread::ScopedJoinHandle<'scope, OutputCapture> {
+    scope.spawn(move || {
+        let mut output = OutputCapture::new(max_bytes, keep_tail);
+        let mut line = vec![];
+        if let Some(mut pipe) = pipe {
+            let mut buf = [0; 8192];
+            while let Ok(n) = pipe.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                output.push(&buf[..n]);
+                if let Some(progress) = progress {
+                    for chunk in buf[..n].split_inclusive(|&b| b == b'\n') {
+                        line.extend_from_slice(chunk);
+                        if line.ends_with(b"\n") {
+                            progress(&String::from_utf8_lossy(&line));
+                            line.clear();
+                            continue;
+                        }
+                        while line.len() >= COMMAND_RUN_MAX_PROGRESS_LINE {
+                            // Keep a character split by the cut for the next piece.
+                            let piece = &line[..COMMAND_RUN_MAX_PROGRESS_LINE];
+                            let end = match std::str::from_utf8(piece) {
+                                Err(err) if err.error_len().is_none() => err.valid_up_to(),
+                                _ => piece.len(),
+                            };
+                            let mut piece: Vec<u8> = line.drain(..end).collect();
+                            piece.push(b'\n');
+                            progress(&String::from_utf8_lossy(&piece));
+                        }
+                    }
+                }
+            }
+        }
+        if let (Some(progress), false) = (progress, line.is_empty()) {
+            line.push(b'\n');
+            progress(&String::from_utf8_lossy(&line));
+        }
+        output
+    })
+}
+
+/// Keeps the first and last halves of at most `max_bytes` of output, or just the first
+/// `max_bytes` without `keep_tail`, so a command producing megabytes never holds more than
+/// that in memory.
+#[derive(Default)]
+struct OutputCapture {
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+    head_limit: usize,
+    tail_limit: usize,
+    total: usize,
+}
+
+impl OutputCapture {
+    fn new(max_bytes: usize, keep_tail: bool) -> Self {
+        let head_limit = if keep_tail { max_bytes / 2 } else { max_bytes };
+        Self {
+            head_limit,
+            tail_limit: max_bytes - head_limit,
+            ..Default::default()
+        }
+    }
+
+    fn push(&mut self, mut bytes: &[u8]) {
+        self.total += bytes.len();
+        let n = bytes.len().min(self.head_limit - self.head.len());
+        self.head.extend_from_slice(&bytes[..n]);
+        bytes = &bytes[n..];
+        self.tail.extend(bytes);
+        let excess = self.tail.len().saturating_sub(self.tail_limit);
+        self.tail.drain(..excess);
+    }
+
+    fn finish(&self) -> (String, bool) {
+        let mut output = self.head.clone();
+        output.extend(&self.tail);
+        if self.total == output.len() {
+            return (String::from_utf8_lossy(&output).into_owned(), false);
+        }
+        let (head, tail) = output.split_at(self.head.len());
+        // Move both cuts to character boundaries rather than splitting a character.
+        let head = match std::str::from_utf8(head) {
+            Err(err) if err.error_len().is_none() => &head[..err.valid_up_to()],
+            _ => head,
+        };
+        let partial = tail
+            .iter()
+            .take(3)
+            .take_while(|&&b| b & 0xc0 == 0x80)
+            .count();
+        let tail = &tail[partial..];
+        let omitted = self.total - head.len() - tail.len();
+        let (head, tail) = (String::from_utf8_lossy(head), String::from_utf8_lossy(tail));
+        (
+            format!("{head}\n...[truncated {omitted} bytes]\n{tail}"),
+            true,
+        )
+    }
+}
+
+#[cfg(unix)]
+fn kill_process_group(child: &mut Child) {
+    // SAFETY: `killpg` has no memory-safety preconditions; the child leads its own group.
+    unsafe {
+        libc::killpg(child.id() as libc::pid_t, libc::SIGKILL);
+    }
+    let _ = child.kill();
+}
+
+#[cfg(not(unix))]
+fn kill_process_group(child: &mut Child) {
+    let _ = child.kill();
+}
+
+/// Resolves the path and directory arguments of a tool with [`jail_path`]. A listing
+/// without a `path`, a git tool without a `repo` and a command without a working directory
+/// start at the root rather than the current directory.
+fn jail_args(name: &str, args: &Value, root: &Path) -> Result<Value> {
+    let mut args = args.clone();
+    let is_command = matches!(name, "command_run" | "command_spawn");
+    let has_cwd = args["cwd"].is_string()
+        || args["repo"].is_string()
+        || args["working_directory"].is_string();
+    for key in [
+        "path",
+        "other_path",
+        "from",
+        "to",
+        "repo",
+        "cwd",
+        "working_directory",
+    ] {
+        let path = match args[key].as_str() {
+            Some(path) => path,
+            None if key == "path" && matches!(name, "fs_ls" | "fs_tree" | "fs_find") => ".",
+            None if key == "repo" && name.starts_with("git_") && !has_cwd => ".",
+            None if key == "working_directory" && is_command && !has_cwd => ".",
+            None => continue,
+        };
+        args[key] = jail_path(path, root)?.display().to_string().into();
+    }
+    Ok(args)
+}
+
+/// Resolves `path` against the sandbox `root`, following symlinks, and fails when the result
+/// lies outside of it. A path that does not exist yet resolves through its nearest existing
+/// ancestor.
+fn jail_path(path: &str, root: &Path) -> Result<PathBuf> {
+    let root = fs::canonicalize(root)
+        .map_err(|e| anyhow!("Invalid sandbox root {}: {e}", root.display()))?;
+    let mut existing = root.join(path);
+    let mut missing = vec![];
+    let mut resolved = loop {
+        if let Ok(v) = fs::canonicalize(&existing) {
+            break v;
+        }
+        if fs::symlink_metadata(&existing).is_ok() {
+            bail!("Path {path} is a broken symlink");
+        }
+        match (existing.file_name(), existing.parent()) {
+            (Some(name), Some(parent)) => {
+                missing.push(name.to_os_string());
+                existing = parent.to_path_buf();
+            }
+            _ => bail!("Invalid path: {path}"),
+        }
+    };
+    resolved.extend(missing.iter().rev());
+    if !resolved.starts_with(&root) {
+        bail!("Path {path} is outside the sandbox root {}", root.display());
+    }
+    Ok(resolved)
+}
+
+/// Applies a unified diff file by file. A file is only written when all of its hunks
+/// apply, so a failing hunk leaves that file untouched.
+fn fs_apply_patch(patch: &str, root: Option<&Path>) -> Result<Value> {
+    let mut patches = match parse_unified_diff(patch) {
+        Ok(v) => v,
+        Err(err) => return Ok(json!({ "error": format!("Invalid patch: {err}") })),
+    };
+    if let Some(root) = root {
+        for patch in &mut patches {
+            for path in [&mut patch.old_path, &mut patch.new_path]
+                .into_iter()
+                .flatten()
+            {
+                *path = jail_path(path, root)?.display().to_string();
+            }
+        }
+    }
+    let mut files = vec![];
+    let mut success = true;
+    for patch in &patches {
+        let Some(path) = patch.new_path.as_ref().or(patch.old_path.as_ref()) else {
+            continue;
+        };
+        let failed = |reason: String| {
+            json!({
+                "path": path,
+                "status": "failed",
+                "hunks_applied": 0,
+                "rejected": [{ "reason": reason }],
+            })
+        };
+        let content = match &patch.old_path {
+            None if Path::new(path).exists() => {
+                success = false;
+                files.push(failed("File already exists".into()));
+                continue;
+            }
+            None => String::new(),
+            Some(old_path) => match fs::read_to_string(old_path) {
+                Ok(v) => v,
+                Err(err) => {
+                    success = false;
+                    files.push(failed(format!("Failed to read {old_path}: {err}")));
+                    continue;
+                }
+            },
+        };
+        let (new_content, results) = apply_file_patch(&content, patch);
+        let applied: Vec<Value> = results
+            .iter()
+            .enumerate()
+            .filter_map(|(i, v)| match v {
+                HunkResult::Applied { line, fuzz } => Some(json!({
+                    "hunk": i + 1,
+                    "line": line,
+                    "fuzz": fuzz,
+                })),
+                HunkResult::Rejected { .. } => None,
+            })
+            .collect();
+        let rejected: Vec<Value> = results
+            .iter()
+            .enumerate()
+            .filter_map(|(i, v)| match v {
+                HunkResult::Rejected { old_start, reason } => Some(json!({
+                    "hunk": i + 1,
+                    "old_start": old_start,
+                    "reason": reason,
+                })),
+                HunkResult::Applied { .. } => None,
+            })
+            .collect();
+        let status = match (new_content, &patch.old_path, &patch.new_path) {
+            (None, _, _) => "failed",
+            (Some(_), Some(old_path), None) => {
+                fs::remove_file(old_path)?;
+                "deleted"
+            }
+            (Some(new_content), old_path, Some(new_path)) => {
+                let new_path = Path::new(new_path);
+                if let Some(parent) = new_path.parent().filter(|v| !v.as_os_str().is_empty()) {
+                    fs::create_dir_all(parent)?;
+                }
+                write_atomic(new_path, new_content.as_bytes())?;
+                match old_path {
+                    None => "created",
+                    Some(old_path) => {
+                        if Path::new(old_path) != new_path {
+                            fs::remove_file(old_path)?;
+                        }
+                        "modified"
+                    }
+                }
+            }
+            (Some(_), None, None) => unreachable!(),
+        };
+        if status == "failed" {
+            success = false;
+        }
+        files.push(json!({
+            "path": path,
+            "status": status,
+            "hunks_applied": applied.len(),
+            "applied": applied,
+            "rejected": rejected,
+        }));
+    }
+    Ok(json!({ "success": success, "files": files }))
+}
+
+/// Reads the first `count` lines of a file. The flag tells whether the whole file was read.
+fn read_head(path: &Path, count: usize) -> Result<(Vec<u8>, bool)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut bytes = vec![];
+    for _ in 0..count {
+        if reader.read_until(b'\n', &mut bytes)? == 0 {
+            break;
+        }
+    }
+    let complete = reader.fill_buf()?.is_empty();
+    Ok((bytes, complete))
+}
+
+/// Reads the last `count` lines of a file by scanning backwards from the end in chunks, so
+/// only the tail of a large file is loaded. The flag tells whether the whole file was read.
+fn read_tail(path: &Path, count: usize) -> Result<(Vec<u8>, bool)> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if count == 0 {
+        return Ok((vec![], len == 0));
+    }
+    let mut bytes: Vec<u8> = vec![];
+    let mut pos = len;
+    let mut newlines = 0;
+    while pos > 0 {
+        let size = FS_TAIL_CHUNK_SIZE.min(pos);
+        pos -= size;
+        let mut chunk = vec![0; size as usize];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut chunk)?;
+        // The newline ending the last line does not start a new line.
+        let scan = if pos + size == len && chunk.last() == Some(&b'\n') {
+            &chunk[..chunk.len() - 1]
+        } else {
+            &chunk[..]
+        };
+        let start = scan
+            .iter()
+            .rev()
+            .position(|&v| {
+                newlines += usize::from(v == b'\n');
+                newlines >= count
+            })
+            .map(|offset| scan.len() - offset);
+        chunk.extend_from_slice(&bytes);
+        bytes = chunk;
+        if let Some(start) = start {
+            return Ok((bytes.split_off(start), false));
+        }
+    }
+    Ok((bytes, true))
+}
+
+/// Appends to `path`, creating it if missing, and returns the resulting file size.
+fn append_file(path: &Path, contents: &[u8]) -> Result<u64> {
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    file.write_all(contents)?;
+    Ok(file.metadata()?.len())
+}
+
+/// Writes to a temporary file next to `path` and renames it into place, so readers never
+/// observe a partially written file. The permissions of an existing file are preserved.
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid path: {}", path.display()))?;
+    let temp_path = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        uuid::Uuid::new_v4()
+    ));
+    let permissions = fs::metadata(&path).ok().map(|v| v.permissions());
+    let write = || -> std::io::Result<()> {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        if let Some(permissions) = permissions {
+            fs::set_permissions(&temp_path, permissions)?;
+        }
+        fs::rename(&temp_path, &path)
+    };
+    if let Err(err) = write() {
+        let _ = fs::remove_file(&temp_path);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Returns why `path` must not be removed, if it is one of the directories whose removal
+/// would be catastrophic, or the sandbox `root` itself or a path outside of it.
+fn removal_refusal(path: &Path, root: Option<&Path>) -> Option<String> {
+    let resolved = match (path.parent(), path.file_name()) {
+        // Resolve the parent only, so a symlink is judged by where it lives. A path ending
+        // in `..` has no file name and is resolved whole.
+        (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => {
+            fs::canonicalize(parent).map(|v| v.join(name))
+        }
+        _ => fs::canonicalize(path),
+    };
+    let Ok(path) = resolved else {
+        return Some(format!(
+            "Refusing to remove {}, it cannot be resolved",
+            path.display()
+        ));
+    };
+    let canonical = |v: Option<PathBuf>| v.and_then(|v| fs::canonicalize(v).ok());
+    if path.parent().is_none() {
+        return Some(format!(
+            "Refusing to remove the filesystem root {}",
+            path.display()
+        ));
+    }
+    if canonical(dirs::home_dir()).is_some_and(|v| v == path) {
+        return Some(format!(
+            "Refusing to remove the home directory {}",
+            path.display()
+        ));
+    }
+    if canonical(env::current_dir().ok()).is_some_and(|v| v.starts_with(&path)) {
+        return Some(format!(
+            "Refusing to remove the current directory or one of its parents {}",
+            path.display()
+        ));
+    }
+    let root = canonical(root.map(|v| v.to_path_buf()))?;
+    if path == root {
+        return Some(format!(
+            "Refusing to remove the sandbox root {}",
+            path.display()
+        ));
+    }
+    if !path.starts_with(&root) {
+        return Some(format!(
+            "Refusing to remove {}, it is outside the sandbox root",
+            path.display()
+        ));
+    }
+    None
+}
+
+fn count_files(path: &Path) -> u64 {
+    match fs::read_dir(path) {
+        Ok(entries) => entries
+            .flatten()
+            .map(|entry| match entry.file_type() {
+                Ok(v) if v.is_dir() => count_files(&entry.path()),
+                _ => 1,
+            })
+            .sum(),
+        Err(_) => 0,
+    }
+}
+
+/// Resolves `path` to an absolute location whose existing parent directories are canonical, so
+/// different spellings of the same path compare equal. The final component is not resolved,
+/// so a symlink is judged by where it lives rather than by what it points to.
+fn resolve_location(path: &Path) -> Result<PathBuf> {
+    let path = PathBuf::from(to_absolute_path(&path.to_string_lossy())?);
+    let (Some(name), Some(parent)) = (path.file_name(), path.parent()) else {
+        return Ok(path);
+    };
+    let mut base = parent.to_path_buf();
+    let mut missing = vec![];
+    let base = loop {
+        match fs::canonicalize(&base) {
+            Ok(v) => break v,
+            Err(_) => match (base.file_name(), base.parent()) {
+                (Some(name), Some(parent)) => {
+                    missing.push(name.to_owned());
+                    base = parent.to_path_buf();
+                }
+                _ => break base,
+            },
+        }
+    };
+    Ok(missing
+        .iter()
+        .rev()
+        .fold(base, |v, name| v.join(name))
+        .join(name))
+}
+
+/// Makes `to` ready to receive a move or copy of `source` (an already resolved path): refuses
+/// a destination that is the source itself, lies inside it or contains it, clears an existing
+/// destination when `overwrite` is set, and creates missing parent directories.
+fn prepare_destination(source: &Path, to: &str, overwrite: bool) -> Result<Result<(), Value>> {
+    let target = resolve_location(Path::new(to))?;
+    let error = if target == source {
+        Some(format!("Source and destination are the same path: {to}"))
+    } else if target.starts_with(source) {
+        Some(format!("Destination is inside the source: {to}"))
+    } else if source.starts_with(&target) {
+        Some(format!("Destination contains the source: {to}"))
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        return Ok(Err(json!({ "error": error })));
+    }
+    if let Ok(metadata) = fs::symlink_metadata(&target) {
+        if !overwrite {
+            return Ok(Err(json!({
+                "error": format!("Destination already exists: {to}, set `overwrite` to replace it")
+            })));
+        }
+        remove_path(&target, &metadata)?;
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(Ok(()))
+}
+
+fn remove_path(path: &Path, metadata: &fs::Metadata) -> std::io::Result<()> {
+    if metadata.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Copies a file or a whole directory tree, returning the number of files and bytes copied.
+/// Symlinks are recreated as links rather than followed.
+fn copy_recursive(from: &Path, to: &Path) -> Result<(u64, u64)> {
+    let file_type = fs::symlink_metadata(from)?.file_type();
+    if file_type.is_symlink() {
+        copy_symlink(from, to)?;
+        return Ok((1, 0));
+    }
+    if !file_type.is_dir() {
+        let bytes = fs::copy(from, to)?;
+        return Ok((1, bytes));
+    }
+    fs::create_dir_all(to)?;
+    let (mut files, mut bytes) = (0, 0);
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let (n, size) = copy_recursive(&entry.path(), &to.join(entry.file_name()))?;
+        files += n;
+        bytes += size;
+    }
+    Ok((files, bytes))
+}
+
+#[cfg(unix)]
+fn copy_symlink(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(from)?, to)
+}
+
+#[cfg(windows)]
+fn copy_symlink(from: &Path, to: &Path) -> std::io::Result<()> {
+    let target = fs::read_link(from)?;
+    if fs::metadata(from).is_ok_and(|v| v.is_dir()) {
+        std::os::windows::fs::symlink_dir(target, to)
+    } else {
+        std::os::windows::fs::symlink_file(target, to)
+    }
+}
+
+struct TreeOptions {
+    max_depth: usize,
+    max_entries: usize,
+    ignore: Vec<String>,
+}
+
+#[derive(Default)]
+struct TreeOutput {
+    tree: String,
+    entries: usize,
+    truncated: bool,
+}
+
+fn render_tree(root: &Path, options: &TreeOptions, output: &mut TreeOutput) {
+    let ignore = options.ignore.clone();
+    let walker = WalkBuilder::new(root)
+        .hidden(false)
+        .require_git(false)
+        .max_depth(Some(options.max_depth))
+        .filter_entry(move |v| {
+            v.depth() == 0 || !ignore.iter().any(|name| v.file_name() == name.as_str())
+        })
+        .sort_by_file_path(|a, b| b.is_dir().cmp(&a.is_dir()).then_with(|| a.cmp(b)))
+        .build();
+
+    // (depth, name, is_dir), with one extra entry to detect truncation.
+    let mut entries: Vec<(usize, String, bool)> = walker
+        .flatten()
+        .filter(|v| v.depth() > 0)
+        .take(options.max_entries.saturating_add(1))
+        .map(|v| {
+            let is_dir = v.file_type().is_some_and(|v| v.is_dir());
+            (
+                v.depth(),
+                v.file_name().to_string_lossy().to_string(),
+                is_dir,
+            )
+        })
+        .collect();
+
+    if entries.len() > options.max_entries {
+        entries.truncate(options.max_entries);
+        output.truncated = true;
+    }
+
+    // An entry is the last of its siblings if no later entry shares its depth before the
+    // walk climbs back to its parent. Past a truncation, assume more siblings follow.
+    let mut is_last = vec![false; entries.len()];
+    let mut has_next = vec![output.truncated; options.max_depth + 1];
+    for (i, (depth, _, _)) in entries.iter().enumerate().rev() {
+        is_last[i] = !has_next[*depth];
+        has_next[*depth] = true;
+        has_next[*depth + 1..].fill(false);
+    }
+
+    let mut ancestors: Vec<bool> = vec![];
+    for (i, (depth, name, is_dir)) in entries.iter().enumerate() {
+        ancestors.truncate(depth - 1);
+        for last in &ancestors {
+            output.tree.push_str(if *last { "    " } else { "│   " });
+        }
+        let connector = if is_last[i] {
+            "└── "
+        } else {
+            "├── "
+        };
+        let suffix = if *is_dir { "/" } else { "" };
+        output
+            .tree
+            .push_str(&format!("{connector}{name}{suffix}\n"));
+        ancestors.push(is_last[i]);
+    }
+    output.entries = entries.len();
+    if output.truncated {
+        output.tree.push_str(&format!(
+            "... truncated after {} entries, narrow `path` or lower `max_depth`\n",
+            output.entries
+        ));
+    }
+}
+
+fn file_entry(name: &str, metadata: &fs::Metadata) -> Value {
+    json!({
+        "name": name,
+        "type": file_type_name(&metadata.file_type()),
+        "size": metadata.is_file().then_some(metadata.len()),
+        "modified": metadata.modified().ok().map(format_time),
+    })
+}
+
+fn file_type_name(file_type: &fs::FileType) -> &'static str {
+    if file_type.is_symlink() {
+        "symlink"
+    } else if file_type.is_dir() {
+        "dir"
+    } else {
+        "file"
+    }
+}
+
+#[cfg(unix)]
+fn permissions_octal(metadata: &fs::Metadata) -> Option<String> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(format!("{:04o}", metadata.permissions().mode() & 0o7777))
+}
+
+#[cfg(not(unix))]
+fn permissions_octal(_metadata: &fs::Metadata) -> Option<String> {
+    None
+}
+
+fn format_time(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Local>::from(time)
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, false)
+}
+
+/// Heuristically detects binary content by looking for NUL bytes in the first 8 KiB.
+fn is_probably_binary(bytes: &[u8]) -> bool {
+    bytes.iter().take(8192).any(|&b| b == 0)
+}
+
+/// Decodes `bytes` with the given encoding, or sniffs a BOM and falls back to UTF-8.
+/// Returns `None` when no encoding is given and the content looks binary. A `partial` read may
+/// end partway through a UTF-8 character, which is dropped rather than treated as invalid.
+fn decode_text(
+    bytes: &[u8],
+    encoding: Option<&'static Encoding>,
+    partial: bool,
+) -> Option<(String, &'static Encoding)> {
+    if let Some(encoding) = encoding {
+        let (text, _) = encoding.decode_with_bom_removal(bytes);
+        return Some((text.into_owned(), encoding));
+    }
+    if let Some((encoding, bom_len)) = Encoding::for_bom(bytes) {
+        let text = encoding.decode_without_bom_handling(&bytes[bom_len..]).0;
+        return Some((text.into_owned(), encoding));
+    }
+    if is_probably_binary(bytes) {
+        return None;
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Some((text.to_string(), UTF_8)),
+        Err(err) if partial && err.error_len().is_none() => {
+            let text = String::from_utf8_lossy(&bytes[..err.valid_up_to()]);
+            Some((text.into_owned(), UTF_8))
+        }
+        Err(_) => None,
+    }
+}
+
+/// Truncates `text` to at most `max_bytes` bytes without splitting a UTF-8 character.
+fn truncate_at_char_boundary(text: &mut String, max_bytes: usize) {
+    if text.len() <= max_bytes {
+        return;
+    }
+    let mut index = max_bytes;
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    text.truncate(index);
+}
+
+enum SearchPattern {
+    /// `text` is already lowercased when `ignore_case` is set.
+    Literal {
+        text: String,
+        ignore_case: bool,
+        whole_word: bool,
+    },
+    Regex(Regex),
+}
+
+impl SearchPattern {
+    fn is_match(&self, line: &str) -> bool {
+        match self {
+            SearchPattern::Literal {
+                text,
+                ignore_case,
+                whole_word,
+            } => {
+                let line = if *ignore_case {
+                    Cow::Owned(line.to_lowercase())
+                } else {
+                    Cow::Borrowed(line)
+                };
+                if !whole_word {
+                    return line.contains(text.as_str());
+                }
+                let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+                line.match_indices(text.as_str()).any(|(i, m)| {
+                    !line[..i].chars().next_back().is_some_and(is_word_char)
+                        && !line[i + m.len()..].chars().next().is_some_and(is_word_char)
+                })
+            }
+            SearchPattern::Regex(re) => re.is_match(line),
+        }
+    }
+}
+
+enum FilePattern {
+    Substring(String),
+    Glob {
+        matcher: GlobMatcher,
+        match_path: bool,
+    },
+}
+
+impl FilePattern {
+    fn is_match(&self, path: &Path, root: &Path) -> bool {
+        match self {
+            FilePattern::Substring(text) => path.to_string_lossy().contains(text.as_str()),
+            FilePattern::Glob {
+                matcher,
+                match_path: true,
+            } => matcher.is_match(path.strip_prefix(root).unwrap_or(path)),
+            FilePattern::Glob { matcher, .. } => {
+                path.file_name().is_some_and(|name| matcher.is_match(name))
+            }
+        }
+    }
+}
+
+struct SearchOptions {
+    pattern: SearchPattern,
+    file_pattern: Option<FilePattern>,
+    include_ignored: bool,
+    respect_gitignore: bool,
+    max_results: usize,
+    max_file_size: u64,
+    context_before: usize,
+    context_after: usize,
+}
+
+#[derive(Default)]
+struct SearchOutput {
+    results: Vec<Value>,
+    truncated: bool,
+    skipped_files: usize,
+}
+
+/// Searches the files under `dir` on several threads. Results are sorted by path before
+/// `max_results` is applied, so a complete search does not depend on scheduling. The walk
+/// stops once more than `max_results` matches are found; which files supplied them then
+/// depends on the walk order.
+fn visit_dirs(dir: &Path, options: &SearchOptions, output: &mut SearchOutput) {
+    let mut builder = WalkBuilder::new(dir);
+    builder
+        .standard_filters(!options.include_ignored)
+        .require_git(false);
+    if !options.respect_gitignore {
+        builder
+            .ignore(false)
+            .git_ignore(false)
+            .git_global(false)
+            .git_exclude(false);
+    }
+    let files: Mutex<Vec<(PathBuf, Vec<Value>)>> = Mutex::default();
+    let skipped_files = AtomicUsize::new(0);
+    let found = AtomicUsize::new(0);
+    builder.build_parallel().run(|| {
+        Box::new(|entry| {
+            if found.load(Ordering::Relaxed) > options.max_results {
+                return WalkState::Quit;
+            }
+            let Ok(entry) = entry else {
+                return WalkState::Continue;
+            };
+            if !entry.file_type().is_some_and(|v| v.is_file()) {
+                return WalkState::Continue;
+            }
+            let path = entry.path();
+            if let Some(pattern) = &options.file_pattern {
+                if !pattern.is_match(path, dir) {
+                    return WalkState::Continue;
+                }
+            }
+            if entry
+                .metadata()
+                .is_ok_and(|v| v.len() > options.max_file_size)
+            {
+                skipped_files.fetch_add(1, Ordering::Relaxed);
+                return WalkState::Continue;
+            }
+            let results = search_file(path, options);
+            if results.is_empty() {
+                return WalkState::Continue;
+            }
+            let count = results.len();
+            files.lock().unwrap().push((path.to_path_buf(), results));
+            if found.fetch_add(count, Ordering::Relaxed) + count > options.max_results {
+                return WalkState::Quit;
+            }
+            WalkState::Continue
+        })
+    });
+
+    let mut files = files.into_inner().unwrap();
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    output.results = files.into_iter().flat_map(|(_, v)| v).collect();
+    output.truncated = output.results.len() > options.max_results;
+    output.results.truncate(options.max_results);
+    output.skipped_files = skipped_files.into_inner();
+}
+
+fn search_file(path: &Path, options: &SearchOptions) -> Vec<Value> {
+    let Ok(bytes) = fs::read(path) else {
+        return vec![];
+    };
+    if is_probably_binary(&bytes) {
+        return vec![];
+    }
+    let Ok(content) = String::from_utf8(bytes) else {
+        return vec![];
+    };
+    let lines: Vec<&str> = content.lines().collect();
+    let matches: Vec<usize> = (0..lines.len())
+        .filter(|&i| options.pattern.is_match(lines[i]))
+        .take(MAX_SEARCH_MATCHES_PER_FILE)
+        .collect();
+    let mut results = vec![];
+    // The first line not yet shown as context of an earlier match in this file.
+    let mut next_unseen = 0;
+    for (i, &index) in matches.iter().enumerate() {
+        let mut result = json!({
+            "path": path.display().to_string(),
+            "line": index + 1,
+            "text": lines[index],
+        });
+        if options.context_before > 0 || options.context_after > 0 {
+            let before = index
+                .saturating_sub(options.context_before)
+                .max(next_unseen);
+            let next_match = matches.get(i + 1).copied().unwrap_or(lines.len());
+            let after = (index + 1 + options.context_after).min(next_match);
+            let context: Vec<Value> = (before..index)
+                .chain(index + 1..after)
+                .map(|i| json!({ "line": i + 1, "text": lines[i] }))
+                .collect();
+            result["context"] = context.into();
+            next_unseen = after;
+        }
+        results.push(result);
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn run(name: &str, args: &Value) -> Result<Option<Value>> {
+        run_with(name, args, &BuiltinConfig::default(), &Hooks::default()).await
+    }
+
+    async fn run_with(
+        name: &str,
+        args: &Value,
+        config: &BuiltinConfig,
+        hooks: &Hooks<'_>,
+    ) -> Result<Option<Value>> {
+        super::run(name, args, config, hooks).await
+    }
+
+    #[test]
+    fn test_resolve_policy() {
+        let mut config = BuiltinConfig::default();
+        config
+            .policy
+            .insert("fs_write".to_string(), ToolPolicy::Allow);
+        config.policy.insert("fs_rm".to_string(), ToolPolicy::Deny);
+
+        assert_eq!(
+            resolve_policy("fs_cat", &config, false, false),
+            ToolPolicy::Allow
+        );
+        assert_eq!(
+            resolve_policy("command_run", &config, true, false),
+            ToolPolicy::Ask
+        );
+        assert_eq!(
+            resolve_policy("command_run", &config, true, true),
+            ToolPolicy::Allow
+        );
+        assert_eq!(
+            resolve_policy("command_run", &config, false, true),
+            ToolPolicy::Deny
+        );
+        assert_eq!(
+            resolve_policy("fs_write", &config, false, false),
+            ToolPolicy::Allow
+        );
+        assert_eq!(
+            resolve_policy("fs_rm", &config, true, true),
+            ToolPolicy::Deny
+        );
+
+        config.policy.insert("fs_mv".to_string(), ToolPolicy::Deny);
+        assert_eq!(
+            resolve_policy("fs_move", &config, true, true),
+            ToolPolicy::Deny
+        );
+        assert_eq!(
+            resolve_policy("fs_copy", &config, true, false),
+            ToolPolicy::Ask
+        );
+
+        config.non_interactive_policy = ToolPolicy::Allow;
+        assert_eq!(
+            resolve_policy("fs_patch", &config, false, false),
+            ToolPolicy::Allow
+        );
+    }
+
+    #[test]
+    fn test_describe_call() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        let path = path.to_str().unwrap();
+        fs::write(path, "one\n").unwrap();
+
+        let description = describe_call("fs_write", &json!({ "path": path, "contents": "two\n" }));
+        assert!(description.ends_with("@@ -1,1 +1,1 @@\n-one\n+two\n"));
+
+        let args = json!({ "path": path, "contents": "two\n", "append": true });
+        assert_eq!(
+            describe_call("fs_write", &args),
+            format!("fs_write {path} (append)\ntwo\n")
+        );
+
+        let args = json!({ "from": "b.txt", "to": path });
+        assert_eq!(
+            describe_call("fs_cp", &args),
+            format!("fs_cp b.txt\nto {path} (replacing it)")
+        );
+    }
+
+    #[test]
+    fn test_confirm_call() {
+        let config = BuiltinConfig::default();
+        let args = json!({ "command": "ls -la", "cwd": "/tmp" });
+        let mut allow_all = false;
+
+        let approved = confirm_call(
+            "command_run",
+            &args,
+            &config,
+            true,
+            &mut allow_all,
+            |desc| {
+                assert_eq!(desc, "$ ls -la\n(in /tmp)");
+                Ok(Confirmation::No)
+            },
+        )
+        .unwrap();
+        assert!(!approved);
+
+        let approved = confirm_call("command_run", &args, &config, true, &mut allow_all, |_| {
+            Ok(Confirmation::All)
+        })
+        .unwrap();
+        assert!(approved);
+        assert!(allow_all);
+        let approved = confirm_call("command_run", &args, &config, true, &mut allow_all, |_| {
+            panic!("should not ask once all calls are allowed")
+        })
+        .unwrap();
+        assert!(approved);
+
+        let mut allow_all = false;
+        let approved = confirm_call(
+            "fs_rm",
+            &json!({ "path": "x" }),
+            &config,
+            false,
+            &mut allow_all,
+            |_| panic!("should not ask without a terminal"),
+        )
+        .unwrap();
+        assert!(!approved);
+    }
+
+    #[tokio::test]
+    async fn test_run_disabled() {
+        let mut config = BuiltinConfig::default();
+        assert!(enabled("http_request", &config));
+        config.http_request.enabled = false;
+        assert!(!enabled("http_request", &config));
+        assert!(enabled("fs_cat", &config));
+        let args = json!({ "url": "http://127.0.0.1:9/" });
+        let json = run_with("http_request", &args, &config, &Hooks::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["error"], "http_request is disabled");
+    }
+
+    #[tokio::test]
+    async fn test_run_approve() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        let path_str = path.to_str().unwrap();
+        let asked = Mutex::new(vec![]);
+        let approve = |name: &str, _: &Value| {
+            asked.lock().unwrap().push(name.to_string());
+            Ok(false)
+        };
+        let hooks = Hooks {
+            approve: Some(&approve),
+            ..Default::default()
+        };
+        let config = BuiltinConfig::default();
+
+        let args = json!({ "path": path_str, "contents": "hello" });
+        let json = run_with("fs_write", &args, &config, &hooks)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["denied"], true);
+        assert_eq!(json["error"], "The user declined the fs_write call");
+        assert!(!path.exists());
+
+        fs::write(&path, "hello").unwrap();
+        let args = json!({ "path": path_str });
+        run_with("fs_cat", &args, &config, &hooks)
+            .await
+            .unwrap()
+            .unwrap();
+        let args = json!({ "path": dir.path().to_str().unwrap() });
+        run_with("fs_ls", &args, &config, &hooks)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(*asked.lock().unwrap(), ["fs_write"]);
+    }
+
+    #[tokio::test]
+    async fn test_registry() {
+        let decls = declarations();
+        // Tools are keyed by their declared name, so a duplicate name would collapse into one.
+        assert_eq!(all_tools().len(), TOOLS.len());
+        assert_eq!(decls.len(), TOOLS.len());
+        for decl in &decls {
+            let tool = &TOOLS[decl.name.as_str()];
+            assert_eq!(tool.declaration().name, decl.name);
+        }
+        for (alias, name) in ALIASES {
+            assert!(TOOLS.contains_key(name), "{alias} points to a missing tool");
+            assert!(!TOOLS.contains_key(alias));
+        }
+        for name in CONFIRM_TOOLS {
+            assert!(TOOLS.contains_key(name), "{name} is not a tool");
+        }
+        assert!(run("not_a_tool", &json!({})).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_declarations() {
+        let decls = declarations();
+        assert!(decls.iter().any(|d| d.name == "fs_cat"));
+        assert!(decls.iter().any(|d| d.name == "fs_ls"));
+    }
+
+    #[tokio::test]
+    async fn test_run_cat_line_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lines.txt");
+        fs::write(&path, "one\ntwo\nthree\nfour\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let json = run("fs_cat", &json!({ "path": path }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["content"], "one\ntwo\nthree\nfour\n");
+        assert_eq!(json["total_lines"], 4);
+
+        let json = run(
+            "fs_cat",
+            &json!({ "path": path, "start_line": 2, "end_line": 3 }),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(json["content"], "two\nthree\n");
+        assert_eq!(json["total_lines"], 4);
+
+        let json = run("fs_cat", &json!({ "path": path, "start_line": 3 }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["content"], "three\nfour\n");
+
+        let json = run("fs_cat", &json!({ "path": path, "end_line": 2 }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["content"], "one\ntwo\n");
+        assert_eq!(json["total_lines"], 4);
+
+        let json = run(
+            "fs_cat",
+            &json!({ "path": path, "start_line": 3, "end_line": 100 }),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(json["content"], "three\nfour\n");
+
+        let json = run(
+            "fs_cat",
+            &json!({ "path": path, "start_line": 0, "end_line": 100 }),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(json["content"], "one\ntwo\nthree\nfour\n");
+
+        let json = run("fs_cat", &json!({ "path": path, "start_line": 10 }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["content"], "");
+    }
+
+    #[tokio::test]
+    async fn test_run_cat_max_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("big.txt");
+        fs::write(&path, "a€b").unwrap();
+        let path = path.to_str().unwrap();
+
+        let json = run("fs_cat", &json!({ "path": path }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["content"], "a€b");
+        assert_eq!(json["truncated"], false);
+        assert_eq!(json["total_bytes"], 5);
+
+        let json = run("fs_cat", &json!({ "path": path, "max_bytes": 3 }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["content"], "a");
+        assert_eq!(json["truncated"], true);
+        assert_eq!(json["total_bytes"], 5);
+
+        let path = dir.path().join("log.txt");
+        let content = "x".repeat(FS_CAT_MAX_BYTES + 1);
+        fs::write(&path, &content).unwrap();
+        let args = json!({ "path": path.to_str().unwrap() });
+        let json = run("fs_cat", &args).await.unwrap().unwrap();
+        assert_eq!(json["content"], &content[..FS_CAT_MAX_BYTES]);
+        assert_eq!(json["truncated"], true);
+        assert_eq!(json["total_bytes"], FS_CAT_MAX_BYTES + 1);
+
+        let config = BuiltinConfig {
+            fs_cat_max_bytes: FS_CAT_MAX_BYTES + 1,
+            ..Default::default()
+        };
+        let json = run_with("fs_cat", &args, &config, &Hooks::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["content"], content);
+        assert_eq!(json["truncated"], false);
+    }
+
+    #[tokio::test]
+    async fn test_run_cat_reads_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("euro.txt");
+        fs::write(&path, "€\n".repeat(1000)).unwrap();
+        let path = path.to_str().unwrap();
+
+        let json = run("fs_cat", &json!({ "path": path, "max_bytes": 7 }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["content"], "€\n€");
+        assert_eq!(json["truncated"], true);
+        assert_eq!(json["total_bytes"], 4000);
+        assert!(json.get("total_lines").is_none());
+
+        let args = json!({ "path": path, "start_line": 1000, "max_bytes": 6 });
+        let json = run("fs_cat", &args).await.unwrap().unwrap();
+        assert_eq!(json["content"], "€\n");
+        assert_eq!(json["total_lines"], 1000);
+    }
+
+    #[tokio::test]
+    async fn test_run_cat_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.png");
+        fs::write(&path, b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR").unwrap();
+        let json = run("fs_cat", &json!({ "path": path.to_str().unwrap() }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json, json!({ "binary": true, "size": 16 }));
+
+        let path = dir.path().join("random.bin");
+        let mut bytes = vec![0u8; 8192];
+        rand::Rng::fill(&mut rand::rng(), &mut bytes[..]);
+        bytes[100] = 0;
+        fs::write(&path, &bytes).unwrap();
+        let json = run("fs_cat", &json!({ "path": path.to_str().unwrap() }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json, json!({ "binary": true, "size": 8192 }));
+
+        let path = dir.path().join("latin1.txt");
+        fs::write(&path, b"caf\xe9").unwrap();
+        let json = run("fs_cat", &json!({ "path": path.to_str().unwrap() }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["binary"], true);
+    }
+
+    #[tokio::test]
+    async fn test_run_cat_encoding() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("latin1.txt");
+        fs::write(&path, b"caf\xe9").unwrap();
+        let args = json!({ "path": path.to_str().unwrap(), "encoding": "latin1" });
+        let json = run("fs_cat", &args).await.unwrap().unwrap();
+        assert_eq!(json["content"], "café");
+        assert_eq!(json["encoding"], "windows-1252");
+
+        let path = dir.path().join("utf16.txt");
+        fs::write(&path, b"\xff\xfeh\x00i\x00").unwrap();
+        let json = run("fs_cat", &json!({ "path": path.to_str().unwrap() }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["content"], "hi");
+        assert_eq!(json["encoding"], "UTF-16LE");
+
+        let args = json!({ "path": path.to_str().unwrap(), "encoding": "utf-16be" });
+        let json = run("fs_cat", &args).await.unwrap().unwrap();
+        assert_eq!(json["encoding"], "UTF-16BE");
+
+        let args = json!({ "path": path.to_str().unwrap(), "encoding": "klingon" });
+        let json = run("fs_cat", &args).await.unwrap().unwrap();
+        assert_eq!(json["error"], "Unsupported encoding: klingon");
+    }
+
+    #[tokio::test]
+    async fn test_run_search() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "foo\nbar\nfoo bar\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "nothing here\n").unwrap();
+        let args = json!({ "path": dir.path().to_str().unwrap(), "text": "foo" });
+        let json = run("fs_search", &args).await.unwrap().unwrap();
+        let results = json["results"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results[0]["path"].as_str().unwrap().ends_with("a.txt"));
+        assert_eq!(results[0]["line"], 1);
+        assert_eq!(results[0]["text"], "foo");
+        assert_eq!(results[1]["line"], 3);
+        assert_eq!(results[1]["text"], "foo bar");
+
+        fs::write(dir.path().join("c.log"), "hit\n".repeat(50)).unwrap();
+        let args = json!({ "path": dir.path().to_str().unwrap(), "text": "hit" });
+        let json = run("fs_search", &args).await.unwrap().unwrap();
+        assert_eq!(
+            json["results"].as_array().unwrap().len(),
+            MAX_SEARCH_MATCHES_PER_FILE
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_search_case_insensitive() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("notes.md"), "TODO: a\ntodo: b\nToDo: c\n").unwrap();
+        fs::write(dir.path().join("other.txt"), "todo: d\n").unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let json = run("fs_search", &json!({ "path": path, "text": "todo" }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["results"].as_array().unwrap().len(), 2);
+
+        let args = json!({ "path": path, "text": "todo", "case_sensitive": false });
+        let json = run("fs_search", &args).await.unwrap().unwrap();
+        assert_eq!(json["results"].as_array().unwrap().len(), 4);
+
+        let args = json!({
+            "path": path,
+            "text": "TODO",
+            "case_sensitive": false,
+            "file_pattern": ".md"
+        });
+        let json = run("fs_search", &args).await.unwrap().unwrap();
+        let results = json["results"].as_array().unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[2]["line"], 3);
+        assert_eq!(results[2]["text"], "ToDo: c");
+
+        let args = json!({ "path": path, "text": "^todo", "regex": true, "case_sensitive": false });
+        let json = run("fs_search", &args).await.unwrap().unwrap();
+        assert_eq!(json["results"].as_array().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_run_head_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        // Larger than a read chunk, so the tail spans a chunk boundary.
+        let content: String = (1..=20_000).map(|i| format!("line {i}\n")).collect();
+        assert!(content.len() as u64 > FS_TAIL_CHUNK_SIZE);
+        fs::write(&path, &content).unwrap();
+        let path = path.to_str().unwrap();
+
+        let json = run("fs_head", &json!({ "path": path, "lines": 2 }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json, json!({ "content": "line 1\nline 2\n", "lines": 2 }));
+
+        let json = run("fs_tail", &json!({ "path": path, "lines": 2 }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            json,
+            json!({ "content": "line 19999\nline 20000\n", "lines": 2 })
+        );
+
+        let json = run("fs_tail", &json!({ "path": path, "lines": 12_000 }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["lines"], 12_000);
+        assert!(json["content"].as_str().unwrap().starts_with("line 8001\n"));
+        assert!(json.get("total_lines").is_none());
+
+        let json = run("fs_tail", &json!({ "path": path, "lines": 30_000 }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["content"], content);
+        assert_eq!(json["total_lines"], 20_000);
+
+        fs::write(path, "a\nb").unwrap();
+        let json = run("fs_tail", &json!({ "path": path, "lines": 1 }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["content"], "b");
+        let json = run("fs_head", &json!({ "path": path }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["total_lines"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_run_search_sorted() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..40 {
+            let sub = dir.path().join(format!("dir{}", i % 4));
+            fs::create_dir_all(&sub).unwrap();
+            fs::write(sub.join(format!("file{i:02}.txt")), "needle\n").unwrap();
+        }
+        let paths = |json: &Value| -> Vec<String> {
+            json["results"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|v| v["path"].as_str().unwrap().to_string())
+                .collect()
+        };
+        let path = dir.path().to_str().unwrap();
+
+        let json = run("fs_search", &json!({ "path": path, "text": "needle" }))
+            .await
+            .unwrap()
+            .unwrap();
+        let all = paths(&json);
+        assert_eq!(all.len(), 40);
+        assert!(all.windows(2).all(|v| v[0] < v[1]));
+        assert!(all[0].ends_with("dir0/file00.txt"));
+        assert_eq!(json["truncated"], false);
+
+        // The walk stops early once enough matches are found, so only the order is stable.
+        let args = json!({ "path": path, "text": "needle", "max_results": 25 });
+        let json = run("fs_search", &args).await.unwrap().unwrap();
+        let some = paths(&json);
+        assert_eq!(some.len(), 25);
+        assert!(some.windows(2).all(|v| v[0] < v[1]));
+        assert_eq!(json["truncated"], true);
+    }
+
+    #[tokio::test]
+    async fn test_run_search_file_pattern() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src/nested")).unwrap();
+        fs::write(dir.path().join("Cargo.toml"), "needle\n").unwrap();
+        fs::write(dir.path().join("src/main.rs"), "needle\n").unwrap();
+        fs::write(dir.path().join("src/nested/lib.rs"), "needle\n").unwrap();
+        fs::write(dir.path().join("src/nested/config.toml"), "needle\n").unwrap();
+        let path = dir.path().to_str().unwrap();
+        let search = async |file_pattern: &str| -> Vec<String> {
+            let args = json!({ "path": path, "text": "needle", "file_pattern": file_pattern });
+            let json = run("fs_search", &args).await.unwrap().unwrap();
+            json["results"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|v| {
+                    let v = Path::new(v["path"].as_str().unwrap());
+                    v.strip_prefix(path).unwrap().to_string_lossy().to_string()
+                })
+                .collect()
+        };
+
+        assert_eq!(search("*.rs").await, ["src/main.rs", "src/nested/lib.rs"]);
+        assert_eq!(search("src/*.rs").await, ["src/main.rs"]);
+        assert_eq!(search("src/**/*.toml").await, ["src/nested/config.toml"]);
+        assert_eq!(
+            search("nested").await,
+            ["src/nested/config.toml", "src/nested/lib.rs"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_search_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = "a\nb\nhit 1\nc\nhit 2\nd\ne\nf\n";
+        fs::write(dir.path().join("a.txt"), content).unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let args = json!({ "path": path, "text": "hit", "context_before": 2, "context_after": 2 });
+        let json = run("fs_search", &args).await.unwrap().unwrap();
+        let results = json["results"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(
+            results[0]["context"],
+            json!([
+                { "line": 1, "text": "a" },
+                { "line": 2, "text": "b" },
+                { "line": 4, "text": "c" }
+            ])
+        );
+        assert_eq!(
+            results[1]["context"],
+            json!([{ "line": 6, "text": "d" }, { "line": 7, "text": "e" }])
+        );
+
+        let args = json!({ "path": path, "text": "hit" });
+        let json = run("fs_search", &args).await.unwrap().unwrap();
+        assert!(json["results"][0].get("context").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_run_search_word_and_case() {
+        let dir = tempfile::tempdir().unwrap();
+        let content =
+            "let count = 1;\nlet counter = 2;\nCOUNT_MAX\nfn COUNT() {}\nStraße\nΣΊΣΥΦΟΣ\n";
+        fs::write(dir.path().join("a.txt"), content).unwrap();
+        let path = dir.path().to_str().unwrap();
+        let lines = async |args: Value| -> Vec<u64> {
+            let json = run("fs_search", &args).await.unwrap().unwrap();
+            json["results"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|v| v["line"].as_u64().unwrap())
+                .collect()
+        };
+
+        assert_eq!(
+            lines(json!({ "path": path, "text": "count" })).await,
+            [1, 2]
+        );
+        let args = json!({ "path": path, "text": "count", "whole_word": true });
+        assert_eq!(lines(args).await, [1]);
+        let args =
+            json!({ "path": path, "text": "count", "whole_word": true, "ignore_case": true });
+        assert_eq!(lines(args).await, [1, 4]);
+        let args = json!({ "path": path, "text": "count", "ignore_case": true });
+        assert_eq!(lines(args).await, [1, 2, 3, 4]);
+        let args = json!({ "path": path, "text": "coun?t", "regex": true, "whole_word": true, "ignore_case": true });
+        assert_eq!(lines(args).await, [1, 4]);
+
+        let args = json!({ "path": path, "text": "STRASSE", "ignore_case": true });
+        assert!(lines(args).await.is_empty());
+        let args = json!({ "path": path, "text": "STRAẞE", "ignore_case": true });
+        assert_eq!(lines(args).await, [5]);
+        let args =
+            json!({ "path": path, "text": "σίσυφος", "ignore_case": true, "whole_word": true });
+        assert_eq!(lines(args).await, [6]);
+    }
+
+    #[tokio::test]
+    async fn test_run_search_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "hit\n".repeat(10)).unwrap();
+        fs::write(dir.path().join("b.txt"), "hit\n".repeat(10)).unwrap();
+        fs::write(dir.path().join("c.txt"), "hit\n".repeat(1000)).unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let args = json!({ "path": path, "text": "hit", "max_file_size": 100 });
+        let json = run("fs_search", &args).await.unwrap().unwrap();
+        assert_eq!(json["results"].as_array().unwrap().len(), 20);
+        assert_eq!(json["truncated"], false);
+        assert_eq!(json["skipped_files"], 1);
+
+        let args = json!({ "path": path, "text": "hit", "max_results": 15 });
+        let json = run("fs_search", &args).await.unwrap().unwrap();
+        assert_eq!(json["results"].as_array().unwrap().len(), 15);
+        assert_eq!(json["truncated"], true);
+    }
+
+    #[tokio::test]
+    async fn test_run_search_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(".gitignore"), "target/\n").unwrap();
+        fs::create_dir_all(dir.path().join("target/debug")).unwrap();
+        fs::create_dir_all(dir.path().join(".git")).unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("target/debug/out.rs"), "needle\n").unwrap();
+        fs::write(dir.path().join(".git/config"), "needle\n").unwrap();
+        fs::write(dir.path().join("src/main.rs"), "needle\n").unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let json = run("fs_search", &json!({ "path": path, "text": "needle" }))
+            .await
+            .unwrap()
+            .unwrap();
+        let results = json["results"].as_array().unwrap();
+        assert_eq!(results.len(), 1);
+        assert!(results[0]["path"].as_str().unwrap().ends_with("main.rs"));
+
+        let args = json!({ "path": path, "text": "needle", "respect_gitignore": false });
+        let json = run("fs_search", &args).await.unwrap().unwrap();
+        let results = json["results"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results[1]["path"].as_str().unwrap().ends_with("out.rs"));
+
+        let args = json!({ "path": path, "text": "needle", "include_ignored": true });
+        let json = run("fs_search", &args).await.unwrap().unwrap();
+        assert_eq!(json["results"].as_array().unwrap().len(), 3);
+
+        fs::write(dir.path().join("src/data.bin"), b"needle\0\x01").unwrap();
+        let json = run("fs_search", &json!({ "path": path, "text": "needle" }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["results"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_search_regex() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("lib.rs"),
+            "fn foo() {}\n    fn bar() {}\nlet x = 42;\n",
+        )
+        .unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let args = json!({ "path": path, "text": r"^fn\s+\w+", "regex": true });
+        let json = run("fs_search", &args).await.unwrap().unwrap();
+        let results = json["results"].as_array().unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["text"], "fn foo() {}");
+
+        let args = json!({ "path": path, "text": r"[0-9]{2};$", "regex": true });
+        let json = run("fs_search", &args).await.unwrap().unwrap();
+        let results = json["results"].as_array().unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["line"], 3);
+
+        let args = json!({ "path": path, "text": r"^fn\s+\w+" });
+        let json = run("fs_search", &args).await.unwrap().unwrap();
+        assert!(json["results"].as_array().unwrap().is_empty());
+
+        let args = json!({ "path": path, "text": "fn(", "regex": true });
+        let json = run("fs_search", &args).await.unwrap().unwrap();
+        assert!(json["error"].as_str().unwrap().starts_with("Invalid regex"));
+    }
+
+    #[tokio::test]
+    async fn test_run_ls() {
+        let args = json!({ "path": "." });
+        let result = run("fs_ls", &args).await.unwrap();
+        assert!(result.is_some());
+        let json = result.unwrap();
+        let files = json["files"].as_array().unwrap();
+        let cargo_toml = files.iter().find(|v| v["name"] == "Cargo.toml").unwrap();
+        assert_eq!(cargo_toml["type"], "file");
+        assert!(cargo_toml["size"].as_u64().unwrap() > 0);
+        assert!(cargo_toml["modified"].is_string());
+        let src = files.iter().find(|v| v["name"] == "src").unwrap();
+        assert_eq!(src["type"], "dir");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run_ls_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("target.txt"), "hello").unwrap();
+        std::os::unix::fs::symlink(dir.path().join("target.txt"), dir.path().join("link")).unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        for args in [
+            json!({ "path": path }),
+            json!({ "path": path, "recursive": true }),
+        ] {
+            let json = run("fs_ls", &args).await.unwrap().unwrap();
+            let files = json["files"].as_array().unwrap();
+            assert_eq!(files.len(), 2);
+            assert_eq!(files[0]["name"], "link");
+            assert_eq!(files[0]["type"], "symlink");
+            assert!(files[0]["size"].is_null());
+            assert_eq!(files[1]["name"], "target.txt");
+            assert_eq!(files[1]["type"], "file");
+            assert_eq!(files[1]["size"], 5);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_ls_recursive() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b/c/d")).unwrap();
+        fs::create_dir_all(dir.path().join(".git")).unwrap();
+        fs::write(dir.path().join("a/b/file.txt"), "hi").unwrap();
+        fs::write(dir.path().join(".git/HEAD"), "ref").unwrap();
+        let path = dir.path().to_str().unwrap();
+        let names = |json: Value| -> Vec<String> {
+            json["files"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|v| v["name"].as_str().unwrap().replace('\\', "/"))
+                .collect()
+        };
+
+        let json = run("fs_ls", &json!({ "path": path, "recursive": true }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(names(json), ["a/", "a/b/", "a/b/c/", "a/b/file.txt"]);
+
+        let args = json!({ "path": path, "recursive": true, "max_depth": 1, "show_hidden": true });
+        let json = run("fs_ls", &args).await.unwrap().unwrap();
+        assert_eq!(names(json), [".git/", "a/"]);
+
+        let args = json!({ "path": path, "recursive": true, "max_depth": 1, "all": true });
+        let json = run("fs_ls", &args).await.unwrap().unwrap();
+        assert_eq!(names(json), [".git/", "a/"]);
+    }
+
+    #[tokio::test]
+    async fn test_run_ls_pattern() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src/config")).unwrap();
+        fs::write(dir.path().join("build.rs"), "").unwrap();
+        fs::write(dir.path().join("Cargo.toml"), "").unwrap();
+        fs::write(dir.path().join("src/main.rs"), "").unwrap();
+        fs::write(dir.path().join("src/config/app.toml"), "").unwrap();
+        let path = dir.path().to_str().unwrap();
+        let names = |json: Value| -> Vec<String> {
+            json["files"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|v| v["name"].as_str().unwrap().replace('\\', "/"))
+                .collect()
+        };
+
+        let json = run("fs_ls", &json!({ "path": path, "pattern": "*.rs" }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(names(json), ["build.rs"]);
+
+        let json = run("fs_ls", &json!({ "path": path, "pattern": "**/*.rs" }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(names(json), ["build.rs", "src/main.rs"]);
+
+        let json = run(
+            "fs_ls",
+            &json!({ "path": path, "pattern": "src/**/*.toml" }),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(names(json), ["src/config/app.toml"]);
+
+        let json = run("fs_ls", &json!({ "path": path, "pattern": "*.yaml" }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(names(json).is_empty());
+
+        let json = run("fs_ls", &json!({ "path": path, "pattern": "src/[" }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(json["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid glob pattern"));
+    }
+
+    #[tokio::test]
+    async fn test_run_ls_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..FS_LS_MAX_ENTRIES + 1 {
+            fs::write(dir.path().join(format!("{i:04}.txt")), "").unwrap();
+        }
+        let args = json!({ "path": dir.path().to_str().unwrap(), "recursive": true });
+        let json = run("fs_ls", &args).await.unwrap().unwrap();
+        assert_eq!(json["files"].as_array().unwrap().len(), FS_LS_MAX_ENTRIES);
+        assert_eq!(json["truncated"], true);
+    }
+
+    #[tokio::test]
+    async fn test_run_rm() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file.txt");
+        let empty = dir.path().join("empty");
+        let full = dir.path().join("full");
+        fs::write(&file, "").unwrap();
+        fs::create_dir(&empty).unwrap();
+        fs::create_dir_all(full.join("nested")).unwrap();
+        fs::write(full.join("nested/file.txt"), "").unwrap();
+
+        let json = run("fs_rm", &json!({ "path": file.to_str().unwrap() }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json, json!({ "removed": true, "was_dir": false }));
+        assert!(!file.exists());
+
+        let json = run("fs_rm", &json!({ "path": file.to_str().unwrap() }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(json["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Path not found"));
+
+        let json = run("fs_rm", &json!({ "path": empty.to_str().unwrap() }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json, json!({ "removed": true, "was_dir": true }));
+
+        let json = run("fs_rm", &json!({ "path": full.to_str().unwrap() }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(json["error"].is_string());
+        assert!(full.exists());
+
+        let args = json!({ "path": full.to_str().unwrap(), "recursive": true });
+        let json = run("fs_rm", &args).await.unwrap().unwrap();
+        assert_eq!(
+            json,
+            json!({ "removed": true, "was_dir": true, "files": 1 })
+        );
+        assert!(!full.exists());
+    }
+
+    #[test]
+    fn test_run_rm_refusal() {
+        let home = dirs::home_dir().unwrap();
+        let cwd = env::current_dir().unwrap();
+        for path in [
+            Path::new("/"),
+            Path::new("."),
+            Path::new(".."),
+            Path::new("/tmp/.."),
+            &home,
+            &home.join("x/.."),
+            &cwd,
+            &cwd.join("src/.."),
+        ] {
+            let reason = removal_refusal(path, None).unwrap();
+            assert!(reason.starts_with("Refusing"), "{reason}");
+        }
+
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let file = outside.path().join("file.txt");
+        fs::write(&file, "").unwrap();
+        let root = root.path();
+        assert!(removal_refusal(&file, Some(root)).is_some());
+        let reason = removal_refusal(root, Some(root)).unwrap();
+        assert!(reason.contains("sandbox root"), "{reason}");
+        assert!(removal_refusal(&root.join("file.txt"), Some(root)).is_none());
+        assert!(removal_refusal(&file, None).is_none());
+        assert!(file.exists());
+    }
+
+    #[tokio::test]
+    async fn test_run_move() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("a.txt");
+        let to = dir.path().join("nested/dir/b.txt");
+        fs::write(&from, "hello").unwrap();
+
+        let (from_str, to_str) = (from.to_str().unwrap(), to.to_str().unwrap());
+        let args = json!({ "from": from_str, "to": to_str });
+        let json = run("fs_mv", &args).await.unwrap().unwrap();
+        assert_eq!(
+            json,
+            json!({ "moved": true, "from": from_str, "to": to_str })
+        );
+        assert!(!from.exists());
+        assert_eq!(fs::read_to_string(&to).unwrap(), "hello");
+
+        let json = run("fs_mv", &args).await.unwrap().unwrap();
+        assert!(json["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Path not found"));
+
+        fs::write(&from, "world").unwrap();
+        let json = run("fs_mv", &args).await.unwrap().unwrap();
+        assert!(json["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Destination already exists"));
+        assert!(from.exists());
+
+        let args = json!({ "from": from_str, "to": to_str, "overwrite": true });
+        let json = run("fs_mv", &args).await.unwrap().unwrap();
+        assert_eq!(json["moved"], true);
+        assert_eq!(fs::read_to_string(&to).unwrap(), "world");
+    }
+
+    #[tokio::test]
+    async fn test_run_move_onto_itself() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("nested");
+        let file = nested.join("a.txt");
+        fs::create_dir(&nested).unwrap();
+        fs::write(&file, "hello").unwrap();
+
+        let same = nested.join("../nested/a.txt");
+        let args = json!({ "from": file, "to": same, "overwrite": true });
+        let json = run("fs_mv", &args).await.unwrap().unwrap();
+        assert!(json["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Source and destination are the same path"));
+
+        let args = json!({ "from": file, "to": nested, "overwrite": true });
+        let json = run("fs_mv", &args).await.unwrap().unwrap();
+        assert!(json["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Destination contains the source"));
+
+        let args = json!({ "from": nested, "to": nested.join("inner/moved") });
+        let json = run("fs_mv", &args).await.unwrap().unwrap();
+        assert!(json["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Destination is inside the source"));
+        assert!(!nested.join("inner").exists());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "hello");
+    }
+
+    #[tokio::test]
+    async fn test_run_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir_all(src.join("nested")).unwrap();
+        fs::write(src.join("a.txt"), "hello").unwrap();
+        fs::write(src.join("nested/b.txt"), "world!").unwrap();
+
+        let file_to = dir.path().join("out/a.txt");
+        let args = json!({
+            "from": src.join("a.txt").to_str().unwrap(),
+            "to": file_to.to_str().unwrap()
+        });
+        let json = run("fs_cp", &args).await.unwrap().unwrap();
+        assert_eq!(json["files"], 1);
+        assert_eq!(json["bytes"], 5);
+        assert_eq!(json["to"], file_to.to_str().unwrap());
+        assert_eq!(fs::read_to_string(&file_to).unwrap(), "hello");
+
+        let json = run("fs_cp", &args).await.unwrap().unwrap();
+        assert!(json["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Destination already exists"));
+
+        let dir_to = dir.path().join("copy");
+        let args = json!({ "from": src.to_str().unwrap(), "to": dir_to.to_str().unwrap() });
+        let json = run("fs_cp", &args).await.unwrap().unwrap();
+        assert!(json["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Source is a directory"));
+        assert!(!dir_to.exists());
+
+        let args = json!({
+            "from": src.to_str().unwrap(),
+            "to": dir_to.to_str().unwrap(),
+            "recursive": true
+        });
+        let json = run("fs_cp", &args).await.unwrap().unwrap();
+        assert_eq!(json["files"], 2);
+        assert_eq!(json["bytes"], 11);
+        assert_eq!(
+            fs::read_to_string(dir_to.join("nested/b.txt")).unwrap(),
+            "world!"
+        );
+        assert!(src.join("nested/b.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run_copy_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let outside = dir.path().join("outside");
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(src.join("a.txt"), "hello").unwrap();
+        fs::write(outside.join("big.txt"), "x".repeat(1000)).unwrap();
+        std::os::unix::fs::symlink("a.txt", src.join("link.txt")).unwrap();
+        std::os::unix::fs::symlink(&outside, src.join("outside")).unwrap();
+
+        let to = dir.path().join("copy");
+        let args = json!({ "from": src, "to": to, "recursive": true });
+        let json = run("fs_cp", &args).await.unwrap().unwrap();
+        assert_eq!(json["files"], 3);
+        assert_eq!(json["bytes"], 5);
+        assert_eq!(
+            fs::read_link(to.join("link.txt")).unwrap(),
+            Path::new("a.txt")
+        );
+        assert_eq!(fs::read_link(to.join("outside")).unwrap(), outside);
+        assert_eq!(fs::read_to_string(to.join("link.txt")).unwrap(), "hello");
+    }
+
+    #[tokio::test]
+    async fn test_run_copy_onto_itself() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let file = src.join("a.txt");
+        fs::create_dir(&src).unwrap();
+        fs::write(&file, "hello").unwrap();
+
+        let args = json!({ "from": file, "to": src.join("./a.txt"), "overwrite": true });
+        let json = run("fs_cp", &args).await.unwrap().unwrap();
+        assert!(json["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Source and destination are the same path"));
+
+        let args = json!({ "from": src, "to": src.join("copy"), "recursive": true });
+        let json = run("fs_cp", &args).await.unwrap().unwrap();
+        assert!(json["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Destination is inside the source"));
+        assert!(!src.join("copy").exists());
+
+        let args = json!({ "from": file, "to": src, "overwrite": true });
+        let json = run("fs_cp", &args).await.unwrap().unwrap();
+        assert!(json["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Destination contains the source"));
+        assert_eq!(fs::read_to_string(&file).unwrap(), "hello");
+    }
+
+    #[tokio::test]
+    async fn test_run_write_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.txt");
+        let path = path.to_str().unwrap();
+
+        let args = json!({ "path": path, "contents": "first\n", "append": true });
+        let json = run("fs_write", &args).await.unwrap().unwrap();
+        assert_eq!(json["size"], 6);
+
+        let args = json!({ "path": path, "contents": "second\n", "append": true });
+        let json = run("fs_write", &args).await.unwrap().unwrap();
+        assert_eq!(json["size"], 13);
+        assert_eq!(fs::read_to_string(path).unwrap(), "first\nsecond\n");
+
+        let args = json!({ "path": path, "contents": "replaced", "overwrite": true });
+        let json = run("fs_write", &args).await.unwrap().unwrap();
+        assert_eq!(json["size"], 8);
+    }
+
+    #[tokio::test]
+    async fn test_run_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.md");
+        let path = path.to_str().unwrap();
+
+        let json = run("fs_append", &json!({ "path": path, "contents": "- one\n" }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json, json!({ "success": true, "total_bytes": 6 }));
+
+        let json = run("fs_append", &json!({ "path": path, "contents": "- two\n" }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json, json!({ "success": true, "total_bytes": 12 }));
+        assert_eq!(fs::read_to_string(path).unwrap(), "- one\n- two\n");
+    }
+
+    #[tokio::test]
+    async fn test_run_write_creates_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a/b/file.txt");
+        let args = json!({ "path": path.to_str().unwrap(), "contents": "hi" });
+        let json = run("fs_write", &args).await.unwrap().unwrap();
+        assert_eq!(json["created_dirs"], true);
+        assert_eq!(json["path"], path.to_str().unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hi");
+
+        let args = json!({ "path": path.to_str().unwrap(), "contents": "hi", "overwrite": true });
+        let json = run("fs_write", &args).await.unwrap().unwrap();
+        assert_eq!(json["created_dirs"], false);
+    }
+
+    #[tokio::test]
+    async fn test_run_write_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.txt");
+        let path = path.to_str().unwrap();
+
+        let json = run("fs_write", &json!({ "path": path, "contents": "original" }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["success"], true);
+
+        let json = run("fs_write", &json!({ "path": path, "contents": "clobber" }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json, json!({ "error": "file exists", "size": 8 }));
+        assert_eq!(fs::read_to_string(path).unwrap(), "original");
+
+        let args = json!({ "path": path, "contents": "rewrite", "overwrite": true });
+        let json = run("fs_write", &args).await.unwrap().unwrap();
+        assert_eq!(json["success"], true);
+        assert_eq!(fs::read_to_string(path).unwrap(), "rewrite");
+    }
+
+    #[tokio::test]
+    async fn test_run_write_atomic() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("script.sh");
+        fs::write(&path, "old").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let args = json!({ "path": path.to_str().unwrap(), "contents": "new", "overwrite": true });
+        let json = run("fs_write", &args).await.unwrap().unwrap();
+        assert_eq!(json["success"], true);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o755);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_patch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lib.rs");
+        let path_str = path.to_str().unwrap();
+        let original = "let a = foo();\nlet b = foo();\nlet c = foo();\n";
+        fs::write(&path, original).unwrap();
+
+        let args = json!({ "path": path_str, "search": "foo()", "replace": "bar()" });
+        let json = run("fs_patch", &args).await.unwrap().unwrap();
+        assert_eq!(json["matches"], 3);
+        assert_eq!(json["lines"], json!([1, 2, 3]));
+        assert_eq!(fs::read_to_string(&path).unwrap(), original);
+
+        let args =
+            json!({ "path": path_str, "search": "foo()", "replace": "bar()", "occurrence": 2 });
+        let json = run("fs_patch", &args).await.unwrap().unwrap();
+        assert_eq!(json, json!({ "success": true, "count": 1 }));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "let a = foo();\nlet b = bar();\nlet c = foo();\n"
+        );
+
+        let args =
+            json!({ "path": path_str, "search": "foo()", "replace": "bar()", "occurrence": 3 });
+        let json = run("fs_patch", &args).await.unwrap().unwrap();
+        assert!(json["error"].as_str().unwrap().contains("out of range"));
+
+        let args = json!({ "path": path_str, "search": "foo()", "replace": "baz()", "all": true });
+        let json = run("fs_patch", &args).await.unwrap().unwrap();
+        assert_eq!(json, json!({ "success": true, "count": 2 }));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "let a = baz();\nlet b = bar();\nlet c = baz();\n"
+        );
+
+        let args =
+            json!({ "path": path_str, "search": "baz()", "replace": "qux()", "replace_all": true });
+        let json = run("fs_patch", &args).await.unwrap().unwrap();
+        assert_eq!(json, json!({ "success": true, "count": 2 }));
+
+        let args = json!({ "path": path_str, "search": "let b = bar();", "replace": "" });
+        let json = run("fs_patch", &args).await.unwrap().unwrap();
+        assert_eq!(json, json!({ "success": true, "count": 1 }));
+
+        let args = json!({ "path": path_str, "search": "missing", "replace": "" });
+        let json = run("fs_patch", &args).await.unwrap().unwrap();
+        assert_eq!(json["error"], "Search string not found in file");
+    }
+
+    #[tokio::test]
+    async fn test_run_patch_ambiguous() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        let original = "a:\n  enabled: true\nb:\n  enabled: true\nc:\n  enabled: true\n";
+        fs::write(&path, original).unwrap();
+
+        let args = json!({
+            "path": path.to_str().unwrap(),
+            "search": "enabled: true",
+            "replace": "enabled: false"
+        });
+        let json = run("fs_patch", &args).await.unwrap().unwrap();
+        let error = json["error"].as_str().unwrap();
+        assert!(error.contains("found 3 times"));
+        assert!(error.contains("more surrounding context"));
+        assert_eq!(json["lines"], json!([2, 4, 6]));
+        assert_eq!(fs::read_to_string(&path).unwrap(), original);
+    }
+
+    #[test]
+    fn test_reindent() {
+        let text = "if x {\n    y();\n}";
+        assert_eq!(reindent(text, "", "  ", "\n"), "  if x {\n      y();\n  }");
+        assert_eq!(
+            reindent(text, "", "\t", "\r\n"),
+            "\tif x {\r\n\t\ty();\r\n\t}"
+        );
+        assert_eq!(reindent("\tb();\n  c();", "\t", "", "\n"), "b();\nc();");
+        assert_eq!(
+            reindent("        a();\n    b();", "        ", "\t\t", "\n"),
+            "\t\ta();\n\tb();"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_patch_fuzzy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lib.rs");
+        let path_str = path.to_str().unwrap();
+        fs::write(
+            &path,
+            "impl Foo {\n\tfn run(&self) {\n\t\tself.step();  \n\t}\n}\n",
+        )
+        .unwrap();
+
+        let args = json!({
+            "path": path_str,
+            "search": "    fn run(&self) {\n        self.step();\n    }\n",
+            "replace": "    fn run(&self) {\n        self.step();\n        self.finish();\n    }\n"
+        });
+        let json = run("fs_patch", &args).await.unwrap().unwrap();
+        assert_eq!(json, json!({ "success": true, "count": 1, "fuzzy": true }));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "impl Foo {\n\tfn run(&self) {\n\t\tself.step();\n\t\tself.finish();\n\t}\n}\n"
+        );
+
+        fs::write(&path, "a\r\n  b\r\nc\r\n").unwrap();
+        let args = json!({ "path": path_str, "search": "a\nb\n", "replace": "a\nB\n" });
+        let json = run("fs_patch", &args).await.unwrap().unwrap();
+        assert_eq!(json["fuzzy"], true);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a\r\nB\r\nc\r\n");
+
+        fs::write(&path, "if x {\n    y();\n}\nif x {\n\ty();\n}\n").unwrap();
+        let args = json!({ "path": path_str, "search": "if x {\n  y();\n}", "replace": "" });
+        let json = run("fs_patch", &args).await.unwrap().unwrap();
+        assert!(json["error"].as_str().unwrap().contains("2 locations"));
+        assert_eq!(json["lines"], json!([1, 4]));
+    }
+
+    #[tokio::test]
+    async fn test_run_patch_regex() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lib.rs");
+        let path_str = path.to_str().unwrap();
+        fs::write(&path, "let count = 1;\nlet counter = count + 1;\n").unwrap();
+
+        let args = json!({
+            "path": path_str,
+            "search": r"\bcount\b",
+            "replace": "total",
+            "regex": true,
+            "replace_all": true
+        });
+        let json = run("fs_patch", &args).await.unwrap().unwrap();
+        assert_eq!(json, json!({ "success": true, "count": 2 }));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "let total = 1;\nlet counter = total + 1;\n"
+        );
+
+        let args = json!({
+            "path": path_str,
+            "search": r"let (\w+) = (\d+);",
+            "replace": "const ${1}: i32 = $2;",
+            "regex": true
+        });
+        let json = run("fs_patch", &args).await.unwrap().unwrap();
+        assert_eq!(json, json!({ "success": true, "count": 1 }));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "const total: i32 = 1;\nlet counter = total + 1;\n"
+        );
+
+        let args = json!({ "path": path_str, "search": "(", "replace": "", "regex": true });
+        let json = run("fs_patch", &args).await.unwrap().unwrap();
+        assert!(json["error"].as_str().unwrap().starts_with("Invalid regex"));
+    }
+
+    #[tokio::test]
+    async fn test_run_patch_preview() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.rs");
+        let path_str = path.to_str().unwrap();
+        let original = "fn main() {\n    println!(\"hello\");\n}\n";
+        fs::write(&path, original).unwrap();
+
+        let args = json!({
+            "path": path_str,
+            "search": "hello",
+            "replace": "world",
+            "preview": true
+        });
+        let json = run("fs_patch", &args).await.unwrap().unwrap();
+        assert_eq!(json["count"], 1);
+        assert_eq!(
+            json["diff"],
+            format!(
+                "--- a/{path_str}\n+++ b/{path_str}\n@@ -1,3 +1,3 @@\n fn main() {{\n\
+                 -    println!(\"hello\");\n+    println!(\"world\");\n }}\n"
+            )
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), original);
+    }
+
+    #[tokio::test]
+    async fn test_run_patch_edits() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.rs");
+        let path_str = path.to_str().unwrap();
+        let original = "fn one() {}\nfn two() {}\nfn three() {}\n";
+        fs::write(&path, original).unwrap();
+
+        let args = json!({
+            "path": path_str,
+            "edits": [
+                { "search": "fn one()", "replace": "fn first()" },
+                { "search": "fn three()", "replace": "fn third()" }
+            ]
+        });
+        let json = run("fs_patch", &args).await.unwrap().unwrap();
+        assert_eq!(json, json!({ "success": true, "count": 2 }));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "fn first() {}\nfn two() {}\nfn third() {}\n"
+        );
+
+        let args = json!({
+            "path": path_str,
+            "edits": [
+                { "search": "fn two()", "replace": "fn second()" },
+                { "search": "fn missing()", "replace": "fn found()" }
+            ]
+        });
+        let json = run("fs_patch", &args).await.unwrap().unwrap();
+        assert!(json["error"].is_string());
+        assert_eq!(json["edits"][0]["status"], "ok");
+        assert_eq!(json["edits"][1]["error"], "Search string not found");
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "fn first() {}\nfn two() {}\nfn third() {}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_apply_patch() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.txt");
+        let b = dir.path().join("b.txt");
+        fs::write(&a, "one\ntwo\nthree\n").unwrap();
+        let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+
+        let patch = format!(
+            "--- {a}\n+++ {a}\n@@ -1,3 +1,3 @@\n one\n-two\n+TWO\n three\n\
+             --- /dev/null\n+++ {b}\n@@ -0,0 +1,2 @@\n+new\n+file\n"
+        );
+        let json = run("fs_apply_patch", &json!({ "patch": patch }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["success"], true);
+        assert_eq!(json["files"][0]["status"], "modified");
+        assert_eq!(json["files"][1]["status"], "created");
+        assert_eq!(fs::read_to_string(a).unwrap(), "one\nTWO\nthree\n");
+        assert_eq!(fs::read_to_string(b).unwrap(), "new\nfile\n");
+
+        let patch = format!("--- {b}\n+++ /dev/null\n@@ -1,2 +0,0 @@\n-new\n-file\n");
+        let json = run("fs_apply_patch", &json!({ "patch": patch }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["files"][0]["status"], "deleted");
+        assert!(!Path::new(b).exists());
+    }
+
+    #[tokio::test]
+    async fn test_run_apply_patch_rejected_hunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        fs::write(&path, "1\n2\n3\n4\n5\n6\n7\n8\n9\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let patch = format!(
+            "--- {path}\n+++ {path}\n@@ -1,3 +1,3 @@\n 1\n-2\n+two\n 3\n\
+             @@ -7,3 +7,3 @@\n 7\n-missing\n+eight\n 9\n"
+        );
+        let json = run("fs_apply_patch", &json!({ "patch": patch }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["success"], false);
+        let file = &json["files"][0];
+        assert_eq!(file["status"], "failed");
+        assert_eq!(file["hunks_applied"], 1);
+        assert_eq!(
+            file["applied"],
+            json!([{ "hunk": 1, "line": 1, "fuzz": 0 }])
+        );
+        assert_eq!(file["rejected"][0]["hunk"], 2);
+        assert_eq!(file["rejected"][0]["old_start"], 7);
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            "1\n2\n3\n4\n5\n6\n7\n8\n9\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_diff() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.txt");
+        let b = dir.path().join("b.txt");
+        let bin = dir.path().join("a.bin");
+        fs::write(&a, "one\ntwo\n").unwrap();
+        fs::write(&b, "one\n2\n").unwrap();
+        fs::write(&bin, b"one\0two").unwrap();
+        let (a, b, bin) = (
+            a.to_str().unwrap(),
+            b.to_str().unwrap(),
+            bin.to_str().unwrap(),
+        );
+
+        let json = run("fs_diff", &json!({ "path": a, "other_path": b }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            json["diff"],
+            format!("--- a/{a}\n+++ b/{b}\n@@ -1,2 +1,2 @@\n one\n-two\n+2\n")
+        );
+        assert_eq!(json["truncated"], false);
+
+        let args = json!({ "path": a, "content": "one\ntwo\nthree\n", "max_bytes": 10 });
+        let json = run("fs_diff", &args).await.unwrap().unwrap();
+        assert_eq!(json["diff"].as_str().unwrap().len(), 10);
+        assert_eq!(json["truncated"], true);
+
+        let json = run("fs_diff", &json!({ "path": a, "content": "one\ntwo\n" }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json, json!({ "identical": true }));
+
+        let json = run("fs_diff", &json!({ "path": a, "other_path": bin }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["error"], "binary file");
+        assert!(json.get("diff").is_none());
+
+        let big = dir.path().join("big.txt");
+        let size = FS_DIFF_MAX_INPUT_BYTES + 1;
+        File::create(&big).unwrap().set_len(size).unwrap();
+        let json = run("fs_diff", &json!({ "path": a, "other_path": big }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            json,
+            json!({ "error": "input too large to diff", "size": size })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_json_query() {
+        let document = r#"{"items": [{"id": 1, "name": "a"}, {"id": 2, "name": "b"}], "total": 2}"#;
+        let json = run(
+            "json_query",
+            &json!({ "json": document, "query": "$.items[*].name" }),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "count": 2,
+                "matches": [
+                    { "path": "$['items'][0]['name']", "value": "a" },
+                    { "path": "$['items'][1]['name']", "value": "b" },
+                ],
+            })
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.json");
+        fs::write(&path, document).unwrap();
+        let args = json!({ "path": path.to_str().unwrap(), "query": ".items[?@.id > 1]" });
+        let json = run("json_query", &args).await.unwrap().unwrap();
+        assert_eq!(json["matches"][0]["value"], json!({ "id": 2, "name": "b" }));
+
+        let json = run(
+            "json_query",
+            &json!({ "json": document, "query": "$..missing" }),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(json, json!({ "count": 0, "matches": [] }));
+
+        let json = run(
+            "json_query",
+            &json!({ "json": document, "query": "$.items[" }),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(json["error"].as_str().unwrap().starts_with("Invalid query"));
+
+        let json = run("json_query", &json!({ "json": "{", "query": "$" }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(json["error"].as_str().unwrap().starts_with("Invalid JSON"));
+    }
+
+    #[tokio::test]
+    async fn test_run_stat() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.txt");
+        fs::write(&path, "hello").unwrap();
+
+        let json = run("fs_stat", &json!({ "path": path.to_str().unwrap() }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["exists"], true);
+        assert_eq!(json["type"], "file");
+        assert_eq!(json["size"], 5);
+        assert!(json["modified"].is_string());
+        assert_eq!(json["readonly"], false);
+        assert_eq!(json["is_symlink"], false);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o440)).unwrap();
+            let json = run("fs_stat", &json!({ "path": path.to_str().unwrap() }))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(json["permissions_octal"], "0440");
+            assert_eq!(json["readonly"], true);
+
+            let link = dir.path().join("link");
+            std::os::unix::fs::symlink(&path, &link).unwrap();
+            let json = run("fs_stat", &json!({ "path": link.to_str().unwrap() }))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(json["type"], "symlink");
+            assert_eq!(json["target_type"], "file");
+            assert_eq!(json["target"], path.to_str().unwrap());
+            assert_eq!(json["size"], 5);
+
+            let broken = dir.path().join("broken");
+            std::os::unix::fs::symlink(dir.path().join("missing"), &broken).unwrap();
+            let json = run("fs_stat", &json!({ "path": broken.to_str().unwrap() }))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(json["type"], "symlink");
+            assert_eq!(json["target_type"], Value::Null);
+        }
+
+        let json = run("fs_stat", &json!({ "path": dir.path().to_str().unwrap() }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["type"], "dir");
+
+        let missing = dir.path().join("missing");
+        let json = run("fs_stat", &json!({ "path": missing.to_str().unwrap() }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json, json!({ "exists": false }));
+    }
+
+    #[tokio::test]
+    async fn test_run_find() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("crates/core/tests")).unwrap();
+        fs::create_dir_all(dir.path().join("target/debug")).unwrap();
+        fs::create_dir_all(dir.path().join(".git")).unwrap();
+        fs::write(dir.path().join("Cargo.toml"), "").unwrap();
+        fs::write(dir.path().join("crates/core/Cargo.toml"), "").unwrap();
+        fs::write(dir.path().join("crates/core/tests/test_parse.rs"), "").unwrap();
+        fs::write(dir.path().join("crates/core/tests/common.rs"), "").unwrap();
+        fs::write(dir.path().join("target/debug/Cargo.toml"), "").unwrap();
+        fs::write(dir.path().join(".git/config.toml"), "").unwrap();
+        fs::write(dir.path().join(".gitignore"), "target/\n").unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let args = json!({ "path": path, "name_pattern": "*.toml" });
+        let json = run("fs_find", &args).await.unwrap().unwrap();
+        assert_eq!(
+            json,
+            json!({ "files": ["Cargo.toml", "crates/core/Cargo.toml"], "truncated": false })
+        );
+
+        let args = json!({ "path": path, "name_pattern": "test_*" });
+        let json = run("fs_find", &args).await.unwrap().unwrap();
+        assert_eq!(json["files"], json!(["crates/core/tests/test_parse.rs"]));
+
+        let args = json!({ "path": path, "name_pattern": "tests" });
+        let json = run("fs_find", &args).await.unwrap().unwrap();
+        assert_eq!(json["files"], json!(["crates/core/tests/"]));
+
+        let args = json!({ "path": path, "name_pattern": "crates/**/*.rs", "max_results": 1 });
+        let json = run("fs_find", &args).await.unwrap().unwrap();
+        assert_eq!(json["files"], json!(["crates/core/tests/common.rs"]));
+        assert_eq!(json["truncated"], true);
+
+        let args = json!({ "path": path, "name_pattern": "*.toml", "max_depth": 1 });
+        let json = run("fs_find", &args).await.unwrap().unwrap();
+        assert_eq!(json["files"], json!(["Cargo.toml"]));
+    }
+
+    #[tokio::test]
+    async fn test_run_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src/bin")).unwrap();
+        fs::create_dir_all(dir.path().join("target/debug")).unwrap();
+        fs::write(dir.path().join("src/bin/cli.rs"), "").unwrap();
+        fs::write(dir.path().join("src/main.rs"), "").unwrap();
+        fs::write(dir.path().join("Cargo.toml"), "").unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let json = run("fs_tree", &json!({ "path": path }))
+            .await
+            .unwrap()
+            .unwrap();
+        let expected = format!(
+            "{path}\n├── src/\n│   ├── bin/\n│   │   └── cli.rs\n│   └── main.rs\n└── Cargo.toml\n"
+        );
+        assert_eq!(json["tree"], expected);
+        assert_eq!(json["truncated"], false);
+
+        let args = json!({ "path": path, "max_depth": 1, "ignore": [] });
+        let json = run("fs_tree", &args).await.unwrap().unwrap();
+        let expected = format!("{path}\n├── src/\n├── target/\n└── Cargo.toml\n");
+        assert_eq!(json["tree"], expected);
+
+        let args = json!({ "path": path, "max_entries": 2 });
+        let json = run("fs_tree", &args).await.unwrap().unwrap();
+        assert_eq!(
+            json["tree"],
+            format!(
+                "{path}\n├── src/\n│   ├── bin/\n... truncated after 2 entries, narrow `path` or lower `max_depth`\n"
+            )
+        );
+        assert_eq!(json["truncated"], true);
+
+        fs::write(dir.path().join(".gitignore"), "*.log\nbin/\n").unwrap();
+        fs::write(dir.path().join("debug.log"), "").unwrap();
+        let json = run("fs_tree", &json!({ "path": path }))
+            .await
+            .unwrap()
+            .unwrap();
+        let expected =
+            format!("{path}\n├── src/\n│   └── main.rs\n├── .gitignore\n└── Cargo.toml\n");
+        assert_eq!(json["tree"], expected);
+    }
+
+    /// A blocking tool must not stall the (current-thread) runtime it is awaited on.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run_blocking_off_executor() {
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let counter = ticks.clone();
+        let ticker = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        let json = run("command_run", &json!({ "command": "sleep 1" }))
+            .await
+            .unwrap()
+            .unwrap();
+        ticker.abort();
+        assert_eq!(json["exit_code"], 0);
+        assert!(ticks.load(Ordering::SeqCst) >= 5);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_command_run_timeout() {
+        let start = Instant::now();
+        let args = json!({ "command": "sh -c 'echo partial; sleep 600'", "timeout_secs": 1 });
+        let json = run("command_run", &args).await.unwrap().unwrap();
+        assert_eq!(json["timed_out"], true);
+        assert_eq!(json["exit_code"], Value::Null);
+        assert_eq!(json["stdout"], "partial\n");
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        let args = json!({ "command": "echo done" });
+        let json = run("command_run", &args).await.unwrap().unwrap();
+        assert_eq!(json["stdout"], "done\n");
+        assert!(json.get("timed_out").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_command_run_working_directory_and_env() {
+        let dir = tempfile::tempdir().unwrap();
+        let cwd = fs::canonicalize(dir.path()).unwrap();
+        let cwd = cwd.to_str().unwrap();
+        #[cfg(unix)]
+        let (pwd, printenv) = ("pwd", "printenv GREETING");
+        #[cfg(windows)]
+        let (pwd, printenv) = ("cmd /C cd", "cmd /C echo %GREETING%");
+
+        let args = json!({ "command": pwd, "working_directory": cwd });
+        let json = run("command_run", &args).await.unwrap().unwrap();
+        assert_eq!(json["stdout"].as_str().unwrap().trim(), cwd);
+        assert_eq!(json["cwd"], cwd);
+
+        let args = json!({ "command": printenv, "env": { "GREETING": "hello" } });
+        let json = run("command_run", &args).await.unwrap().unwrap();
+        assert_eq!(json["stdout"].as_str().unwrap().trim(), "hello");
+
+        let args = json!({ "command": pwd, "cwd": cwd });
+        let json = run("command_run", &args).await.unwrap().unwrap();
+        assert_eq!(json["stdout"].as_str().unwrap().trim(), cwd);
+
+        let missing = dir.path().join("missing");
+        let args = json!({ "command": pwd, "cwd": missing.to_str().unwrap() });
+        let json = run("command_run", &args).await.unwrap().unwrap();
+        assert!(json["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Working directory not found"));
+    }
+
+    #[tokio::test]
+    async fn test_command_run_stdin() {
+        #[cfg(unix)]
+        let sort = "sort";
+        #[cfg(windows)]
+        let sort = "cmd /C sort";
+        let args = json!({ "command": sort, "stdin": "pear\napple\nfig\n" });
+        let json = run("command_run", &args).await.unwrap().unwrap();
+        let stdout = json["stdout"].as_str().unwrap();
+        assert_eq!(stdout.lines().collect::<Vec<_>>(), ["apple", "fig", "pear"]);
+
+        #[cfg(unix)]
+        {
+            let input = "0123456789abcdef\n".repeat(256 * 1024);
+            let args = json!({ "command": "cat", "stdin": input, "max_output": input.len() });
+            let json = run("command_run", &args).await.unwrap().unwrap();
+            assert_eq!(json["stdout"].as_str().unwrap().len(), input.len());
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_command_run_progress() {
+        let lines = Mutex::new(vec![]);
+        let progress = |line: &str| lines.lock().unwrap().push(line.to_string());
+        let args = json!({ "command": "printf 'one\\ntwo\\nthree'" });
+        let json = run_with(
+            "command_run",
+            &args,
+            &BuiltinConfig::default(),
+            &Hooks {
+                progress: Some(&progress),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(json["stdout"], "one\ntwo\nthree");
+        assert_eq!(*lines.lock().unwrap(), ["one\n", "two\n", "three\n"]);
+    }
+
+    #[test]
+    fn test_read_pipe_long_line() {
+        let lines = Mutex::new(vec![]);
+        let progress = |line: &str| lines.lock().unwrap().push(line.len());
+        let input = "x".repeat(10_000);
+        let output = std::thread::scope(|scope| {
+            read_pipe(scope, Some(input.as_bytes()), 100, true, Some(&progress))
+                .join()
+                .unwrap()
+        });
+        assert_eq!(output.total, 10_000);
+        assert_eq!(*lines.lock().unwrap(), [4097, 4097, 1809]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_command_run_max_output() {
+        let script = "i=0; while [ $i -lt 20000 ]; do echo line $i ................................................; i=$((i+1)); done";
+        let args = json!({ "command": format!("sh -c '{script}'") });
+        let json = run("command_run", &args).await.unwrap().unwrap();
+        let stdout = json["stdout"].as_str().unwrap();
+        assert_eq!(json["stdout_truncated"], true);
+        assert_eq!(json["stderr_truncated"], false);
+        assert!(stdout.len() < COMMAND_RUN_MAX_OUTPUT + 100);
+        assert!(stdout.starts_with("line 0 "));
+        assert!(stdout.ends_with("line 19999 ................................................\n"));
+        assert!(stdout.contains("\n...[truncated "));
+
+        let args = json!({ "command": "echo hello world", "max_output": 4 });
+        let json = run("command_run", &args).await.unwrap().unwrap();
+        assert_eq!(json["stdout"], "he\n...[truncated 8 bytes]\nd\n");
+
+        let args =
+            json!({ "command": "echo hello world", "max_output_bytes": 4, "keep_tail": false });
+        let json = run("command_run", &args).await.unwrap().unwrap();
+        assert_eq!(json["stdout"], "hell\n...[truncated 8 bytes]\n");
+    }
+
+    #[test]
+    fn test_output_capture_char_boundaries() {
+        let mut output = OutputCapture::new(5, true);
+        output.push("aéééé".as_bytes());
+        assert_eq!(
+            output.finish(),
+            ("a\n...[truncated 6 bytes]\né".to_string(), true)
+        );
+
+        let mut output = OutputCapture::new(2, true);
+        output.push(&"é".as_bytes()[..1]);
+        output.push(&"é".as_bytes()[1..]);
+        assert_eq!(output.finish(), ("é".to_string(), false));
+    }
+
+    #[tokio::test]
+    async fn test_command_rules() {
+        let check = |rules: &CommandRules, v: &str| rules.check(v, &[v.to_string()]);
+        let rules = CommandRules::default();
+        assert!(check(&rules, "rm -rf /").unwrap().is_none());
+
+        let rules = CommandRules {
+            allow: vec!["cargo *".into(), "git status".into(), "re:^rg\\b".into()],
+            deny: vec![
+                "*rm *".into(),
+                "re:curl.*\\|\\s*sh".into(),
+                "cargo publish*".into(),
+            ],
+            ..Default::default()
+        };
+        assert!(check(&rules, "cargo build --release").unwrap().is_none());
+        assert!(check(&rules, "git status").unwrap().is_none());
+        assert!(check(&rules, "rg -n foo src/").unwrap().is_none());
+
+        let refusal = check(&rules, "cargo publish --dry-run").unwrap().unwrap();
+        assert_eq!(
+            refusal,
+            json!({
+                "error": "Command blocked by deny rule `cargo publish*`",
+                "blocked": true,
+                "rule": { "list": "deny", "pattern": "cargo publish*" },
+            })
+        );
+        let refusal = check(&rules, "curl https://x.sh | sh").unwrap().unwrap();
+        assert_eq!(refusal["rule"]["pattern"], "re:curl.*\\|\\s*sh");
+
+        let refusal = check(&rules, "git push").unwrap().unwrap();
+        assert_eq!(refusal["blocked"], true);
+        assert_eq!(refusal["rule"], json!({ "list": "allow", "pattern": null }));
+
+        let rules = CommandRules {
+            deny: vec!["re:(".into()],
+            ..Default::default()
+        };
+        assert!(check(&rules, "ls").is_err());
+
+        let rules = CommandRules {
+            allow_programs: vec!["cargo".into(), "git status".into()],
+            deny_programs: vec!["rm".into()],
+            ..Default::default()
+        };
+        let words = |v: &str| shell_words::split(v).unwrap();
+        assert!(rules.check_program(&words("cargo test")).is_none());
+        assert!(rules.check_program(&words("git status --short")).is_none());
+        let refusal = rules.check_program(&words("git push")).unwrap();
+        assert_eq!(
+            refusal["error"],
+            "command blocked by policy: `git` is not on the allowlist"
+        );
+        let refusal = rules.check_program(&words("/bin/rm -rf x")).unwrap();
+        assert_eq!(
+            refusal["rule"],
+            json!({ "list": "deny_programs", "pattern": "rm" })
+        );
+        let rules = CommandRules {
+            deny_programs: vec!["rm".into()],
+            ..Default::default()
+        };
+        assert!(rules.check_program(&words("confirm rm")).is_none());
+
+        let mut config = BuiltinConfig::default();
+        config.command_run.deny.push("echo *".into());
+        let args = json!({ "command": "echo hi" });
+        let json = run_with("command_run", &args, &config, &Hooks::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["blocked"], true);
+    }
+
+    #[test]
+    fn test_command_segments() {
+        let segments = |v: &str| command_segments(v).unwrap();
+        assert_eq!(segments("cargo build"), ["cargo build"]);
+        assert_eq!(
+            segments("cargo build; rm -rf ~ && ls | wc -l"),
+            ["cargo build", "rm -rf ~", "ls", "wc -l"]
+        );
+        assert_eq!(segments("(cd x\ny) & z"), ["cd x", "y", "z"]);
+        assert_eq!(segments("make 2>&1 &> log"), ["make 2>&1 &> log"]);
+        assert_eq!(
+            segments("echo 'a; b' \"c | d\" e\\;f"),
+            ["echo 'a; b' \"c | d\" e\\;f"]
+        );
+        assert!(command_segments("echo $(rm x)").is_none());
+        assert!(command_segments("echo \"`rm x`\"").is_none());
+        assert!(command_segments("diff <(ls a) b").is_none());
+        assert_eq!(segments("echo '$(x)'"), ["echo '$(x)'"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_command_rules_segments() {
+        let mut config = BuiltinConfig::default();
+        config.command_run.allow.push("echo *".into());
+        config.command_run.deny.push("rm *".into());
+        let run = |command: &str| {
+            let args = json!({ "command": command, "shell": "sh" });
+            let config = config.clone();
+            async move {
+                run_with("command_run", &args, &config, &Hooks::default())
+                    .await
+                    .unwrap()
+                    .unwrap()
+            }
+        };
+        let json = run("echo a; echo b").await;
+        assert_eq!(json["stdout"], "a\nb\n");
+        let json = run("echo a; rm -rf x").await;
+        assert_eq!(json["rule"]["pattern"], "rm *");
+        let json = run("echo a && touch x").await;
+        assert_eq!(json["rule"]["list"], "allow");
+        let json = run("echo $(touch x)").await;
+        assert_eq!(json["blocked"], true);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_command_run_shell_rules() {
+        let mut config = BuiltinConfig::default();
+        config.command_run.allow_programs.push("echo".into());
+        config.command_run.deny_programs.push("rm".into());
+        let run = |args: Value| {
+            let config = config.clone();
+            async move {
+                run_with("command_run", &args, &config, &Hooks::default())
+                    .await
+                    .unwrap()
+                    .unwrap()
+            }
+        };
+        let json = run(json!({ "command": "A=1 echo a && echo b", "shell": "sh" })).await;
+        assert_eq!(json["stdout"], "a\nb\n");
+        let json = run(json!({ "command": "echo a; rm -rf ~", "shell": "sh" })).await;
+        assert_eq!(
+            json["rule"],
+            json!({ "list": "deny_programs", "pattern": "rm" })
+        );
+        let json = run(json!({ "command": "echo a | tee x", "shell": "sh" })).await;
+        assert_eq!(json["rule"]["list"], "allow_programs");
+        let json = run(json!({ "command": "echo", "shell": "rm -rf /x" })).await;
+        assert_eq!(
+            json["error"],
+            "Unknown shell `rm -rf /x`; use one of none, auto, sh, bash, zsh, fish, nu, pwsh, powershell, cmd"
+        );
+
+        let mut config = config.clone();
+        config.command_run.deny_programs.push("sh".into());
+        let args = json!({ "command": "echo a", "shell": "sh" });
+        let json = run_with("command_run", &args, &config, &Hooks::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["rule"]["pattern"], "sh");
+    }
+
+    #[test]
+    fn test_shell_args() {
+        assert!(shell_args("none").unwrap().is_empty());
+        assert_eq!(shell_args("bash").unwrap(), ["bash", "-c"]);
+        assert_eq!(shell_args("zsh").unwrap(), ["zsh", "-c"]);
+        assert_eq!(
+            shell_args("pwsh").unwrap(),
+            ["pwsh", "-NoProfile", "-Command"]
+        );
+        assert_eq!(
+            shell_args("powershell").unwrap(),
+            ["powershell", "-NoProfile", "-Command"]
+        );
+        assert_eq!(shell_args("cmd").unwrap(), ["cmd", "/C"]);
+        assert_eq!(shell_args("bash -lc").unwrap(), ["bash", "-lc"]);
+        assert_eq!(
+            shell_args("'C:/Program Files/Git/bin/bash.exe' -c").unwrap(),
+            ["C:/Program Files/Git/bin/bash.exe", "-c"]
+        );
+        assert_eq!(shell_args("auto").unwrap()[0], SHELL.cmd);
+        assert!(shell_args("bash '-c").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_command_run_shell() {
+        let args = json!({ "command": "echo hello; echo world", "shell": "sh" });
+        let json = run("command_run", &args).await.unwrap().unwrap();
+        assert_eq!(json["stdout"], "hello\nworld\n");
+
+        let config = BuiltinConfig {
+            command_shell: Some("sh".into()),
+            ..Default::default()
+        };
+        let args = json!({ "command": "echo $((1 + 2))" });
+        let json = run_with("command_run", &args, &config, &Hooks::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["stdout"], "3\n");
+        let args = json!({ "command": "echo $((1 + 2))", "shell": "none" });
+        let json = run_with("command_run", &args, &config, &Hooks::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["stdout"], "$((1 + 2))\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_command_jobs() {
+        let poll = async |job_id: &Value| {
+            run("command_poll", &json!({ "job_id": job_id }))
+                .await
+                .unwrap()
+                .unwrap()
+        };
+        let args = json!({ "command": "sh -c 'for i in 1 2 3; do echo tick $i; sleep 0.1; done; exit 3'" });
+        let job = run("command_spawn", &args).await.unwrap().unwrap();
+        let job_id = &job["job_id"];
+        assert_eq!(poll(job_id).await["status"], "running");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let json = loop {
+            let json = poll(job_id).await;
+            if json["status"] == "exited" || Instant::now() > deadline {
+                break json;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+        assert_eq!(json["status"], "exited");
+        assert_eq!(json["exit_code"], 3);
+        assert_eq!(json["stdout"], "tick 1\ntick 2\ntick 3\n");
+        assert!(poll(job_id).await["error"].is_string());
+
+        let job = run("command_spawn", &json!({ "command": "sleep 600" }))
+            .await
+            .unwrap()
+            .unwrap();
+        let started = Instant::now();
+        let json = run("command_kill", &json!({ "job_id": job["job_id"] }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["status"], "exited");
+        assert!(json["exit_code"].is_null());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_fs_root() {
+        let outside = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        fs::write(outside.path().join("secret.txt"), "secret").unwrap();
+        fs::create_dir(root.join("src")).unwrap();
+        fs::write(root.join("src/main.rs"), "fn main() {}").unwrap();
+
+        assert_eq!(
+            jail_path("src/main.rs", &root).unwrap(),
+            root.join("src/main.rs")
+        );
+        assert_eq!(
+            jail_path("src/../new/a.txt", &root).unwrap(),
+            root.join("new/a.txt")
+        );
+        let outside_file = outside.path().join("secret.txt");
+        assert!(jail_path(outside_file.to_str().unwrap(), &root).is_err());
+        assert!(jail_path("../secret.txt", &root).is_err());
+        assert!(jail_path("missing/../../x", &root).is_err());
+
+        let config = BuiltinConfig {
+            fs_root: Some(root.clone()),
+            ..Default::default()
+        };
+        let run =
+            async |name: &str, args: Value| run_with(name, &args, &config, &Hooks::default()).await;
+        let json = run("fs_cat", json!({ "path": "src/main.rs" }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["content"], "fn main() {}");
+        let json = run("fs_ls", json!({})).await.unwrap().unwrap();
+        assert_eq!(json["files"][0]["name"], "src");
+        run("fs_write", json!({ "path": "out.txt", "contents": "hi" }))
+            .await
+            .unwrap();
+        assert_eq!(fs::read_to_string(root.join("out.txt")).unwrap(), "hi");
+        let err = run("fs_cat", json!({ "path": "../secret.txt" }))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("outside the sandbox root"));
+        assert!(
+            run("fs_rm", json!({ "path": outside_file.to_str().unwrap() }))
+                .await
+                .is_err()
+        );
+        assert!(outside_file.exists());
+        let json =
+            json!({ "from": "out.txt", "to": outside.path().join("out.txt").to_str().unwrap() });
+        assert!(run("fs_mv", json).await.is_err());
+        let patch = "--- /dev/null\n+++ b/../escape.txt\n@@ -0,0 +1 @@\n+x\n";
+        assert!(run("fs_apply_patch", json!({ "patch": patch }))
+            .await
+            .is_err());
+        let json = run("fs_rm", json!({ "path": ".", "recursive": true }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(json["error"].as_str().unwrap().starts_with("Refusing"));
+        assert!(root.join("src/main.rs").exists());
+        let outside_dir = outside.path().to_str().unwrap();
+        for (name, key) in [
+            ("command_run", "working_directory"),
+            ("command_spawn", "cwd"),
+            ("git_status", "repo"),
+            ("git_log", "cwd"),
+        ] {
+            let args = json!({ "command": "ls", key: outside_dir });
+            assert!(run(name, args).await.is_err(), "{name}");
+        }
+        #[cfg(unix)]
+        {
+            let json = run("command_run", json!({ "command": "pwd" }))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                json["stdout"].as_str().unwrap().trim(),
+                root.to_str().unwrap()
+            );
+        }
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(outside.path(), root.join("link")).unwrap();
+            assert!(run("fs_cat", json!({ "path": "link/secret.txt" }))
+                .await
+                .is_err());
+            std::os::unix::fs::symlink(outside.path().join("new.txt"), root.join("dangling"))
+                .unwrap();
+            assert!(
+                run("fs_write", json!({ "path": "dangling", "contents": "x" }))
+                    .await
+                    .is_err()
+            );
+            assert!(!outside.path().join("new.txt").exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_git_tools() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().to_str().unwrap();
+        let json = run("git_status", &json!({ "repo": repo }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["not_a_repository"], true);
+        assert_eq!(json["error"], "not a git repository");
+
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .args([
+                    "-C",
+                    repo,
+                    "-c",
+                    "user.name=Test",
+                    "-c",
+                    "user.email=test@example.com",
+                ])
+                .args(args)
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success(), "git {args:?}");
+        };
+        git(&["init", "-q", "-b", "main"]);
+        let json = run("git_log", &json!({ "repo": repo }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["commits"], json!([]));
+
+        fs::write(dir.path().join("a.txt"), "one\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "two\n").unwrap();
+        git(&["add", "."]);
+        git(&["commit", "-q", "-m", "First commit"]);
+        fs::write(dir.path().join("a.txt"), "one\nmore\n").unwrap();
+        fs::write(dir.path().join("c.txt"), "three\n").unwrap();
+        git(&["mv", "b.txt", "d.txt"]);
+
+        let json = run("git_status", &json!({ "repo": repo }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["branch"], "main");
+        assert_eq!(
+            json["files"],
+            json!([
+                { "path": "a.txt", "status": "modified", "code": " M", "staged": false },
+                { "path": "d.txt", "status": "renamed", "code": "R ", "staged": true, "orig_path": "b.txt" },
+                { "path": "c.txt", "status": "untracked", "code": "??", "staged": false },
+            ])
+        );
+
+        let json = run("git_diff", &json!({ "cwd": repo }))
+            .await
+            .unwrap()
+            .unwrap();
+        let diff = json["diff"].as_str().unwrap();
+        assert!(diff.contains("+++ b/a.txt"));
+        assert!(diff.contains("+more"));
+        assert_eq!(
+            json["files"],
+            json!([{
+                "path": "a.txt",
+                "status": "modified",
+                "hunks": [{
+                    "old_start": 1,
+                    "old_lines": 1,
+                    "new_start": 1,
+                    "new_lines": 2,
+                    "added": 1,
+                    "removed": 0,
+                }],
+            }])
+        );
+        let json = run("git_diff", &json!({ "repo": repo, "staged": true }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(json["diff"].as_str().unwrap().contains("rename to d.txt"));
+        assert_eq!(json["files"], json!([]));
+        fs::write(dir.path().join("d.txt"), "two\nfour\n").unwrap();
+        git(&["add", "d.txt"]);
+        let args = json!({ "repo": repo, "staged": true, "max_bytes": 10 });
+        let json = run("git_diff", &args).await.unwrap().unwrap();
+        assert_eq!(json["truncated"], true);
+        assert_eq!(json["diff"].as_str().unwrap().len(), 10);
+        let files = json["files"].as_array().unwrap();
+        let paths: Vec<_> = files.iter().map(|v| v["path"].as_str().unwrap()).collect();
+        assert_eq!(paths, ["b.txt", "d.txt"]);
+        let json = run("git_diff", &json!({ "repo": repo, "path": "c.txt" }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["diff"], "");
+
+        git(&["add", "."]);
+        git(&["commit", "-q", "-m", "Second commit"]);
+        let json = run("git_log", &json!({ "repo": repo }))
+            .await
+            .unwrap()
+            .unwrap();
+        let commits = json["commits"].as_array().unwrap();
+        assert_eq!(commits.len(), 2);
+        assert_eq!(commits[0]["subject"], "Second commit");
+        assert_eq!(commits[0]["author"], "Test");
+        assert_eq!(commits[0]["hash"].as_str().unwrap().len(), 40);
+        assert_eq!(commits[1]["subject"], "First commit");
+        let json = run("git_log", &json!({ "repo": repo, "limit": 1 }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["commits"].as_array().unwrap().len(), 1);
+        let json = run("git_log", &json!({ "cwd": repo, "count": 1 }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["commits"].as_array().unwrap().len(), 1);
+        let json = run("git_log", &json!({ "repo": repo, "path": "a.txt" }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(json["commits"].as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_git_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().to_str().unwrap();
+        let git = |args: &[&str]| {
+            let output = Command::new("git")
+                .args(["-C", repo])
+                .args(args)
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "git {args:?}");
+            String::from_utf8(output.stdout).unwrap()
+        };
+        git(&["init", "-q"]);
+        git(&["config", "user.name", ""]);
+        fs::write(dir.path().join("a.txt"), "one\n").unwrap();
+        let commit = async |args: Value| run("git_commit", &args).await.unwrap().unwrap();
+
+        let json = commit(json!({ "repo": repo, "message": "Add a" })).await;
+        assert_eq!(json["missing_config"], "user.name");
+        git(&["config", "user.name", "Test"]);
+        git(&["config", "user.email", "test@example.com"]);
+
+        let json = commit(json!({ "repo": repo, "message": "Add a" })).await;
+        let hash = json["hash"].as_str().unwrap();
+        assert_eq!(hash, git(&["rev-parse", "HEAD"]).trim());
+        assert!(json["stat"].as_str().unwrap().contains("a.txt"));
+
+        let json = commit(json!({ "repo": repo, "message": "Nothing" })).await;
+        assert_eq!(json["nothing_to_commit"], true);
+
+        fs::write(dir.path().join("b.txt"), "two\n").unwrap();
+        fs::write(dir.path().join("c.txt"), "it's \"quoted\"\n").unwrap();
+        let message = "Add c\n\nWith \"quotes\" and 'apostrophes'";
+        let json = commit(json!({ "repo": repo, "message": message, "paths": ["c.txt"] })).await;
+        assert!(json["hash"].is_string());
+        assert_eq!(git(&["log", "-1", "--format=%B"]).trim(), message);
+        assert_eq!(
+            git(&["show", "--name-only", "--format=", "HEAD"]),
+            "c.txt\n"
+        );
+        assert_eq!(git(&["status", "--short"]), "?? b.txt\n");
+
+        let description = describe_call("git_commit", &json!({ "repo": repo, "message": "Add b" }));
+        assert!(description.contains("Add b"));
+        assert!(description.contains("?? b.txt"));
+
+        let outside = tempfile::tempdir().unwrap();
+        let json = commit(json!({ "repo": outside.path(), "message": "x" })).await;
+        assert_eq!(json["not_a_repository"], true);
+    }
+
+    #[tokio::test]
+    async fn test_command_run_injection() {
+        let args = json!({ "command": "echo hello; echo world" });
+        let result = run("command_run", &args).await.unwrap();
+        assert!(result.is_some());
+        let json = result.unwrap();
+        let stdout = json["stdout"].as_str().unwrap();
+        assert!(stdout.contains(";"));
+    }
+}
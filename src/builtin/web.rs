@@ -0,0 +1,2414 @@
+use super::*;
+
+use rand::Rng;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, Response, Url};
+use scraper::{ElementRef, Html, Selector};
+use serde::Serialize;
+use std::collections::HashSet;
+
+const WEB_SEARCH_MAX_RESULTS: usize = 5;
+const WEB_SEARCH_MAX_RESULTS_CAP: usize = 20;
+const WEB_SEARCH_RETRIES: u32 = 2;
+const WEB_SEARCH_BACKOFF_MS: u64 = 1000;
+const WEB_BROWSE_MAX_BYTES: usize = 10 * 1024 * 1024;
+const WEB_BROWSE_MAX_CHARS: usize = 20_000;
+const WEB_BROWSE_MIN_CHARS: usize = 100;
+const WEB_BROWSE_MIN_PARAGRAPH: usize = 25;
+const WEB_BROWSE_MIN_ARTICLE: usize = 200;
+const WEB_BROWSE_CACHE_TTL: u64 = 600;
+const WEB_BROWSE_CACHE_ENTRIES: usize = 32;
+const WEB_BROWSE_MAX_LINKS: usize = 100;
+const WEB_TIMEOUT: u64 = 30;
+const HTTP_REQUEST_MAX_BODY: usize = 100 * 1024;
+const DOWNLOAD_MAX_BYTES: u64 = 100 * 1024 * 1024;
+const DOWNLOAD_TIMEOUT: u64 = 300;
+const WEB_META_CHARSET_SCAN: usize = 1024;
+/// Headers that describe the connection rather than the request, plus `host` and
+/// `content-length`, which must match the URL and body.
+const HOP_BY_HOP_HEADERS: [&str; 10] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "host",
+    "content-length",
+];
+const PDF_MAGIC: &[u8] = b"%PDF-";
+/// Content types that say nothing about the body, which is then sniffed for a PDF.
+const UNTYPED_CONTENT_TYPES: [&str; 4] = [
+    "application/octet-stream",
+    "binary/octet-stream",
+    "application/download",
+    "application/force-download",
+];
+const WEB_CONNECT_TIMEOUT: u64 = 10;
+const WEB_MAX_REDIRECTS: usize = 10;
+const WEB_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+const DUCKDUCKGO_URL: &str = "https://lite.duckduckgo.com/lite/";
+const BRAVE_URL: &str = "https://api.search.brave.com/res/v1/web/search";
+const TAVILY_URL: &str = "https://api.tavily.com/search";
+const DUCKDUCKGO_CHALLENGE_MARKERS: [&str; 3] =
+    ["anomaly-modal", "bots use DuckDuckGo too", "challenge-form"];
+
+/// The client every web tool sends through, so connections and TLS sessions are reused.
+static WEB_CLIENT: LazyLock<Client> = LazyLock::new(|| {
+    Client::builder()
+        .user_agent(WEB_USER_AGENT)
+        .redirect(reqwest::redirect::Policy::limited(WEB_MAX_REDIRECTS))
+        .connect_timeout(Duration::from_secs(WEB_CONNECT_TIMEOUT))
+        .timeout(Duration::from_secs(WEB_TIMEOUT))
+        .build()
+        .expect("failed to build the web client")
+});
+
+static PAGE_CACHE: LazyLock<Mutex<PageCache>> = LazyLock::new(Default::default);
+
+static TITLE_SELECTOR: LazyLock<Selector> = LazyLock::new(|| Selector::parse("title").unwrap());
+
+/// Page chrome dropped before looking for the main content.
+static NOISE_SELECTOR: LazyLock<Selector> = LazyLock::new(|| {
+    Selector::parse(
+        "script, style, noscript, template, iframe, nav, aside, footer, form, button, \
+         [role=navigation], [role=complementary], [aria-hidden=true], \
+         [id*=cookie], [class*=cookie], [id*=consent], [class*=consent]",
+    )
+    .unwrap()
+});
+
+/// Elements that mark the main content outright.
+static MAIN_SELECTOR: LazyLock<Selector> =
+    LazyLock::new(|| Selector::parse("article, main, [role=main]").unwrap());
+
+static PARAGRAPH_SELECTOR: LazyLock<Selector> =
+    LazyLock::new(|| Selector::parse("p, pre, blockquote").unwrap());
+
+static LINK_SELECTOR: LazyLock<Selector> = LazyLock::new(|| Selector::parse("a").unwrap());
+
+static META_CHARSET: LazyLock<regex::bytes::Regex> = LazyLock::new(|| {
+    regex::bytes::Regex::new(r#"(?i)<meta[^>]*?charset\s*=\s*["']?\s*([a-z0-9_:.\-]+)"#).unwrap()
+});
+
+static BASE_SELECTOR: LazyLock<Selector> = LazyLock::new(|| Selector::parse("base[href]").unwrap());
+
+static DUCKDUCKGO_RESULT: LazyLock<Selector> =
+    LazyLock::new(|| Selector::parse("a.result-link, td.result-snippet").unwrap());
+
+/// Settings for `web_search`, read from `builtin_functions.web_search`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WebSearchConfig {
+    /// The search backend; duckduckgo needs no key.
+    pub provider: SearchProvider,
+    /// The key for `provider` when it is brave or tavily, falling back to `BRAVE_API_KEY` or
+    /// `TAVILY_API_KEY`. It is never sent to an engine picked per call.
+    pub api_key: Option<String>,
+    /// The base URL of the SearXNG instance, e.g. `http://localhost:8080`.
+    pub endpoint: Option<String>,
+    /// How many times to retry DuckDuckGo when it answers with its bot challenge page.
+    pub retries: u32,
+}
+
+impl Default for WebSearchConfig {
+    fn default() -> Self {
+        Self {
+            provider: Default::default(),
+            api_key: None,
+            endpoint: None,
+            retries: WEB_SEARCH_RETRIES,
+        }
+    }
+}
+
+/// Settings for `web_browse`, read from `builtin_functions.web_browse`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WebBrowseConfig {
+    /// The default cap in characters on the returned content.
+    pub max_chars: usize,
+    /// How long in seconds a fetched page is served from memory; 0 disables the cache.
+    pub cache_ttl: u64,
+    /// How many pages the cache holds before dropping the least recently used.
+    pub cache_entries: usize,
+}
+
+impl Default for WebBrowseConfig {
+    fn default() -> Self {
+        Self {
+            max_chars: WEB_BROWSE_MAX_CHARS,
+            cache_ttl: WEB_BROWSE_CACHE_TTL,
+            cache_entries: WEB_BROWSE_CACHE_ENTRIES,
+        }
+    }
+}
+
+/// Settings for `http_request`, read from `builtin_functions.http_request`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HttpRequestConfig {
+    /// Whether the tool is offered to the model at all.
+    pub enabled: bool,
+    /// The default request timeout in seconds.
+    pub timeout: u64,
+    /// The cap in bytes on the returned response body.
+    pub max_body: usize,
+}
+
+impl Default for HttpRequestConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            timeout: WEB_TIMEOUT,
+            max_body: HTTP_REQUEST_MAX_BODY,
+        }
+    }
+}
+
+/// Settings for `download_file`, read from `builtin_functions.download_file`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DownloadFileConfig {
+    /// The largest download in bytes; bigger ones are stopped and discarded.
+    pub max_bytes: u64,
+    /// The timeout in seconds for the whole download.
+    pub timeout: u64,
+}
+
+impl Default for DownloadFileConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: DOWNLOAD_MAX_BYTES,
+            timeout: DOWNLOAD_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchProvider {
+    #[default]
+    DuckDuckGo,
+    SearXNG,
+    Brave,
+    Tavily,
+}
+
+impl SearchProvider {
+    fn name(&self) -> &'static str {
+        match self {
+            SearchProvider::DuckDuckGo => "duckduckgo",
+            SearchProvider::SearXNG => "searxng",
+            SearchProvider::Brave => "brave",
+            SearchProvider::Tavily => "tavily",
+        }
+    }
+}
+
+/// Returned by an engine that keeps refusing queries, so the model hears it was throttled
+/// rather than seeing an empty result list.
+#[derive(Debug)]
+struct RateLimited;
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "search provider rate limited")
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// One search hit; every provider is mapped onto this shape.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct SearchResult {
+    title: String,
+    url: String,
+    snippet: String,
+    /// The link as the provider returned it, when `url` had to be unwrapped from it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw_url: Option<String>,
+}
+
+impl SearchResult {
+    fn new(title: &str, url: &str, snippet: &str) -> Self {
+        Self {
+            title: normalize_whitespace(title),
+            url: url.to_string(),
+            snippet: normalize_whitespace(snippet),
+            raw_url: None,
+        }
+    }
+}
+
+pub(super) struct WebSearch;
+
+#[async_trait]
+impl BuiltinTool for WebSearch {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "web_search".to_string(),
+            description: "Search the web. Returns `results` as `{title, url, snippet}` entries."
+                .to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "query": {
+                        "type": "string",
+                        "description": "The search query"
+                    },
+                    "engine": {
+                        "type": "string",
+                        "enum": ["duckduckgo", "searxng", "brave", "tavily"],
+                        "description": "The search engine to use instead of the configured one"
+                    },
+                    "max_results": {
+                        "type": "integer",
+                        "description": "The maximum number of results (defaults to 5, at most 20)"
+                    },
+                    "count": {
+                        "type": "integer",
+                        "description": "Alias of `max_results`"
+                    },
+                    "timeout_seconds": {
+                        "type": "integer",
+                        "description": "Give up after this many seconds (defaults to 30)"
+                    }
+                },
+                "required": ["query"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    fn blocking(&self) -> bool {
+        false
+    }
+
+    async fn run(&self, args: &Value, ctx: &Context<'_>) -> Result<Value> {
+        let query = args["query"]
+            .as_str()
+            .filter(|v| !v.trim().is_empty())
+            .ok_or_else(|| anyhow!("Missing query"))?;
+        let config = &ctx.config.web_search;
+        let provider = match args.get("engine").filter(|v| !v.is_null()) {
+            Some(engine) => match SearchProvider::deserialize(engine) {
+                Ok(provider) => provider,
+                Err(_) => return Ok(json!({ "error": format!("Unknown search engine {engine}") })),
+            },
+            None => config.provider,
+        };
+        let engine = match search_engine(config, provider) {
+            Ok(engine) => engine,
+            Err(err) => return Ok(json!({ "error": err })),
+        };
+        let provider = engine.provider().name();
+        let timeout = Duration::from_secs(args["timeout_seconds"].as_u64().unwrap_or(WEB_TIMEOUT));
+        let limit = args["max_results"]
+            .as_u64()
+            .or_else(|| args["count"].as_u64())
+            .map(|v| (v as usize).clamp(1, WEB_SEARCH_MAX_RESULTS_CAP))
+            .unwrap_or(WEB_SEARCH_MAX_RESULTS);
+        match engine.search(query, limit, timeout).await {
+            Ok(mut results) => {
+                results.truncate(limit);
+                Ok(json!({ "provider": provider, "results": results }))
+            }
+            Err(err) if err.is::<RateLimited>() => Ok(json!({
+                "error": err.to_string(),
+                "rate_limited": true,
+                "provider": provider,
+            })),
+            Err(err) => Ok(json!({
+                "error": format!("{provider} search failed: {err}"),
+                "provider": provider,
+            })),
+        }
+    }
+}
+
+/// A search backend, built from the config by [`search_engine`].
+#[async_trait]
+trait SearchEngine: Send + Sync {
+    fn provider(&self) -> SearchProvider;
+
+    /// Sends `query` and returns up to `limit` results.
+    async fn search(
+        &self,
+        query: &str,
+        limit: usize,
+        timeout: Duration,
+    ) -> Result<Vec<SearchResult>>;
+}
+
+/// Scrapes DuckDuckGo Lite, which needs no key.
+struct DuckDuckGo {
+    retries: u32,
+}
+
+#[async_trait]
+impl SearchEngine for DuckDuckGo {
+    fn provider(&self) -> SearchProvider {
+        SearchProvider::DuckDuckGo
+    }
+
+    async fn search(
+        &self,
+        query: &str,
+        _limit: usize,
+        timeout: Duration,
+    ) -> Result<Vec<SearchResult>> {
+        let mut attempt = 0;
+        loop {
+            let html = WEB_CLIENT
+                .post(DUCKDUCKGO_URL)
+                .timeout(timeout)
+                .form(&[("q", query)])
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            let results = parse_duckduckgo(&html);
+            if !results.is_empty() || !is_duckduckgo_challenge(&html) {
+                return Ok(results);
+            }
+            if attempt >= self.retries {
+                return Err(RateLimited.into());
+            }
+            tokio::time::sleep(backoff_delay(attempt)).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Queries the JSON API of a SearXNG instance, which must have the json format enabled.
+struct SearXNG {
+    endpoint: String,
+}
+
+#[async_trait]
+impl SearchEngine for SearXNG {
+    fn provider(&self) -> SearchProvider {
+        SearchProvider::SearXNG
+    }
+
+    async fn search(
+        &self,
+        query: &str,
+        _limit: usize,
+        timeout: Duration,
+    ) -> Result<Vec<SearchResult>> {
+        let data: Value = WEB_CLIENT
+            .get(format!("{}/search", self.endpoint))
+            .timeout(timeout)
+            .query(&[("q", query), ("format", "json")])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(parse_searxng(&data))
+    }
+}
+
+struct Brave {
+    api_key: String,
+}
+
+#[async_trait]
+impl SearchEngine for Brave {
+    fn provider(&self) -> SearchProvider {
+        SearchProvider::Brave
+    }
+
+    async fn search(
+        &self,
+        query: &str,
+        limit: usize,
+        timeout: Duration,
+    ) -> Result<Vec<SearchResult>> {
+        let data: Value = WEB_CLIENT
+            .get(BRAVE_URL)
+            .timeout(timeout)
+            .query(&[("q", query), ("count", &limit.to_string())])
+            .header("X-Subscription-Token", &self.api_key)
+            .header("Accept", "application/json")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(parse_brave(&data))
+    }
+}
+
+struct Tavily {
+    api_key: String,
+}
+
+#[async_trait]
+impl SearchEngine for Tavily {
+    fn provider(&self) -> SearchProvider {
+        SearchProvider::Tavily
+    }
+
+    async fn search(
+        &self,
+        query: &str,
+        limit: usize,
+        timeout: Duration,
+    ) -> Result<Vec<SearchResult>> {
+        let data: Value = WEB_CLIENT
+            .post(TAVILY_URL)
+            .timeout(timeout)
+            .bearer_auth(&self.api_key)
+            .json(&json!({ "query": query, "max_results": limit }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(parse_tavily(&data))
+    }
+}
+
+/// Builds the engine for `provider`, or explains which setting it is missing. The configured
+/// `api_key` belongs to the configured provider, so an engine picked per call only gets the
+/// key from its own environment variable.
+fn search_engine(
+    config: &WebSearchConfig,
+    provider: SearchProvider,
+) -> Result<Box<dyn SearchEngine>, String> {
+    let configured = provider == config.provider;
+    let api_key = |var: &str| {
+        config
+            .api_key
+            .clone()
+            .filter(|_| configured)
+            .or_else(|| env::var(var).ok())
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| match configured {
+                true => format!(
+                    "web_search provider `{}` needs `web_search.api_key` or {var}",
+                    provider.name()
+                ),
+                false => format!("web_search engine `{}` needs {var}", provider.name()),
+            })
+    };
+    match provider {
+        SearchProvider::DuckDuckGo => Ok(Box::new(DuckDuckGo {
+            retries: config.retries,
+        })),
+        SearchProvider::SearXNG => match config.endpoint.as_deref().filter(|v| !v.is_empty()) {
+            Some(endpoint) => Ok(Box::new(SearXNG {
+                endpoint: endpoint.trim_end_matches('/').to_string(),
+            })),
+            None => Err("web_search provider `searxng` needs `web_search.endpoint`".to_string()),
+        },
+        SearchProvider::Brave => Ok(Box::new(Brave {
+            api_key: api_key("BRAVE_API_KEY")?,
+        })),
+        SearchProvider::Tavily => Ok(Box::new(Tavily {
+            api_key: api_key("TAVILY_API_KEY")?,
+        })),
+    }
+}
+
+/// Parses a DuckDuckGo Lite page, where each result link is followed by a snippet row.
+fn parse_duckduckgo(html: &str) -> Vec<SearchResult> {
+    let document = Html::parse_document(html);
+    let mut results: Vec<SearchResult> = vec![];
+    for element in document.select(&DUCKDUCKGO_RESULT) {
+        let text = element.text().collect::<String>();
+        if element.value().name() == "a" {
+            if let Some(href) = element.value().attr("href") {
+                let url = duckduckgo_target(href);
+                let mut result = SearchResult::new(&text, &url, "");
+                if url != href {
+                    result.raw_url = Some(href.to_string());
+                }
+                results.push(result);
+            }
+        } else if let Some(last) = results.last_mut() {
+            last.snippet = normalize_whitespace(&text);
+        }
+    }
+    results
+}
+
+/// Whether a page is DuckDuckGo's bot challenge, which it serves with a 200 when throttling.
+fn is_duckduckgo_challenge(html: &str) -> bool {
+    DUCKDUCKGO_CHALLENGE_MARKERS
+        .iter()
+        .any(|marker| html.contains(marker))
+}
+
+/// Exponential backoff from [`WEB_SEARCH_BACKOFF_MS`], with up to 50% jitter so parallel
+/// callers do not retry in lockstep.
+fn backoff_delay(attempt: u32) -> Duration {
+    let base = WEB_SEARCH_BACKOFF_MS << attempt.min(6);
+    let jitter = rand::rng().random_range(0..=base / 2);
+    Duration::from_millis(base + jitter)
+}
+
+/// Unwraps a DuckDuckGo redirect link such as `//duckduckgo.com/l/?uddg=<encoded url>`
+/// to its target, and makes protocol-relative links https. Anything else is returned as is.
+fn duckduckgo_target(href: &str) -> String {
+    let href = match href.strip_prefix("//") {
+        Some(rest) => format!("https://{rest}"),
+        None => href.to_string(),
+    };
+    let Ok(url) = Url::parse(&href) else {
+        return href;
+    };
+    let is_redirect = url
+        .host_str()
+        .is_some_and(|host| host == "duckduckgo.com" || host.ends_with(".duckduckgo.com"))
+        && url.path() == "/l/";
+    if is_redirect {
+        let target = url
+            .query_pairs()
+            .find(|(key, _)| key == "uddg")
+            .map(|(_, value)| value.into_owned());
+        if let Some(target) = target.filter(|v| Url::parse(v).is_ok()) {
+            return target;
+        }
+    }
+    href
+}
+
+fn parse_searxng(data: &Value) -> Vec<SearchResult> {
+    json_results(&data["results"], "content")
+}
+
+fn parse_brave(data: &Value) -> Vec<SearchResult> {
+    json_results(&data["web"]["results"], "description")
+}
+
+fn parse_tavily(data: &Value) -> Vec<SearchResult> {
+    json_results(&data["results"], "content")
+}
+
+/// Collapses the line breaks and runs of spaces that markup leaves in titles and snippets.
+fn normalize_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Maps a JSON array of `{title, url, <snippet_key>}` objects, skipping entries without a URL.
+fn json_results(items: &Value, snippet_key: &str) -> Vec<SearchResult> {
+    let Some(items) = items.as_array() else {
+        return vec![];
+    };
+    items
+        .iter()
+        .filter_map(|item| {
+            Some(SearchResult::new(
+                item["title"].as_str().unwrap_or_default(),
+                item["url"].as_str()?,
+                item[snippet_key].as_str().unwrap_or_default(),
+            ))
+        })
+        .collect()
+}
+
+pub(super) struct WebBrowse;
+
+#[async_trait]
+impl BuiltinTool for WebBrowse {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "web_browse".to_string(),
+            description: "Fetch a web page and return its content: the main content of HTML pages as markdown, JSON pretty-printed, PDF as extracted text and plain text as is.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "url": {
+                        "type": "string",
+                        "description": "The http or https URL to fetch"
+                    },
+                    "max_chars": {
+                        "type": "integer",
+                        "description": "The maximum number of characters to return (defaults to 20000, at least 100)"
+                    },
+                    "max_length": {
+                        "type": "integer",
+                        "description": "Alias of `max_chars`"
+                    },
+                    "offset": {
+                        "type": "integer",
+                        "description": "Start this many characters into the content, to read the page after a truncated one"
+                    },
+                    "selector": {
+                        "type": "string",
+                        "description": "A CSS selector; only the matching elements of an HTML page are converted"
+                    },
+                    "full_page": {
+                        "type": "boolean",
+                        "description": "Convert the whole HTML page instead of only its main content (defaults to false)"
+                    },
+                    "include_links": {
+                        "type": "boolean",
+                        "description": "Also return the page's links as `links`, a list of `{url, text}`"
+                    },
+                    "no_cache": {
+                        "type": "boolean",
+                        "description": "Fetch the page again even if it was browsed in the last few minutes"
+                    },
+                    "raw": {
+                        "type": "boolean",
+                        "description": "Return the body as fetched, e.g. HTML source, instead of converting it (defaults to false)"
+                    },
+                    "headers": {
+                        "type": "object",
+                        "description": "Extra request headers, such as `Accept` or `Cookie`, as an object of names to string values",
+                        "additionalProperties": { "type": "string" }
+                    },
+                    "timeout_seconds": {
+                        "type": "integer",
+                        "description": "Give up after this many seconds (defaults to 30)"
+                    }
+                },
+                "required": ["url"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    fn blocking(&self) -> bool {
+        false
+    }
+
+    async fn run(&self, args: &Value, ctx: &Context<'_>) -> Result<Value> {
+        let url = args["url"].as_str().ok_or_else(|| anyhow!("Missing url"))?;
+        let config = &ctx.config.web_browse;
+        let max_chars = args["max_chars"]
+            .as_u64()
+            .or_else(|| args["max_length"].as_u64())
+            .map(|v| v as usize)
+            .unwrap_or(config.max_chars)
+            .max(WEB_BROWSE_MIN_CHARS);
+        let offset = args["offset"].as_u64().unwrap_or(0) as usize;
+        let include_links = args["include_links"].as_bool().unwrap_or(false);
+        let respond = |page: &Page, cached| {
+            let mut value = page.to_value(url, offset, max_chars, cached);
+            if include_links {
+                value["links"] = json!(page.links);
+            }
+            value
+        };
+        let scope = match args["selector"].as_str() {
+            Some(selector) => match Selector::parse(selector) {
+                Ok(parsed) => HtmlScope::Selector(parsed),
+                Err(err) => {
+                    return Ok(json!({
+                        "error": format!("Invalid selector `{selector}`: {err}"),
+                    }))
+                }
+            },
+            None if args["full_page"].as_bool().unwrap_or(false) => HtmlScope::FullPage,
+            None => HtmlScope::Article,
+        };
+        let key = json!([
+            url,
+            args["selector"],
+            args["full_page"],
+            args["raw"],
+            args["headers"]
+        ])
+        .to_string();
+        let ttl = Duration::from_secs(config.cache_ttl);
+        let use_cache = !args["no_cache"].as_bool().unwrap_or(false) && !ttl.is_zero();
+        if use_cache {
+            let cached = PAGE_CACHE.lock().unwrap().get(&key, ttl, Instant::now());
+            if let Some(page) = cached {
+                return Ok(respond(&page, true));
+            }
+        }
+        let page = match fetch_page(url, args, scope).await? {
+            Ok(page) => Arc::new(page),
+            Err(err) => return Ok(err),
+        };
+        if use_cache {
+            let mut cache = PAGE_CACHE.lock().unwrap();
+            cache.insert(key, page.clone(), config.cache_entries, Instant::now());
+        }
+        Ok(respond(&page, false))
+    }
+}
+
+/// A fetched page after conversion, as `web_browse` caches it.
+#[derive(Debug)]
+struct Page {
+    /// The URL the content came from, after redirects.
+    final_url: String,
+    status: u16,
+    title: Option<String>,
+    kind: BodyKind,
+    content_type: String,
+    /// The encoding the body was decoded with; `None` for PDFs.
+    charset: Option<&'static str>,
+    content: String,
+    /// Whether the download stopped at [`WEB_BROWSE_MAX_BYTES`].
+    body_truncated: bool,
+    /// The links of an HTML page, whatever part of it was converted.
+    links: Vec<Link>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct Link {
+    url: String,
+    text: String,
+}
+
+impl Page {
+    fn to_value(&self, url: &str, offset: usize, max_chars: usize, cached: bool) -> Value {
+        let (content, next_offset) = page_content(&self.content, offset, max_chars);
+        json!({
+            "url": url,
+            "final_url": self.final_url,
+            "status": self.status,
+            "title": self.title,
+            "kind": self.kind.name(),
+            "content_type": self.content_type,
+            "charset": self.charset,
+            "content": content,
+            "truncated": next_offset.is_some() || self.body_truncated,
+            "total_chars": self.content.chars().count(),
+            "next_offset": next_offset,
+            "cached": cached,
+        })
+    }
+}
+
+/// Pages `web_browse` has converted, keyed by URL and scope, least recently used first.
+#[derive(Default)]
+struct PageCache {
+    entries: IndexMap<String, (Instant, Arc<Page>)>,
+}
+
+impl PageCache {
+    fn get(&mut self, key: &str, ttl: Duration, now: Instant) -> Option<Arc<Page>> {
+        let (stored, page) = self.entries.shift_remove(key)?;
+        if now.saturating_duration_since(stored) >= ttl {
+            return None;
+        }
+        self.entries.insert(key.to_string(), (stored, page.clone()));
+        Some(page)
+    }
+
+    fn insert(&mut self, key: String, page: Arc<Page>, capacity: usize, now: Instant) {
+        self.entries.shift_remove(&key);
+        self.entries.insert(key, (now, page));
+        while self.entries.len() > capacity {
+            self.entries.shift_remove_index(0);
+        }
+    }
+}
+
+/// Fetches and converts `url`, or returns the error to hand back to the model.
+async fn fetch_page(url: &str, args: &Value, scope: HtmlScope) -> Result<Result<Page, Value>> {
+    let timeout = Duration::from_secs(args["timeout_seconds"].as_u64().unwrap_or(WEB_TIMEOUT));
+    let headers = match request_headers(&args["headers"]) {
+        Ok(headers) => headers,
+        Err(err) => return Ok(Err(json!({ "error": err }))),
+    };
+    let request = WEB_CLIENT.get(url).headers(headers).timeout(timeout);
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(err) => {
+            if err.is_redirect() {
+                return Ok(Err(json!({
+                    "error": format!("{url} redirects more than {WEB_MAX_REDIRECTS} times, or in a loop"),
+                    "too_many_redirects": true,
+                })));
+            }
+            return Ok(Err(
+                json!({ "error": format!("Failed to fetch {url}: {err}") }),
+            ));
+        }
+    };
+    let status = response.status();
+    let final_url = response.url().to_string();
+    if !status.is_success() {
+        return Ok(Err(json!({
+            "error": format!("{final_url} answered {status}"),
+            "status": status.as_u16(),
+            "final_url": final_url,
+        })));
+    }
+    let header = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("text/html");
+    let content_type = header
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let charset = charset_param(header).map(|v| v.to_string());
+    let declared = BodyKind::from_content_type(&content_type);
+    if declared.is_none() && !UNTYPED_CONTENT_TYPES.contains(&content_type.as_str()) {
+        return Ok(Err(unsupported_content_type(&content_type)));
+    }
+    let base = response.url().clone();
+    let (body, body_truncated) = read_body(response, WEB_BROWSE_MAX_BYTES).await?;
+    let kind = match declared {
+        _ if body.starts_with(PDF_MAGIC) => BodyKind::Pdf,
+        Some(kind) => kind,
+        None => return Ok(Err(unsupported_content_type(&content_type))),
+    };
+    if body_truncated && kind == BodyKind::Pdf {
+        return Ok(Err(json!({
+            "error": format!("PDF is larger than {WEB_BROWSE_MAX_BYTES} bytes"),
+            "content_type": content_type,
+        })));
+    }
+    let raw = args["raw"].as_bool().unwrap_or(false);
+    if raw && kind == BodyKind::Pdf {
+        return Ok(Err(json!({
+            "error": "A PDF has no raw text; fetch it without `raw` to extract its text",
+            "content_type": content_type,
+        })));
+    }
+    let encoding = body_encoding(&body, charset.as_deref(), kind);
+    let converted = tokio::task::spawn_blocking(move || {
+        let links = match kind {
+            BodyKind::Html => page_links(&encoding.decode(&body).0, &base),
+            _ => vec![],
+        };
+        if raw {
+            return Ok((None, encoding.decode(&body).0.into_owned(), links));
+        }
+        kind.convert(&body, encoding, &scope)
+            .map(|(title, content)| (title, content, links))
+    })
+    .await?;
+    match converted {
+        Ok((title, content, links)) => Ok(Ok(Page {
+            charset: (kind != BodyKind::Pdf).then(|| encoding.name()),
+            final_url,
+            status: status.as_u16(),
+            title,
+            kind,
+            content_type,
+            content,
+            body_truncated,
+            links,
+        })),
+        Err(err) => match err.downcast_ref::<NoMatch>() {
+            Some(NoMatch { elements }) => Ok(Err(json!({
+                "error": err.to_string(),
+                "selector": args["selector"],
+                "elements": elements,
+            }))),
+            None => Ok(Err(json!({
+                "error": format!("Failed to read {content_type}: {err}"),
+                "content_type": content_type,
+            }))),
+        },
+    }
+}
+
+pub(super) struct HttpRequest;
+
+#[async_trait]
+impl BuiltinTool for HttpRequest {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "http_request".to_string(),
+            description: "Send an HTTP request, e.g. to a JSON API. Returns `{status, headers, body}`; a JSON body is returned parsed.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "method": {
+                        "type": "string",
+                        "description": "The HTTP method, such as GET, POST, PUT, PATCH or DELETE (defaults to GET)"
+                    },
+                    "url": {
+                        "type": "string",
+                        "description": "The http or https URL"
+                    },
+                    "headers": {
+                        "type": "object",
+                        "description": "Request headers, as an object of names to string values",
+                        "additionalProperties": { "type": "string" }
+                    },
+                    "body": {
+                        "type": "string",
+                        "description": "The request body"
+                    },
+                    "timeout_seconds": {
+                        "type": "integer",
+                        "description": "Give up after this many seconds (defaults to 30)"
+                    }
+                },
+                "required": ["url"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    fn blocking(&self) -> bool {
+        false
+    }
+
+    async fn run(&self, args: &Value, ctx: &Context<'_>) -> Result<Value> {
+        let config = &ctx.config.http_request;
+        let request = match build_request(args) {
+            Ok(request) => request,
+            Err(err) => return Ok(json!({ "error": err })),
+        };
+        let timeout = args["timeout_seconds"].as_u64().unwrap_or(config.timeout);
+        let response = match request.timeout(Duration::from_secs(timeout)).send().await {
+            Ok(response) => response,
+            Err(err) if err.is_timeout() => {
+                return Ok(json!({
+                    "error": format!("No response within {timeout} seconds"),
+                    "timed_out": true,
+                }))
+            }
+            Err(err) => return Ok(json!({ "error": format!("Request failed: {err}") })),
+        };
+        let status = response.status().as_u16();
+        let mut headers = serde_json::Map::new();
+        for (name, value) in response.headers() {
+            let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+            match headers.get_mut(name.as_str()) {
+                Some(Value::String(existing)) => *existing = format!("{existing}, {value}"),
+                _ => {
+                    headers.insert(name.to_string(), value.into());
+                }
+            }
+        }
+        let header = headers
+            .get("content-type")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let is_json = matches!(
+            BodyKind::from_content_type(header.split(';').next().unwrap_or_default().trim()),
+            Some(BodyKind::Json)
+        );
+        let encoding = body_encoding(&[], charset_param(header), BodyKind::Text);
+        let (bytes, truncated) = read_body(response, config.max_body).await?;
+        let text = encoding.decode(&bytes).0;
+        let body = match serde_json::from_str::<Value>(&text) {
+            Ok(value) if is_json && !truncated => value,
+            _ => text.into(),
+        };
+        Ok(json!({
+            "status": status,
+            "headers": headers,
+            "body": body,
+            "truncated": truncated,
+        }))
+    }
+}
+
+/// Builds the request from the call's arguments, or explains what is wrong with them.
+fn build_request(args: &Value) -> Result<reqwest::RequestBuilder, String> {
+    let url = args["url"].as_str().ok_or("Missing url")?;
+    let url = Url::parse(url).map_err(|err| format!("Invalid url `{url}`: {err}"))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!(
+            "Unsupported scheme `{}`; use http or https",
+            url.scheme()
+        ));
+    }
+    let method = args["method"]
+        .as_str()
+        .unwrap_or("GET")
+        .to_ascii_uppercase();
+    let method = reqwest::Method::from_bytes(method.as_bytes())
+        .map_err(|_| format!("Invalid method `{method}`"))?;
+    let mut request = WEB_CLIENT
+        .request(method, url)
+        .headers(request_headers(&args["headers"])?);
+    if let Some(body) = args["body"].as_str() {
+        request = request.body(body.to_string());
+    }
+    Ok(request)
+}
+
+pub(super) struct DownloadFile;
+
+#[async_trait]
+impl BuiltinTool for DownloadFile {
+    fn declaration(&self) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: "download_file".to_string(),
+            description: "Download a URL to a file as is, e.g. an archive, image or dataset, creating missing parent directories. Downloading to an existing file fails with `{error: \"file exists\", size}` unless `overwrite` is set. Returns `{path, bytes, content_type}`.".to_string(),
+            parameters: serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "url": {
+                        "type": "string",
+                        "description": "The http or https URL to download"
+                    },
+                    "path": {
+                        "type": "string",
+                        "description": "The file to save the download to"
+                    },
+                    "overwrite": {
+                        "type": "boolean",
+                        "description": "Replace the file if it already exists (defaults to false)"
+                    }
+                },
+                "required": ["url", "path"]
+            }))
+            .unwrap(),
+            agent: false,
+        }
+    }
+
+    async fn run(&self, args: &Value, ctx: &Context<'_>) -> Result<Value> {
+        let url = args["url"].as_str().ok_or_else(|| anyhow!("Missing url"))?;
+        let path = args["path"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing path"))?;
+        let config = &ctx.config.download_file;
+        match Url::parse(url) {
+            Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+            Ok(parsed) => {
+                return Ok(json!({
+                    "error": format!("Unsupported scheme `{}`; use http or https", parsed.scheme()),
+                }))
+            }
+            Err(err) => return Ok(json!({ "error": format!("Invalid url `{url}`: {err}") })),
+        }
+        if !args["overwrite"].as_bool().unwrap_or(false) {
+            if let Ok(metadata) = fs::metadata(path) {
+                return Ok(json!({ "error": "file exists", "size": metadata.len() }));
+            }
+        }
+        let timeout = Duration::from_secs(config.timeout);
+        let response = match WEB_CLIENT.get(url).timeout(timeout).send().await {
+            Ok(response) => response,
+            Err(err) => return Ok(json!({ "error": format!("Failed to fetch {url}: {err}") })),
+        };
+        let status = response.status();
+        if !status.is_success() {
+            return Ok(json!({
+                "error": format!("{} answered {status}", response.url()),
+                "status": status.as_u16(),
+            }));
+        }
+        if response
+            .content_length()
+            .is_some_and(|len| len > config.max_bytes)
+        {
+            return Ok(download_too_large(config.max_bytes));
+        }
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+
+        let target = Path::new(path);
+        let created_dirs = match target.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() && !parent.exists() => {
+                fs::create_dir_all(parent)?;
+                true
+            }
+            _ => false,
+        };
+        let file_name = target
+            .file_name()
+            .ok_or_else(|| anyhow!("Invalid path: {}", target.display()))?;
+        // Stream into a temporary file beside the target so a failed download leaves
+        // nothing behind.
+        let temp_path = target.with_file_name(format!(
+            ".{}.{}.tmp",
+            file_name.to_string_lossy(),
+            uuid::Uuid::new_v4()
+        ));
+        let saved = match save_body(response, &temp_path, config.max_bytes).await {
+            Ok(Ok(bytes)) => fs::rename(&temp_path, target)
+                .map(|_| Ok(bytes))
+                .map_err(Into::into),
+            saved => saved,
+        };
+        if !matches!(saved, Ok(Ok(_))) {
+            let _ = fs::remove_file(&temp_path);
+        }
+        let bytes = match saved? {
+            Ok(bytes) => bytes,
+            Err(err) => return Ok(err),
+        };
+        Ok(json!({
+            "path": to_absolute_path(path)?,
+            "bytes": bytes,
+            "content_type": content_type,
+            "created_dirs": created_dirs,
+        }))
+    }
+}
+
+/// Streams the body of `response` into a new file at `path`, returning its size, or the
+/// error to hand back to the model when it fails or grows past `max_bytes`.
+async fn save_body(
+    mut response: Response,
+    path: &Path,
+    max_bytes: u64,
+) -> Result<Result<u64, Value>> {
+    let mut file = File::create(path)?;
+    let mut bytes = 0;
+    loop {
+        let chunk = match response.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(err) => {
+                return Ok(Err(
+                    json!({ "error": format!("The download failed: {err}") }),
+                ))
+            }
+        };
+        bytes += chunk.len() as u64;
+        if bytes > max_bytes {
+            return Ok(Err(download_too_large(max_bytes)));
+        }
+        file.write_all(&chunk)?;
+    }
+    file.sync_all()?;
+    Ok(Ok(bytes))
+}
+
+fn download_too_large(max_bytes: u64) -> Value {
+    json!({
+        "error": format!("The download is larger than {max_bytes} bytes"),
+        "max_bytes": max_bytes,
+    })
+}
+
+/// Parses the `headers` argument of `web_browse` and `http_request`. Values may not contain
+/// line breaks, and hop-by-hop headers, which the client manages, are refused.
+fn request_headers(headers: &Value) -> Result<HeaderMap, String> {
+    let mut map = HeaderMap::new();
+    let entries = match headers {
+        Value::Null => return Ok(map),
+        Value::Object(entries) => entries,
+        _ => return Err("`headers` must be an object of names to string values".into()),
+    };
+    for (name, value) in entries {
+        let value = value
+            .as_str()
+            .ok_or_else(|| format!("Header `{name}` must be a string"))?;
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("Invalid header name `{name}`"))?;
+        if HOP_BY_HOP_HEADERS.contains(&name.as_str()) {
+            return Err(format!("Header `{name}` cannot be set"));
+        }
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| format!("Invalid value for header `{name}`"))?;
+        map.append(name, value);
+    }
+    Ok(map)
+}
+
+/// Which part of an HTML page `web_browse` converts.
+enum HtmlScope {
+    Article,
+    FullPage,
+    Selector(Selector),
+}
+
+/// Returned when a `selector` matches no element of the page.
+#[derive(Debug)]
+struct NoMatch {
+    elements: usize,
+}
+
+impl std::fmt::Display for NoMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "selector matched nothing")
+    }
+}
+
+impl std::error::Error for NoMatch {}
+
+/// The `charset` parameter of a Content-Type header.
+fn charset_param(header: &str) -> Option<&str> {
+    header.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches('"'))
+    })
+}
+
+/// Picks the encoding of a text body: a BOM wins, then the header's charset, then for HTML a
+/// `<meta>` charset near the top of the page, and UTF-8 otherwise.
+fn body_encoding(body: &[u8], charset: Option<&str>, kind: BodyKind) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(body) {
+        return encoding;
+    }
+    charset
+        .and_then(|v| Encoding::for_label(v.as_bytes()))
+        .or_else(|| match kind {
+            BodyKind::Html => META_CHARSET
+                .captures(&body[..body.len().min(WEB_META_CHARSET_SCAN)])
+                .and_then(|v| Encoding::for_label(&v[1])),
+            _ => None,
+        })
+        .unwrap_or(UTF_8)
+}
+
+fn unsupported_content_type(content_type: &str) -> Value {
+    json!({
+        "error": format!(
+            "Unsupported content type {content_type}; web_browse reads HTML, JSON, text and PDF"
+        ),
+        "content_type": content_type,
+    })
+}
+
+/// How `web_browse` turns a response body into text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BodyKind {
+    Html,
+    Json,
+    Pdf,
+    Text,
+}
+
+impl BodyKind {
+    fn from_content_type(content_type: &str) -> Option<Self> {
+        match content_type {
+            "text/html" | "application/xhtml+xml" => Some(BodyKind::Html),
+            "application/json" => Some(BodyKind::Json),
+            "application/pdf" | "application/x-pdf" => Some(BodyKind::Pdf),
+            v if v.ends_with("+json") => Some(BodyKind::Json),
+            v if v.starts_with("text/") || v.ends_with("+xml") || v == "application/xml" => {
+                Some(BodyKind::Text)
+            }
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            BodyKind::Html => "html",
+            BodyKind::Json => "json",
+            BodyKind::Pdf => "pdf",
+            BodyKind::Text => "text",
+        }
+    }
+
+    /// Returns the page title, for HTML, and the text to hand to the model.
+    fn convert(
+        self,
+        body: &[u8],
+        encoding: &'static Encoding,
+        scope: &HtmlScope,
+    ) -> Result<(Option<String>, String)> {
+        let text = || encoding.decode(body).0;
+        let content = match self {
+            BodyKind::Html => {
+                let html = text();
+                let (title, html) = match scope {
+                    HtmlScope::Article => extract_article(&html),
+                    HtmlScope::FullPage => {
+                        (page_title(&Html::parse_document(&html)), html.into_owned())
+                    }
+                    HtmlScope::Selector(selector) => select_fragments(&html, selector)?,
+                };
+                return Ok((title, html_to_md(&html)));
+            }
+            BodyKind::Json => {
+                let text = text();
+                match serde_json::from_str::<Value>(&text) {
+                    Ok(value) => serde_json::to_string_pretty(&value)?,
+                    Err(_) => text.into_owned(),
+                }
+            }
+            BodyKind::Pdf => pdf_extract::extract_text_from_mem(body)?,
+            BodyKind::Text => text().into_owned(),
+        };
+        Ok((None, content))
+    }
+}
+
+/// Collects the http and https links of a page, resolved against its `<base>` or `url`, in
+/// document order without duplicates or fragments, up to [`WEB_BROWSE_MAX_LINKS`].
+fn page_links(html: &str, url: &Url) -> Vec<Link> {
+    let document = Html::parse_document(html);
+    let base = document
+        .select(&BASE_SELECTOR)
+        .next()
+        .and_then(|v| url.join(v.value().attr("href")?).ok())
+        .unwrap_or_else(|| url.clone());
+    let mut seen = HashSet::new();
+    let mut links = vec![];
+    for anchor in document.select(&LINK_SELECTOR) {
+        let Some(mut target) = anchor
+            .value()
+            .attr("href")
+            .and_then(|href| base.join(href.trim()).ok())
+        else {
+            continue;
+        };
+        if !matches!(target.scheme(), "http" | "https") {
+            continue;
+        }
+        target.set_fragment(None);
+        if !seen.insert(target.to_string()) {
+            continue;
+        }
+        links.push(Link {
+            url: target.into(),
+            text: normalize_whitespace(&anchor.text().collect::<String>()),
+        });
+        if links.len() >= WEB_BROWSE_MAX_LINKS {
+            break;
+        }
+    }
+    links
+}
+
+/// Returns the page title and the HTML of the elements matching `selector`, in document
+/// order. A match inside another match is skipped, as its parent already includes it.
+fn select_fragments(html: &str, selector: &Selector) -> Result<(Option<String>, String)> {
+    let document = Html::parse_document(html);
+    let mut matched = HashSet::new();
+    let mut fragments = vec![];
+    for element in document.select(selector) {
+        if !element.ancestors().any(|v| matched.contains(&v.id())) {
+            fragments.push(element.html());
+        }
+        matched.insert(element.id());
+    }
+    if fragments.is_empty() {
+        let elements = document.tree.values().filter(|v| v.is_element()).count();
+        return Err(NoMatch { elements }.into());
+    }
+    Ok((page_title(&document), fragments.join("\n")))
+}
+
+fn page_title(document: &Html) -> Option<String> {
+    document
+        .select(&TITLE_SELECTOR)
+        .next()
+        .map(|v| normalize_whitespace(&v.text().collect::<String>()))
+        .filter(|v| !v.is_empty())
+}
+
+/// Returns the page title and the HTML of its main content, found readability style: page
+/// chrome is dropped, then an `article` or `main` element wins when it has enough text, and
+/// otherwise the element whose paragraphs carry the most text, discounted by link density.
+/// Falls back to the whole page without its chrome.
+fn extract_article(html: &str) -> (Option<String>, String) {
+    let mut document = Html::parse_document(html);
+    let title = page_title(&document);
+    let noise: Vec<_> = document.select(&NOISE_SELECTOR).map(|v| v.id()).collect();
+    for id in noise {
+        if let Some(mut node) = document.tree.get_mut(id) {
+            node.detach();
+        }
+    }
+    let content = main_content(&document).map(|v| v.html());
+    (title, content.unwrap_or_else(|| document.html()))
+}
+
+fn main_content(document: &Html) -> Option<ElementRef<'_>> {
+    let text_len =
+        |element: ElementRef| -> usize { element.text().map(|v| v.trim().chars().count()).sum() };
+    let main = document
+        .select(&MAIN_SELECTOR)
+        .max_by_key(|element| text_len(*element));
+    if let Some(main) = main.filter(|v| text_len(*v) >= WEB_BROWSE_MIN_ARTICLE) {
+        return Some(main);
+    }
+    let mut scores = HashMap::new();
+    for paragraph in document.select(&PARAGRAPH_SELECTOR) {
+        let len = text_len(paragraph);
+        if len < WEB_BROWSE_MIN_PARAGRAPH {
+            continue;
+        }
+        let commas = paragraph
+            .text()
+            .map(|v| v.matches(',').count())
+            .sum::<usize>();
+        let score = 1.0 + commas as f64 + (len as f64 / 100.0).min(3.0);
+        let mut ancestors = paragraph.ancestors().filter_map(ElementRef::wrap);
+        if let Some(parent) = ancestors.next() {
+            *scores.entry(parent.id()).or_insert(0.0) += score;
+        }
+        if let Some(grandparent) = ancestors.next() {
+            *scores.entry(grandparent.id()).or_insert(0.0) += score / 2.0;
+        }
+    }
+    scores
+        .into_iter()
+        .filter_map(|(id, score)| {
+            let element = ElementRef::wrap(document.tree.get(id)?)?;
+            let total = text_len(element).max(1);
+            let links: usize = element.select(&LINK_SELECTOR).map(text_len).sum();
+            Some((element, score * (1.0 - links as f64 / total as f64)))
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(element, _)| element)
+}
+
+/// Returns the page of `content` that starts `offset` characters in and holds at most
+/// `max_chars` characters, cut by [`truncate_markdown`], with a marker saying where the next
+/// page starts. The second value is the next page's offset when there is one.
+fn page_content(content: &str, offset: usize, max_chars: usize) -> (String, Option<usize>) {
+    let rest = &content[char_to_byte(content, offset)..];
+    let mut page = rest.to_string();
+    let Some(kept) = truncate_markdown(&mut page, char_to_byte(rest, max_chars)) else {
+        return (page, None);
+    };
+    let next = offset + rest[..kept].chars().count();
+    let total = offset + rest.chars().count();
+    page.push_str(&format!(
+        "\n...[truncated at character {next} of {total}; pass offset {next} for more]"
+    ));
+    (page, Some(next))
+}
+
+fn char_to_byte(text: &str, chars: usize) -> usize {
+    text.char_indices()
+        .nth(chars)
+        .map_or(text.len(), |(index, _)| index)
+}
+
+/// Cuts `content` to at most `max_length` bytes, returning how many bytes of it were kept
+/// when it was cut. The cut falls on a line break when there is one in the second half,
+/// and before a fenced code block it would otherwise split; when the block starts too early
+/// for that, it is closed after the cut instead.
+fn truncate_markdown(content: &mut String, max_length: usize) -> Option<usize> {
+    if content.len() <= max_length {
+        return None;
+    }
+    truncate_at_char_boundary(content, max_length);
+    if let Some(index) = content.rfind('\n').filter(|v| *v >= max_length / 2) {
+        content.truncate(index + 1);
+    }
+    let mut open_fence = None;
+    let mut offset = 0;
+    for line in content.split_inclusive('\n') {
+        if line.trim_start().starts_with("```") {
+            open_fence = match open_fence {
+                Some(_) => None,
+                None => Some(offset),
+            };
+        }
+        offset += line.len();
+    }
+    if let Some(start) = open_fence.filter(|v| *v >= max_length / 2) {
+        content.truncate(start);
+    }
+    let kept = content.len();
+    if open_fence.is_some_and(|v| v < max_length / 2) {
+        if !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push_str("```\n");
+    }
+    Some(kept)
+}
+
+/// Reads at most `max_bytes` of the body, reporting whether there was more.
+async fn read_body(mut response: Response, max_bytes: usize) -> Result<(Vec<u8>, bool)> {
+    let mut body = vec![];
+    while let Some(chunk) = response.chunk().await? {
+        let room = max_bytes - body.len();
+        if chunk.len() > room {
+            body.extend_from_slice(&chunk[..room]);
+            return Ok((body, true));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok((body, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine(config: Value, provider: SearchProvider) -> Result<SearchProvider, String> {
+        let config: WebSearchConfig = serde_json::from_value(config).unwrap();
+        search_engine(&config, provider).map(|engine| engine.provider())
+    }
+
+    #[test]
+    fn test_search_engine() {
+        let config: WebSearchConfig = serde_json::from_value(json!({})).unwrap();
+        assert_eq!(config.provider, SearchProvider::DuckDuckGo);
+        let config: WebSearchConfig =
+            serde_json::from_value(json!({ "provider": "searxng" })).unwrap();
+        assert_eq!(config.provider, SearchProvider::SearXNG);
+        assert!(serde_json::from_value::<WebSearchConfig>(json!({ "provider": "bing" })).is_err());
+
+        assert_eq!(
+            engine(json!({}), SearchProvider::DuckDuckGo),
+            Ok(SearchProvider::DuckDuckGo)
+        );
+        assert_eq!(
+            engine(
+                json!({ "endpoint": "http://localhost:8080/" }),
+                SearchProvider::SearXNG
+            ),
+            Ok(SearchProvider::SearXNG)
+        );
+        assert!(engine(json!({}), SearchProvider::SearXNG)
+            .unwrap_err()
+            .contains("endpoint"));
+        assert_eq!(
+            engine(
+                json!({ "provider": "brave", "api_key": "k" }),
+                SearchProvider::Brave
+            ),
+            Ok(SearchProvider::Brave)
+        );
+        if env::var("TAVILY_API_KEY").is_err() {
+            assert_eq!(
+                engine(
+                    json!({ "provider": "brave", "api_key": "k" }),
+                    SearchProvider::Tavily
+                ),
+                Err("web_search engine `tavily` needs TAVILY_API_KEY".to_string())
+            );
+        }
+        if env::var("TAVILY_API_KEY").is_err() {
+            let config = json!({ "provider": "tavily", "api_key": "" });
+            assert!(engine(config, SearchProvider::Tavily)
+                .unwrap_err()
+                .contains("api_key"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_web_search_engine_override() {
+        let config = BuiltinConfig::default();
+        let hooks = Hooks::default();
+        let ctx = Context {
+            config: &config,
+            hooks: &hooks,
+        };
+        let value = WebSearch
+            .run(&json!({ "query": "rust", "engine": "bing" }), &ctx)
+            .await
+            .unwrap();
+        assert_eq!(value["error"], "Unknown search engine \"bing\"");
+        let value = WebSearch
+            .run(&json!({ "query": "rust", "engine": "searxng" }), &ctx)
+            .await
+            .unwrap();
+        assert!(value["error"].as_str().unwrap().contains("endpoint"));
+    }
+
+    #[test]
+    fn test_parse_duckduckgo() {
+        let html = include_str!("fixtures/duckduckgo_lite.html");
+        let results = parse_duckduckgo(html);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].title, "Rust Programming Language");
+        assert_eq!(results[0].url, "https://www.rust-lang.org/");
+        assert_eq!(
+            results[0].snippet,
+            "A language empowering everyone to build reliable and efficient software."
+        );
+        assert_eq!(
+            results[0].raw_url.as_deref(),
+            Some("//duckduckgo.com/l/?uddg=https%3A%2F%2Fwww.rust%2Dlang.org%2F&rut=4f1a")
+        );
+        assert_eq!(
+            results[1].snippet,
+            "by Steve Klabnik, Carol Nichols, and Chris Krycho. This version of the text assumes you're using Rust 1.85.0."
+        );
+        assert_eq!(results[2].title, "Rust (programming language) - Wikipedia");
+        assert_eq!(
+            results[2].url,
+            "https://en.wikipedia.org/wiki/Rust_(programming_language)"
+        );
+        assert_eq!(results[2].raw_url, None);
+    }
+
+    #[test]
+    fn test_parse_duckduckgo_without_snippet() {
+        let html = r#"<table>
+            <tr><td><a class='result-link' href="https://a.example/">A</a></td></tr>
+            <tr><td><span class='link-text'>a.example</span></td></tr>
+            <tr><td><a class='result-link' href="https://b.example/">B</a></td></tr>
+            <tr><td class='result-snippet'>About B</td></tr>
+            <tr><td><a class='result-link' href="https://c.example/">C</a></td></tr>
+        </table>"#;
+        let results = parse_duckduckgo(html);
+        let snippets: Vec<_> = results.iter().map(|v| v.snippet.as_str()).collect();
+        assert_eq!(snippets, ["", "About B", ""]);
+    }
+
+    #[test]
+    fn test_duckduckgo_challenge() {
+        let html = include_str!("fixtures/duckduckgo_challenge.html");
+        assert!(parse_duckduckgo(html).is_empty());
+        assert!(is_duckduckgo_challenge(html));
+        assert!(!is_duckduckgo_challenge(include_str!(
+            "fixtures/duckduckgo_lite.html"
+        )));
+
+        for attempt in 0..3 {
+            let delay = backoff_delay(attempt).as_millis() as u64;
+            let base = WEB_SEARCH_BACKOFF_MS << attempt;
+            assert!((base..=base + base / 2).contains(&delay));
+        }
+    }
+
+    #[test]
+    fn test_duckduckgo_target() {
+        assert_eq!(
+            duckduckgo_target(
+                "//duckduckgo.com/l/?uddg=https%3A%2F%2Fexample.com%2Fa%3Fb%3D1%26c%3D2&rut=ab12"
+            ),
+            "https://example.com/a?b=1&c=2"
+        );
+        assert_eq!(
+            duckduckgo_target("//duckduckgo.com/l/?uddg=https%3A%2F%2Fdocs.rs%2Ftokio%2Flatest%2Ftokio%2Ftask%2Ffn.spawn_blocking.html%23examples&rut=5d8e1c0f3a9b2e7d6c4a1f0e9b8d7c6a5f4e3d2c1b0a9f8e7d6c5b4a3f2e1d0c"),
+            "https://docs.rs/tokio/latest/tokio/task/fn.spawn_blocking.html#examples"
+        );
+        assert_eq!(
+            duckduckgo_target("https://duckduckgo.com/l/?uddg=https%3A%2F%2Fexample.com%2F"),
+            "https://example.com/"
+        );
+        assert_eq!(
+            duckduckgo_target("https://example.com/page"),
+            "https://example.com/page"
+        );
+        assert_eq!(
+            duckduckgo_target("//example.com/page"),
+            "https://example.com/page"
+        );
+        assert_eq!(
+            duckduckgo_target("//duckduckgo.com/l/?rut=ab12"),
+            "https://duckduckgo.com/l/?rut=ab12"
+        );
+        assert_eq!(
+            duckduckgo_target("//duckduckgo.com/l/?uddg=not%20a%20url"),
+            "https://duckduckgo.com/l/?uddg=not%20a%20url"
+        );
+        assert_eq!(duckduckgo_target("http://[::1"), "http://[::1");
+    }
+
+    #[test]
+    fn test_body_kind() {
+        let kind = |content_type| BodyKind::from_content_type(content_type);
+        assert_eq!(kind("text/html"), Some(BodyKind::Html));
+        assert_eq!(kind("application/json"), Some(BodyKind::Json));
+        assert_eq!(kind("application/ld+json"), Some(BodyKind::Json));
+        assert_eq!(kind("application/pdf"), Some(BodyKind::Pdf));
+        assert_eq!(kind("text/plain"), Some(BodyKind::Text));
+        assert_eq!(kind("application/rss+xml"), Some(BodyKind::Text));
+        assert_eq!(kind("application/x-pdf"), Some(BodyKind::Pdf));
+        assert_eq!(kind("image/png"), None);
+        assert!(include_bytes!("fixtures/hello.pdf").starts_with(PDF_MAGIC));
+        assert_eq!(
+            unsupported_content_type("image/png")["error"],
+            "Unsupported content type image/png; web_browse reads HTML, JSON, text and PDF"
+        );
+
+        let html = BodyKind::Html
+            .convert(
+                include_bytes!("fixtures/page.html"),
+                UTF_8,
+                &HtmlScope::Article,
+            )
+            .unwrap()
+            .1;
+        assert!(html.contains("# Example Domain"));
+        assert!(html.contains("This domain is for use in documentation examples."));
+        assert!(!html.contains("<p>"));
+
+        let json = BodyKind::Json
+            .convert(
+                include_bytes!("fixtures/data.json"),
+                UTF_8,
+                &HtmlScope::Article,
+            )
+            .unwrap()
+            .1;
+        assert_eq!(
+            json,
+            "{\n  \"name\": \"aichat\",\n  \"tags\": [\n    \"cli\",\n    \"llm\"\n  ],\n  \"stars\": 1\n}"
+        );
+        assert_eq!(
+            BodyKind::Json
+                .convert(b"not json", UTF_8, &HtmlScope::Article)
+                .unwrap()
+                .1,
+            "not json"
+        );
+
+        let pdf = BodyKind::Pdf
+            .convert(
+                include_bytes!("fixtures/hello.pdf"),
+                UTF_8,
+                &HtmlScope::Article,
+            )
+            .unwrap()
+            .1;
+        assert_eq!(pdf.trim(), "Hello from a PDF");
+        assert!(BodyKind::Pdf
+            .convert(b"%PDF-1.4 broken", UTF_8, &HtmlScope::Article)
+            .is_err());
+
+        assert_eq!(
+            BodyKind::Text
+                .convert(b"plain", UTF_8, &HtmlScope::Article)
+                .unwrap(),
+            (None, "plain".to_string())
+        );
+    }
+
+    #[test]
+    fn test_extract_article() {
+        let html = include_str!("fixtures/article.html");
+        let (title, content) = extract_article(html);
+        assert_eq!(
+            title.as_deref(),
+            Some("Async closures are stable | The Example Blog")
+        );
+        let markdown = html_to_md(&content);
+        assert!(markdown.contains("# Async closures are stable"));
+        assert!(markdown.contains("return futures that may borrow from those captures"));
+        assert!(markdown.contains("let fetch = async |url|"));
+        for junk in [
+            "cookies",
+            "Popular posts",
+            "RSS",
+            "Related:",
+            "Copyright",
+            "analytics",
+        ] {
+            assert!(!markdown.contains(junk), "{junk} in {markdown}");
+        }
+
+        let (_, full) = BodyKind::Html
+            .convert(html.as_bytes(), UTF_8, &HtmlScope::FullPage)
+            .unwrap();
+        assert!(full.contains("Popular posts"));
+        assert!(full.contains("Copyright"));
+
+        let html = format!(
+            "<body><div>Menu</div><main><p>{}</p></main><div><p>{}</p></div></body>",
+            "Main text, ".repeat(30),
+            "Other text, ".repeat(5)
+        );
+        let (title, content) = extract_article(&html);
+        assert_eq!(title, None);
+        assert!(content.starts_with("<main>"));
+    }
+
+    #[test]
+    fn test_select_fragments() {
+        let html = include_str!("fixtures/docs.html");
+        let select = |selector: &str| select_fragments(html, &Selector::parse(selector).unwrap());
+
+        let (title, fragment) = select("#readme").unwrap();
+        assert_eq!(title.as_deref(), Some("aichat - GitHub"));
+        let markdown = html_to_md(&fragment);
+        assert!(markdown.contains("## Install"));
+        assert!(markdown.contains("Requires Rust 1.80 or later."));
+        assert!(!markdown.contains("OPENAI_API_KEY"));
+
+        let (_, fragment) = select("table.providers").unwrap();
+        let markdown = html_to_md(&fragment);
+        let openai = markdown.find("OPENAI_API_KEY").unwrap();
+        let claude = markdown.find("CLAUDE_API_KEY").unwrap();
+        assert!(openai < claude);
+        assert!(!markdown.contains("Install"));
+
+        let (_, fragment) = select("#readme, #readme p").unwrap();
+        assert_eq!(fragment.matches("Requires Rust").count(), 1);
+
+        let err = select("article").unwrap_err();
+        let no_match = err.downcast_ref::<NoMatch>().unwrap();
+        assert_eq!(no_match.elements, 28);
+        assert!(Selector::parse("div[").is_err());
+    }
+
+    #[test]
+    fn test_page_links() {
+        let html = r##"<html><head><base href="/docs/"></head><body>
+            <a href="intro.html">Intro</a>
+            <a href="intro.html#install">Intro
+                again</a>
+            <a href="https://example.org/x?y=1">Elsewhere</a>
+            <a href="//cdn.example.com/file.txt">CDN</a>
+            <a href="mailto:me@example.com">Mail</a>
+            <a href="javascript:void(0)">Menu</a>
+            <a href="#top">Top</a>
+            <a>No href</a>
+        </body></html>"##;
+        let url = Url::parse("https://example.com/docs/guide/page.html").unwrap();
+        let links = page_links(html, &url);
+        let link = |url: &str, text: &str| Link {
+            url: url.to_string(),
+            text: text.to_string(),
+        };
+        assert_eq!(
+            links,
+            vec![
+                link("https://example.com/docs/intro.html", "Intro"),
+                link("https://example.org/x?y=1", "Elsewhere"),
+                link("https://cdn.example.com/file.txt", "CDN"),
+                link("https://example.com/docs/", "Top"),
+            ]
+        );
+
+        let html = (0..150)
+            .map(|i| format!("<a href='/p{i}'>{i}</a>"))
+            .collect::<String>();
+        let links = page_links(&html, &url);
+        assert_eq!(links.len(), WEB_BROWSE_MAX_LINKS);
+        assert_eq!(links[0].url, "https://example.com/p0");
+    }
+
+    /// Serves `routes`, pairs of a path and a raw HTTP response head and body, on a local
+    /// port until the test ends; other paths get a 404 and the query string is ignored.
+    /// `{request}` and `{body}` in a response are replaced with the request line and headers,
+    /// and with the request body. Returns the base URL.
+    fn serve(routes: Vec<(&'static str, String)>) -> String {
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let mut request_line = String::new();
+                let mut reader = BufReader::new(&stream);
+                if reader.read_line(&mut request_line).is_err() {
+                    continue;
+                }
+                let mut head = String::new();
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                    head.push_str(&line);
+                    line.clear();
+                }
+                let length = head
+                    .lines()
+                    .find_map(|line| {
+                        line.to_ascii_lowercase()
+                            .strip_prefix("content-length:")
+                            .map(|v| v.trim().parse().unwrap_or(0))
+                    })
+                    .unwrap_or(0);
+                let mut body = vec![0; length];
+                if reader.read_exact(&mut body).is_err() {
+                    continue;
+                }
+                let target = request_line.split(' ').nth(1).unwrap_or_default();
+                let path = target.split('?').next().unwrap_or_default();
+                let response = routes
+                    .iter()
+                    .find(|(route, _)| *route == path)
+                    .map(|(_, response)| {
+                        response
+                            .replace("{request}", &format!("{request_line}{head}"))
+                            .replace("{body}", &String::from_utf8_lossy(&body))
+                    })
+                    .unwrap_or("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".into());
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        base
+    }
+
+    fn redirect(location: &str) -> String {
+        format!(
+            "HTTP/1.1 302 Found\r\nLocation: {location}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        )
+    }
+
+    #[tokio::test]
+    async fn test_http_request() {
+        let base = serve(vec![
+            (
+                "/api",
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nX-Total: 2\r\nContent-Length: 17\r\nConnection: close\r\n\r\n{\"items\": [1, 2]}".to_string(),
+            ),
+            (
+                "/echo",
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\n{request}\r\n{body}".to_string(),
+            ),
+        ]);
+        let config = BuiltinConfig::default();
+        let hooks = Hooks::default();
+        let ctx = Context {
+            config: &config,
+            hooks: &hooks,
+        };
+        let value = HttpRequest
+            .run(
+                &json!({ "url": format!("{base}/api"), "headers": { "Accept": "application/json" } }),
+                &ctx,
+            )
+            .await
+            .unwrap();
+        assert_eq!(value["status"], 200);
+        assert_eq!(value["headers"]["x-total"], "2");
+        assert_eq!(value["body"], json!({ "items": [1, 2] }));
+        assert_eq!(value["truncated"], false);
+
+        let value = HttpRequest
+            .run(&json!({ "url": "file:///etc/passwd" }), &ctx)
+            .await
+            .unwrap();
+        assert!(value["error"]
+            .as_str()
+            .unwrap()
+            .contains("Unsupported scheme"));
+        let value = HttpRequest
+            .run(
+                &json!({
+                    "method": "post",
+                    "url": format!("{base}/echo"),
+                    "headers": { "Content-Type": "application/json" },
+                    "body": "{\"name\": \"x\"}",
+                }),
+                &ctx,
+            )
+            .await
+            .unwrap();
+        let echoed = value["body"].as_str().unwrap();
+        assert!(echoed.starts_with("POST /echo HTTP/1.1\r\n"), "{echoed}");
+        assert!(echoed
+            .to_ascii_lowercase()
+            .contains("content-type: application/json\r\n"));
+        assert!(echoed.ends_with("\r\n{\"name\": \"x\"}"), "{echoed}");
+
+        let value = HttpRequest
+            .run(&json!({ "url": format!("{base}/missing") }), &ctx)
+            .await
+            .unwrap();
+        assert_eq!(value["status"], 404);
+        assert!(value.get("error").is_none());
+
+        // Accepted by the kernel but never answered.
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", silent.local_addr().unwrap());
+        let value = HttpRequest
+            .run(&json!({ "url": url, "timeout_seconds": 1 }), &ctx)
+            .await
+            .unwrap();
+        assert_eq!(value["timed_out"], true);
+
+        for args in [
+            json!({ "url": base, "method": "GE T" }),
+            json!({ "url": base, "headers": { "X-Evil": "a\r\nHost: other" } }),
+            json!({ "url": base, "headers": { "Bad Name": "x" } }),
+            json!({ "url": base, "headers": { "X-Count": 1 } }),
+        ] {
+            let value = HttpRequest.run(&args, &ctx).await.unwrap();
+            assert!(value["error"].is_string(), "{args}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_page_raw_with_headers() {
+        let html = "<html><head><script type=\"application/json\">{\"id\": 1}</script></head><body><p>x</p></body></html>";
+        let base = serve(vec![
+            (
+                "/page",
+                format!("HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{html}", html.len()),
+            ),
+            (
+                "/echo",
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\n{request}".to_string(),
+            ),
+        ]);
+
+        let args = json!({ "raw": true });
+        let page = fetch_page(&format!("{base}/page"), &args, HtmlScope::Article)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(page.content, html);
+        assert_eq!(page.kind, BodyKind::Html);
+
+        let args = json!({ "headers": { "Accept": "application/json", "Cookie": "session=abc" } });
+        let page = fetch_page(&format!("{base}/echo"), &args, HtmlScope::Article)
+            .await
+            .unwrap()
+            .unwrap();
+        let sent = page.content.to_ascii_lowercase();
+        assert!(sent.contains("accept: application/json\r\n"), "{sent}");
+        assert!(sent.contains("cookie: session=abc\r\n"), "{sent}");
+
+        for headers in [
+            json!({ "X-Evil": "a\r\nHost: other" }),
+            json!({ "Connection": "close" }),
+            json!({ "Transfer-Encoding": "chunked" }),
+            json!({ "Host": "other" }),
+            json!("Accept: text/html"),
+        ] {
+            let args = json!({ "headers": headers });
+            let err = fetch_page(&format!("{base}/echo"), &args, HtmlScope::Article)
+                .await
+                .unwrap()
+                .unwrap_err();
+            assert!(err["error"].is_string(), "{headers}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_file() {
+        let chunk = "x".repeat(1000);
+        let base = serve(vec![
+            (
+                "/file.bin",
+                "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello".to_string(),
+            ),
+            (
+                "/stream",
+                format!("HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n{}", chunk.repeat(5)),
+            ),
+        ]);
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = BuiltinConfig::default();
+        config.download_file.max_bytes = 2000;
+        let hooks = Hooks::default();
+        let ctx = Context {
+            config: &config,
+            hooks: &hooks,
+        };
+        let path = dir.path().join("sub/file.bin");
+        let args = json!({ "url": format!("{base}/file.bin"), "path": path.to_str().unwrap() });
+        let value = DownloadFile.run(&args, &ctx).await.unwrap();
+        assert_eq!(value["bytes"], 5);
+        assert_eq!(value["content_type"], "application/octet-stream");
+        assert_eq!(value["created_dirs"], true);
+        assert_eq!(fs::read(&path).unwrap(), b"hello");
+
+        let value = DownloadFile.run(&args, &ctx).await.unwrap();
+        assert_eq!(value, json!({ "error": "file exists", "size": 5 }));
+        let mut args = args;
+        args["overwrite"] = true.into();
+        assert_eq!(DownloadFile.run(&args, &ctx).await.unwrap()["bytes"], 5);
+
+        // Without a Content-Length the limit is only hit while streaming.
+        let path = dir.path().join("stream.bin");
+        let args = json!({ "url": format!("{base}/stream"), "path": path.to_str().unwrap() });
+        let value = DownloadFile.run(&args, &ctx).await.unwrap();
+        assert_eq!(value["max_bytes"], 2000);
+        assert!(!path.exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let args = json!({ "url": "ftp://example.com/a", "path": path.to_str().unwrap() });
+        let value = DownloadFile.run(&args, &ctx).await.unwrap();
+        assert!(value["error"]
+            .as_str()
+            .unwrap()
+            .contains("Unsupported scheme"));
+    }
+
+    /// `#[tokio::test]` runs on a current-thread runtime, where creating or blocking on
+    /// another runtime would panic.
+    #[tokio::test]
+    async fn test_run_web_tools_in_async_context() {
+        let page = "<html><body><article><p>Async browsing works without a runtime of its own.</p></article></body></html>";
+        let results = r#"{"results": [{"title": "Rust", "url": "https://www.rust-lang.org/", "content": "A language"}]}"#;
+        let base = serve(vec![
+            (
+                "/page",
+                format!("HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{page}", page.len()),
+            ),
+            (
+                "/search",
+                format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{results}", results.len()),
+            ),
+        ]);
+        let config = BuiltinConfig {
+            web_search: serde_json::from_value(json!({ "provider": "searxng", "endpoint": base }))
+                .unwrap(),
+            ..Default::default()
+        };
+        let hooks = Hooks::default();
+
+        let args = json!({ "url": format!("{base}/page"), "no_cache": true });
+        let value = run("web_browse", &args, &config, &hooks)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            value["content"],
+            "Async browsing works without a runtime of its own."
+        );
+
+        let value = run("web_search", &json!({ "query": "rust" }), &config, &hooks)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(value["provider"], "searxng");
+        assert_eq!(value["results"][0]["url"], "https://www.rust-lang.org/");
+    }
+
+    #[tokio::test]
+    async fn test_web_browse_min_chars() {
+        let text = "x".repeat(300);
+        let base = serve(vec![(
+            "/text",
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 300\r\nConnection: close\r\n\r\n{text}"),
+        )]);
+        let args = json!({ "url": format!("{base}/text"), "max_chars": 0, "no_cache": true });
+        let value = run(
+            "web_browse",
+            &args,
+            &BuiltinConfig::default(),
+            &Hooks::default(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(value["next_offset"], WEB_BROWSE_MIN_CHARS);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_page_redirects() {
+        let done = "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 4\r\nConnection: close\r\n\r\ndone";
+        let base = serve(vec![
+            ("/short", redirect("/middle")),
+            ("/middle", redirect("/final")),
+            ("/final", done.to_string()),
+            ("/loop", redirect("/loop")),
+            ("/gone", redirect("/missing")),
+        ]);
+        let fetch = |path: &str| {
+            let url = format!("{base}{path}");
+            async move {
+                fetch_page(&url, &json!({}), HtmlScope::Article)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let page = fetch("/short").await.unwrap();
+        assert_eq!(page.final_url, format!("{base}/final"));
+        assert_eq!(page.status, 200);
+        assert_eq!(page.content, "done");
+
+        let err = fetch("/loop").await.unwrap_err();
+        assert_eq!(err["too_many_redirects"], true);
+
+        let err = fetch("/gone").await.unwrap_err();
+        assert_eq!(err["status"], 404);
+        assert_eq!(err["final_url"], format!("{base}/missing"));
+    }
+
+    #[test]
+    fn test_body_encoding() {
+        assert_eq!(
+            charset_param("text/html; charset=Shift_JIS"),
+            Some("Shift_JIS")
+        );
+        assert_eq!(charset_param("text/html;Charset=\"utf-8\""), Some("utf-8"));
+        assert_eq!(charset_param("text/html"), None);
+
+        let html = include_bytes!("fixtures/shift_jis.html");
+        let encoding = body_encoding(html, None, BodyKind::Html);
+        assert_eq!(encoding.name(), "Shift_JIS");
+        let (title, content) = BodyKind::Html
+            .convert(html, encoding, &HtmlScope::FullPage)
+            .unwrap();
+        assert_eq!(title.as_deref(), Some("日本語のページ"));
+        assert!(content.contains("# こんにちは"));
+        assert!(content.contains("これはシフトJISで書かれたページです。"));
+
+        let latin1 = b"<p>Caf\xe9 cr\xe8me</p>";
+        let encoding = body_encoding(latin1, Some("ISO-8859-1"), BodyKind::Html);
+        assert_eq!(encoding.name(), "windows-1252");
+        let (_, content) = BodyKind::Html
+            .convert(latin1, encoding, &HtmlScope::FullPage)
+            .unwrap();
+        assert!(content.contains("Café crème"));
+
+        assert_eq!(body_encoding(b"plain", None, BodyKind::Text), UTF_8);
+        assert_eq!(
+            body_encoding(b"<meta charset=gbk>", None, BodyKind::Text),
+            UTF_8
+        );
+        assert_eq!(
+            body_encoding(
+                b"\xef\xbb\xbf<meta charset=gbk>",
+                Some("latin1"),
+                BodyKind::Html
+            ),
+            UTF_8
+        );
+    }
+
+    #[test]
+    fn test_legacy_encodings() {
+        let decode = |body: &[u8], charset| {
+            let encoding = body_encoding(body, charset, BodyKind::Html);
+            let (_, content) = BodyKind::Html
+                .convert(body, encoding, &HtmlScope::FullPage)
+                .unwrap();
+            (encoding.name(), content)
+        };
+
+        let cyrillic = b"<html><body><p>\xcf\xf0\xe8\xe2\xe5\xf2</p></body></html>";
+        let (name, content) = decode(cyrillic, Some("windows-1251"));
+        assert_eq!(name, "windows-1251");
+        assert_eq!(content.trim(), "Привет");
+
+        let chinese = b"<html><head><meta http-equiv=\"content-type\" content=\"text/html; charset=gb2312\"></head><body><p>\xd6\xd0\xce\xc4\xcd\xf8\xd2\xb3</p></body></html>";
+        let (name, content) = decode(chinese, None);
+        assert_eq!(name, "GBK");
+        assert_eq!(content.trim(), "中文网页");
+
+        let (name, _) = decode(chinese, Some("windows-1251"));
+        assert_eq!(name, "windows-1251");
+    }
+
+    #[test]
+    fn test_page_cache() {
+        let page = |content: &str| {
+            Arc::new(Page {
+                final_url: "https://example.com".to_string(),
+                status: 200,
+                title: None,
+                kind: BodyKind::Text,
+                content_type: "text/plain".to_string(),
+                charset: Some("UTF-8"),
+                content: content.to_string(),
+                body_truncated: false,
+                links: vec![],
+            })
+        };
+        let ttl = Duration::from_secs(600);
+        let now = Instant::now();
+        let mut cache = PageCache::default();
+        assert!(cache.get("a", ttl, now).is_none());
+
+        cache.insert("a".into(), page("A"), 2, now);
+        cache.insert("b".into(), page("B"), 2, now);
+        assert_eq!(cache.get("a", ttl, now).unwrap().content, "A");
+        cache.insert("c".into(), page("C"), 2, now);
+        assert!(cache.get("b", ttl, now).is_none());
+        assert!(cache.get("a", ttl, now).is_some());
+
+        let later = now + Duration::from_secs(599);
+        assert!(cache.get("c", ttl, later).is_some());
+        let expired = now + ttl;
+        assert!(cache.get("c", ttl, expired).is_none());
+        assert!(cache.get("c", ttl, now).is_none());
+
+        let value = page("0123456789").to_value("https://example.com", 2, 5, true);
+        assert_eq!(value["cached"], true);
+        assert_eq!(value["total_chars"], 10);
+        assert_eq!(value["next_offset"], 7);
+        assert!(value["content"].as_str().unwrap().starts_with("23456"));
+    }
+
+    #[test]
+    fn test_truncate_markdown() {
+        let mut content = "short".to_string();
+        assert_eq!(truncate_markdown(&mut content, 10), None);
+        assert_eq!(content, "short");
+
+        let mut content = "héllo wörld".to_string();
+        assert_eq!(truncate_markdown(&mut content, 2), Some(1));
+        assert_eq!(content, "h");
+        let mut content = "日本語のテキスト".to_string();
+        assert_eq!(truncate_markdown(&mut content, 7), Some(6));
+        assert_eq!(content, "日本");
+
+        let mut content = "line one\nline two\nline three".to_string();
+        truncate_markdown(&mut content, 20);
+        assert_eq!(content, "line one\nline two\n");
+
+        let text = "text ".repeat(10);
+        let mut content = format!("{text}\n```rust\nfn main() {{}}\n```\n");
+        truncate_markdown(&mut content, 60);
+        assert_eq!(content, format!("{text}\n"));
+
+        let mut content = format!("intro\n```\n{}```\n", "code line\n".repeat(10));
+        assert_eq!(truncate_markdown(&mut content, 60), Some(60));
+        assert!(content.ends_with("code line\n```\n"));
+        assert_eq!(content.matches("```").count(), 2);
+    }
+
+    #[test]
+    fn test_page_content() {
+        let content = "αβγδε\n".repeat(10);
+        assert_eq!(page_content(&content, 0, 100), (content.clone(), None));
+
+        let (page, next) = page_content(&content, 0, 20);
+        assert_eq!(next, Some(18));
+        assert_eq!(
+            page,
+            format!(
+                "{}\n...[truncated at character 18 of 60; pass offset 18 for more]",
+                "αβγδε\n".repeat(3)
+            )
+        );
+        let (page, next) = page_content(&content, 54, 20);
+        assert_eq!((page.as_str(), next), ("αβγδε\n", None));
+        assert_eq!(page_content(&content, 100, 20), (String::new(), None));
+    }
+
+    #[test]
+    fn test_parse_json_providers() {
+        let searxng = json!({
+            "query": "rust",
+            "results": [
+                { "title": "Rust", "url": "https://www.rust-lang.org/", "content": "A language", "engine": "google" },
+                { "title": "No url", "content": "skipped" }
+            ]
+        });
+        assert_eq!(
+            parse_searxng(&searxng),
+            vec![SearchResult::new(
+                "Rust",
+                "https://www.rust-lang.org/",
+                "A language"
+            )]
+        );
+
+        let brave = json!({
+            "type": "search",
+            "web": {
+                "results": [
+                    { "title": "Rust", "url": "https://www.rust-lang.org/", "description": "A language" }
+                ]
+            }
+        });
+        assert_eq!(
+            parse_brave(&brave),
+            vec![SearchResult::new(
+                "Rust",
+                "https://www.rust-lang.org/",
+                "A language"
+            )]
+        );
+
+        let tavily = json!({
+            "query": "rust",
+            "results": [
+                { "title": "Rust", "url": "https://www.rust-lang.org/", "content": "A language", "score": 0.9 }
+            ]
+        });
+        assert_eq!(
+            parse_tavily(&tavily),
+            vec![SearchResult::new(
+                "Rust",
+                "https://www.rust-lang.org/",
+                "A language"
+            )]
+        );
+
+        assert!(parse_brave(&json!({ "type": "search" })).is_empty());
+    }
+}
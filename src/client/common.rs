@@ -3,7 +3,7 @@ use super::*;
 use crate::{
     config::{Config, GlobalConfig, Input},
     function::{eval_tool_calls, FunctionDeclaration, ToolCall, ToolResult},
-    render::render_stream,
+    render::{render_plain_stream, render_stream},
     utils::*,
 };
 
@@ -16,7 +16,10 @@ use inquire::{
 use reqwest::{Client as ReqwestClient, RequestBuilder};
 use serde::Deserialize;
 use serde_json::{json, Value};
-use std::sync::LazyLock;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    LazyLock,
+};
 use std::time::Duration;
 use tokio::sync::mpsc::unbounded_channel;
 
@@ -432,7 +435,10 @@ pub async fn call_chat_completions(
                     client.global_config().read().print_markdown(&text)?;
                 }
             }
-            Ok((text, eval_tool_calls(client.global_config(), tool_calls)?))
+            Ok((
+                text,
+                eval_tool_calls(client.global_config(), tool_calls, None).await?,
+            ))
         }
         Err(err) => Err(err),
     }
@@ -463,7 +469,9 @@ pub async fn call_chat_completions_streaming(
             if !text.is_empty() && !text.ends_with('\n') {
                 println!();
             }
-            Ok((text, eval_tool_calls(client.global_config(), tool_calls)?))
+            let tool_results =
+                eval_tool_calls_streaming(client.global_config(), tool_calls, abort_signal).await?;
+            Ok((text, tool_results))
         }
         Err(err) => {
             if !text.is_empty() {
@@ -474,6 +482,43 @@ pub async fn call_chat_completions_streaming(
     }
 }
 
+/// Evaluates tool calls while streaming the output of long-running builtins, such as
+/// `command_run`, to the terminal as a fenced block.
+async fn eval_tool_calls_streaming(
+    config: &GlobalConfig,
+    tool_calls: Vec<ToolCall>,
+    abort_signal: AbortSignal,
+) -> Result<Vec<ToolResult>> {
+    if tool_calls.is_empty() {
+        return Ok(vec![]);
+    }
+    let (tx, rx) = unbounded_channel();
+    let handler = parking_lot::Mutex::new(SseHandler::new(tx, abort_signal.clone()));
+    let eval_config = config.clone();
+    let runtime = tokio::runtime::Handle::current();
+    // Evaluate off the async workers, so a blocking tool cannot stall the render below.
+    let eval = tokio::task::spawn_blocking(move || {
+        let fenced = AtomicBool::new(false);
+        let progress = |line: &str| {
+            let mut handler = handler.lock();
+            if !fenced.swap(true, Ordering::SeqCst) {
+                let _ = handler.text("```\n");
+            }
+            let _ = handler.text(line);
+        };
+        let ret = runtime.block_on(eval_tool_calls(&eval_config, tool_calls, Some(&progress)));
+        let mut handler = handler.lock();
+        if fenced.load(Ordering::SeqCst) {
+            let _ = handler.text("```\n");
+        }
+        handler.done();
+        ret
+    });
+    let (eval_ret, render_ret) = tokio::join!(eval, render_plain_stream(rx, abort_signal));
+    render_ret?;
+    eval_ret?
+}
+
 pub fn noop_prepare_embeddings<T>(_client: &T, _data: &EmbeddingsData) -> Result<RequestData> {
     bail!("The client doesn't support embeddings api")
 }
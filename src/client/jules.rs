@@ -79,7 +79,11 @@ impl Client for JulesClient {
         let api_base = self.get_api_base().unwrap_or_else(|_| API_BASE.to_string());
         let source = self.get_source()
             .map_err(|_| anyhow!("Missing 'source' in jules config. Please set it in config.yaml like `source: sources/github/owner/repo`."))?;
-        let starting_branch = self.get_starting_branch().unwrap_or_else(|_| "main".to_string());
+        let starting_branch = self
+            .get_starting_branch()
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+            .unwrap_or_else(|| "main".to_string());
 
         // Determine if we reuse a session or create a new one
         let session_key = input
@@ -168,8 +172,16 @@ impl Client for JulesClient {
                 .send()
                 .await?;
 
-            if !res.status().is_success() {
-                continue; // Retry?
+            let status = res.status();
+            if !status.is_success() {
+                // Rate limits and server errors may pass; anything else will not.
+                if status.as_u16() == 429 || status.is_server_error() {
+                    continue;
+                }
+                let text = res.text().await.unwrap_or_default();
+                let reason = format!("failed to poll activities ({status}): {text}");
+                handler.text(&format!("\n[Session failed: {reason}]\n"))?;
+                bail!("Jules session {session_id} {reason}");
             }
 
             let data: Value = res.json().await?;
@@ -218,6 +230,11 @@ impl Client for JulesClient {
                          }
                     }
 
+                    if let Some(reason) = session_failure(activity) {
+                        handler.text(&format!("\n[Session failed: {reason}]\n"))?;
+                        bail!("Jules session {session_id} failed: {reason}");
+                    }
+
                     if activity.get("sessionCompleted").is_some() {
                         handler.done();
                         return Ok(());
@@ -232,3 +249,36 @@ impl Client for JulesClient {
         Ok(())
     }
 }
+
+/// The reason a session failed, when `activity` reports its failure.
+fn session_failure(activity: &Value) -> Option<String> {
+    let failure = activity
+        .get("sessionFailed")
+        .or_else(|| activity.get("error"))?;
+    let reason = failure["reason"]
+        .as_str()
+        .or_else(|| failure["message"].as_str())
+        .or_else(|| failure.as_str())
+        .filter(|v| !v.trim().is_empty())
+        .unwrap_or("no reason given");
+    Some(reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_failure() {
+        let activity = json!({ "sessionFailed": { "reason": "Could not clone the repository" } });
+        assert_eq!(
+            session_failure(&activity).as_deref(),
+            Some("Could not clone the repository")
+        );
+        let activity = json!({ "error": { "code": 500, "message": "Internal error" } });
+        assert_eq!(session_failure(&activity).as_deref(), Some("Internal error"));
+        let activity = json!({ "sessionFailed": {} });
+        assert_eq!(session_failure(&activity).as_deref(), Some("no reason given"));
+        assert_eq!(session_failure(&json!({ "sessionCompleted": {} })), None);
+    }
+}
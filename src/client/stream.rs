@@ -212,10 +212,8 @@ impl JsonStreamParser {
                     }
                     self.balances.push(ch);
                 }
-                '[' => {
-                    if self.start.is_some() {
-                        self.balances.push(ch);
-                    }
+                '[' if self.start.is_some() => {
+                    self.balances.push(ch);
                 }
                 '}' => {
                     self.balances.pop();
@@ -300,9 +298,13 @@ mod tests {
         let abort_signal = crate::utils::create_abort_signal();
         let mut handler = SseHandler::new(sender, abort_signal);
 
-        let tool_call = ToolCall::new("test_tool".into(), serde_json::json!({"arg": 1}), Some("id1".into()));
+        let tool_call = ToolCall::new(
+            "test_tool".into(),
+            serde_json::json!({"arg": 1}),
+            Some("id1".into()),
+        );
         handler.tool_call(tool_call.clone()).unwrap();
-        assert_eq!(handler.tool_calls(), &[tool_call.clone()]);
+        assert_eq!(handler.tool_calls(), std::slice::from_ref(&tool_call));
 
         handler.text("hello").unwrap();
         assert_eq!(handler.buffer, "hello");
@@ -10,6 +10,7 @@ pub use self::role::{
 };
 use self::session::Session;
 
+use crate::builtin::{self, BuiltinConfig};
 use crate::client::{
     create_client_config, list_client_types, list_models, ClientConfig, MessageContentToolCalls,
     Model, ModelType, ProviderModels, OPENAI_COMPATIBLE_PROVIDERS,
@@ -116,6 +117,7 @@ pub struct Config {
     pub function_calling: bool,
     pub mapping_tools: IndexMap<String, String>,
     pub use_tools: Option<String>,
+    pub builtin_functions: BuiltinConfig,
 
     pub repl_prelude: Option<String>,
     pub cmd_prelude: Option<String>,
@@ -165,6 +167,8 @@ pub struct Config {
     #[serde(skip)]
     pub working_mode: WorkingMode,
     #[serde(skip)]
+    pub allow_all_tools: bool,
+    #[serde(skip)]
     pub last_message: Option<LastMessage>,
 
     #[serde(skip)]
@@ -195,6 +199,7 @@ impl Default for Config {
             function_calling: true,
             mapping_tools: Default::default(),
             use_tools: None,
+            builtin_functions: Default::default(),
 
             repl_prelude: None,
             cmd_prelude: None,
@@ -236,6 +241,7 @@ impl Default for Config {
             model: Default::default(),
             functions: Default::default(),
             working_mode: WorkingMode::Cmd,
+            allow_all_tools: false,
             last_message: None,
 
             role: None,
@@ -1695,7 +1701,9 @@ impl Config {
                     .declarations()
                     .iter()
                     .filter_map(|v| {
-                        if tool_names.contains(&v.name) {
+                        if tool_names.contains(&v.name)
+                            && builtin::enabled(&v.name, &self.builtin_functions)
+                        {
                             Some(v.clone())
                         } else {
                             None
@@ -2776,7 +2784,9 @@ where
 
 fn run_hook(command: &str, envs: &HashMap<&str, String>) -> Result<()> {
     let mut parts = command.split_whitespace();
-    let cmd = parts.next().ok_or_else(|| anyhow!("Invalid hook command"))?;
+    let cmd = parts
+        .next()
+        .ok_or_else(|| anyhow!("Invalid hook command"))?;
     let args: Vec<&str> = parts.collect();
     let mut child = process::Command::new(cmd)
         .args(args)
@@ -6,11 +6,13 @@ use crate::{
 
 use anyhow::{anyhow, bail, Context, Result};
 use indexmap::IndexMap;
+use inquire::Text;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::{
     collections::{HashMap, HashSet},
     fs,
+    io::IsTerminal,
     path::{Path, PathBuf},
 };
 
@@ -19,7 +21,11 @@ const PATH_SEP: &str = ";";
 #[cfg(not(windows))]
 const PATH_SEP: &str = ":";
 
-pub fn eval_tool_calls(config: &GlobalConfig, mut calls: Vec<ToolCall>) -> Result<Vec<ToolResult>> {
+pub async fn eval_tool_calls(
+    config: &GlobalConfig,
+    mut calls: Vec<ToolCall>,
+    progress: Option<builtin::Progress<'_>>,
+) -> Result<Vec<ToolResult>> {
     let mut output = vec![];
     if calls.is_empty() {
         return Ok(output);
@@ -30,7 +36,7 @@ pub fn eval_tool_calls(config: &GlobalConfig, mut calls: Vec<ToolCall>) -> Resul
     }
     let mut is_all_null = true;
     for call in calls {
-        let mut result = call.eval(config)?;
+        let mut result = call.eval(config, progress).await?;
         if result.is_null() {
             result = json!("DONE");
         } else {
@@ -172,14 +178,41 @@ impl ToolCall {
         }
     }
 
-    pub fn eval(&self, config: &GlobalConfig) -> Result<Value> {
+    pub async fn eval(
+        &self,
+        config: &GlobalConfig,
+        progress: Option<builtin::Progress<'_>>,
+    ) -> Result<Value> {
         let mut arguments = self.arguments.clone();
         if let Some(arguments_str) = arguments.as_str() {
             if let Ok(v) = serde_json::from_str(arguments_str) {
                 arguments = v;
             }
         }
-        if let Some(output) = builtin::run(&self.name, &arguments)? {
+        let builtin_config = config.read().builtin_functions.clone();
+        let interactive = *IS_STDOUT_TERMINAL
+            && std::io::stdin().is_terminal()
+            && !config.read().working_mode.is_serve();
+        let approve = |name: &str, args: &Value| {
+            let mut allow_all = config.read().allow_all_tools;
+            let approved = builtin::confirm_call(
+                name,
+                args,
+                &builtin_config,
+                interactive,
+                &mut allow_all,
+                ask_confirmation,
+            )?;
+            if allow_all {
+                config.write().allow_all_tools = true;
+            }
+            Ok(approved)
+        };
+        let hooks = builtin::Hooks {
+            progress: progress.filter(|_| builtin_config.stream_command_output),
+            approve: Some(&approve),
+        };
+        if let Some(output) = builtin::run(&self.name, &arguments, &builtin_config, &hooks).await? {
             if *IS_STDOUT_TERMINAL {
                 let prompt = format!("Call builtin {} {}", self.name, arguments);
                 println!("{}", dimmed_text(&prompt));
@@ -261,6 +294,19 @@ impl ToolCall {
     }
 }
 
+fn ask_confirmation(description: &str) -> Result<builtin::Confirmation> {
+    println!("{description}");
+    let ans = Text::new("Allow this call? [y/N/a]")
+        .with_help_message("a: allow all tool calls for the rest of the session")
+        .prompt()?;
+    let confirmation = match ans.trim().to_lowercase().as_str() {
+        "y" | "yes" => builtin::Confirmation::Yes,
+        "a" | "all" => builtin::Confirmation::All,
+        _ => builtin::Confirmation::No,
+    };
+    Ok(confirmation)
+}
+
 pub fn run_llm_function(
     cmd_name: String,
     cmd_args: Vec<String>,
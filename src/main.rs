@@ -54,7 +54,21 @@ async fn main() -> Result<()> {
         || cli.list_sessions;
     setup_logger(working_mode.is_serve())?;
     let config = Arc::new(RwLock::new(Config::init(working_mode, info_flag).await?));
-    if let Err(err) = run(config, cli, text).await {
+    if working_mode.is_cmd() {
+        // Background jobs run in process groups of their own, out of reach of the terminal's
+        // Ctrl+C, so kill them before exiting on it.
+        tokio::spawn(async {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                builtin::kill_jobs();
+                process::exit(130);
+            }
+        });
+    }
+    let ret = {
+        let _jobs = builtin::JobsGuard;
+        run(config, cli, text).await
+    };
+    if let Err(err) = ret {
         render_error(err);
         std::process::exit(1);
     }
@@ -281,6 +295,7 @@ async fn shell_execute(
                     if code == 0 && config.read().save_shell_history {
                         let _ = append_to_shell_history(&shell.name, &eval_str, code);
                     }
+                    builtin::kill_jobs();
                     process::exit(code);
                 }
                 'r' => {
@@ -2,7 +2,7 @@ mod markdown;
 mod stream;
 
 pub use self::markdown::{MarkdownRender, RenderOptions};
-use self::stream::{markdown_stream, raw_stream};
+use self::stream::{markdown_stream, plain_stream, raw_stream};
 
 use crate::utils::{error_text, pretty_error, AbortSignal, IS_STDOUT_TERMINAL};
 use crate::{client::SseEvent, config::GlobalConfig};
@@ -25,6 +25,17 @@ pub async fn render_stream(
     ret.map_err(|err| err.context("Failed to reader stream"))
 }
 
+/// Prints streamed text as-is, without a spinner or raw mode, so it can interleave with
+/// other output such as tool call notices.
+pub async fn render_plain_stream(
+    rx: UnboundedReceiver<SseEvent>,
+    abort_signal: AbortSignal,
+) -> Result<()> {
+    plain_stream(rx, &abort_signal)
+        .await
+        .map_err(|err| err.context("Failed to reader stream"))
+}
+
 pub fn render_error(err: anyhow::Error) {
     eprintln!("{}", error_text(&pretty_error(&err)));
 }
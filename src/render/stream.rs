@@ -64,6 +64,25 @@ pub async fn raw_stream(
     Ok(())
 }
 
+pub async fn plain_stream(
+    mut rx: UnboundedReceiver<SseEvent>,
+    abort_signal: &AbortSignal,
+) -> Result<()> {
+    while let Some(evt) = rx.recv().await {
+        if abort_signal.aborted() {
+            break;
+        }
+        match evt {
+            SseEvent::Text(text) => {
+                print!("{text}");
+                stdout().flush()?;
+            }
+            SseEvent::Done => break,
+        }
+    }
+    Ok(())
+}
+
 async fn markdown_stream_inner(
     mut rx: UnboundedReceiver<SseEvent>,
     render: &mut MarkdownRender,
@@ -0,0 +1,543 @@
+use anyhow::{anyhow, bail, Result};
+
+/// How many leading/trailing context lines may be ignored when a hunk does not apply cleanly.
+const MAX_FUZZ: usize = 2;
+/// Lines of unchanged context shown around each change by `unified_diff`.
+const DIFF_CONTEXT: usize = 3;
+/// The most cells `diff_lines` spends on a longest-common-subsequence table.
+const DIFF_MAX_CELLS: usize = 4_000_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilePatch {
+    pub old_path: Option<String>,
+    pub new_path: Option<String>,
+    pub hunks: Vec<Hunk>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub new_start: usize,
+    pub lines: Vec<HunkLine>,
+    pub old_no_newline: bool,
+    pub new_no_newline: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HunkLine {
+    Context(String),
+    Remove(String),
+    Add(String),
+}
+
+impl Hunk {
+    fn old_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|v| match v {
+                HunkLine::Context(v) | HunkLine::Remove(v) => Some(v.as_str()),
+                HunkLine::Add(_) => None,
+            })
+            .collect()
+    }
+
+    fn new_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|v| match v {
+                HunkLine::Context(v) | HunkLine::Add(v) => Some(v.as_str()),
+                HunkLine::Remove(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// The outcome of applying a single hunk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HunkResult {
+    /// Applied at the given 1-based line of the original file.
+    Applied {
+        line: usize,
+        fuzz: usize,
+    },
+    Rejected {
+        old_start: usize,
+        reason: String,
+    },
+}
+
+/// Parses a unified diff that may span several files.
+pub fn parse_unified_diff(diff: &str) -> Result<Vec<FilePatch>> {
+    let mut patches: Vec<FilePatch> = vec![];
+    let mut lines = diff.lines().peekable();
+    while let Some(line) = lines.next() {
+        if let Some(old_path) = line.strip_prefix("--- ") {
+            let new_path = lines
+                .next()
+                .and_then(|v| v.strip_prefix("+++ "))
+                .ok_or_else(|| anyhow!("Expected '+++' after '{line}'"))?;
+            patches.push(FilePatch {
+                old_path: parse_diff_path(old_path),
+                new_path: parse_diff_path(new_path),
+                hunks: vec![],
+            });
+        } else if line.starts_with("@@") {
+            let patch = patches
+                .last_mut()
+                .ok_or_else(|| anyhow!("Hunk without file header: '{line}'"))?;
+            let (old_start, mut old_count, new_start, mut new_count) = parse_hunk_header(line)?;
+            let mut hunk = Hunk {
+                old_start,
+                new_start,
+                lines: vec![],
+                old_no_newline: false,
+                new_no_newline: false,
+            };
+            while old_count > 0 || new_count > 0 {
+                let Some(line) = lines.next() else {
+                    bail!("Unexpected end of hunk starting at line {old_start}");
+                };
+                if let Some(text) = line.strip_prefix('+') {
+                    hunk.lines.push(HunkLine::Add(text.to_string()));
+                    new_count = new_count.saturating_sub(1);
+                } else if let Some(text) = line.strip_prefix('-') {
+                    hunk.lines.push(HunkLine::Remove(text.to_string()));
+                    old_count = old_count.saturating_sub(1);
+                } else if line.starts_with('\\') {
+                    mark_no_newline(&mut hunk);
+                } else {
+                    let text = line.strip_prefix(' ').unwrap_or(line);
+                    hunk.lines.push(HunkLine::Context(text.to_string()));
+                    old_count = old_count.saturating_sub(1);
+                    new_count = new_count.saturating_sub(1);
+                }
+            }
+            if lines.next_if(|v| v.starts_with('\\')).is_some() {
+                mark_no_newline(&mut hunk);
+            }
+            patch.hunks.push(hunk);
+        }
+    }
+    if patches.is_empty() {
+        bail!("No file headers found in the diff");
+    }
+    Ok(patches)
+}
+
+/// Records a `\ No newline at end of file` marker against the line it follows: the old
+/// side after a removed line, the new side after an added one and both after context.
+fn mark_no_newline(hunk: &mut Hunk) {
+    match hunk.lines.last() {
+        Some(HunkLine::Remove(_)) => hunk.old_no_newline = true,
+        Some(HunkLine::Add(_)) => hunk.new_no_newline = true,
+        _ => {
+            hunk.old_no_newline = true;
+            hunk.new_no_newline = true;
+        }
+    }
+}
+
+/// Applies the hunks of `patch` to `content`. Returns the new content when every hunk
+/// applied, together with the result of each hunk.
+pub fn apply_file_patch(content: &str, patch: &FilePatch) -> (Option<String>, Vec<HunkResult>) {
+    let newline = if content.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let mut trailing_newline = content.is_empty() || content.ends_with('\n');
+    let mut lines: Vec<String> = content.lines().map(|v| v.to_string()).collect();
+    let mut results = vec![];
+    let mut offset: isize = 0;
+    let mut failed = false;
+    for hunk in &patch.hunks {
+        match locate_hunk(&lines, hunk, offset) {
+            Some((start, fuzz)) => {
+                let old_lines = hunk.old_lines();
+                let new_lines = hunk.new_lines();
+                let (old_lines, new_lines) = (
+                    &old_lines[fuzz..old_lines.len() - fuzz],
+                    &new_lines[fuzz..new_lines.len() - fuzz],
+                );
+                lines.splice(
+                    start..start + old_lines.len(),
+                    new_lines.iter().map(|v| v.to_string()),
+                );
+                offset += new_lines.len() as isize - old_lines.len() as isize;
+                if hunk.new_no_newline {
+                    trailing_newline = false;
+                } else if hunk.old_no_newline {
+                    trailing_newline = true;
+                }
+                results.push(HunkResult::Applied {
+                    line: start + 1,
+                    fuzz,
+                });
+            }
+            None => {
+                failed = true;
+                results.push(HunkResult::Rejected {
+                    old_start: hunk.old_start,
+                    reason: "Context lines do not match the file".into(),
+                });
+            }
+        }
+    }
+    if failed {
+        return (None, results);
+    }
+    let mut output = lines.join(newline);
+    if trailing_newline && !lines.is_empty() {
+        output.push_str(newline);
+    }
+    (Some(output), results)
+}
+
+/// Finds where a hunk applies, trying the expected position first and then moving
+/// outwards, and finally ignoring up to `MAX_FUZZ` context lines at each end.
+fn locate_hunk(lines: &[String], hunk: &Hunk, offset: isize) -> Option<(usize, usize)> {
+    let old_lines = hunk.old_lines();
+    // A hunk without old lines (`-N,0`) inserts after line N rather than at it.
+    let anchor = match old_lines.is_empty() {
+        true => hunk.old_start,
+        false => hunk.old_start.max(1) - 1,
+    };
+    let expected = (anchor as isize + offset).max(0) as usize;
+    let leading = hunk
+        .lines
+        .iter()
+        .take_while(|v| matches!(v, HunkLine::Context(_)))
+        .count();
+    let trailing = hunk
+        .lines
+        .iter()
+        .rev()
+        .take_while(|v| matches!(v, HunkLine::Context(_)))
+        .count();
+    let max_fuzz = leading.min(trailing).min(MAX_FUZZ);
+    for fuzz in 0..=max_fuzz {
+        let block = &old_lines[fuzz..old_lines.len() - fuzz];
+        let expected = expected + fuzz;
+        if block.len() > lines.len() {
+            continue;
+        }
+        let last = lines.len() - block.len();
+        let matches_at = |start: usize| {
+            lines[start..start + block.len()]
+                .iter()
+                .zip(block)
+                .all(|(a, b)| a == b)
+        };
+        for distance in 0..=last.max(expected) {
+            let before = expected.checked_sub(distance).filter(|&v| v <= last);
+            let after = Some(expected + distance).filter(|&v| v <= last && distance > 0);
+            for start in [before, after].into_iter().flatten() {
+                if matches_at(start) {
+                    return Some((start, fuzz));
+                }
+            }
+        }
+    }
+    None
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DiffOp {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Renders a unified diff between `old` and `new`, or an empty string when they are equal.
+pub fn unified_diff(old: &str, new: &str, old_path: &str, new_path: &str) -> String {
+    let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.split_inclusive('\n').collect();
+    let ops = diff_lines(&old_lines, &new_lines);
+    if ops.iter().all(|(op, _)| *op == DiffOp::Equal) {
+        return String::new();
+    }
+
+    // Position of each op in the old and new files.
+    let mut positions = Vec::with_capacity(ops.len());
+    let (mut old_index, mut new_index) = (0, 0);
+    for (op, _) in &ops {
+        positions.push((old_index, new_index));
+        match op {
+            DiffOp::Equal => {
+                old_index += 1;
+                new_index += 1;
+            }
+            DiffOp::Delete => old_index += 1,
+            DiffOp::Insert => new_index += 1,
+        }
+    }
+
+    let mut groups: Vec<(usize, usize)> = vec![];
+    for (i, (op, _)) in ops.iter().enumerate() {
+        if *op == DiffOp::Equal {
+            continue;
+        }
+        let start = i.saturating_sub(DIFF_CONTEXT);
+        let end = (i + DIFF_CONTEXT + 1).min(ops.len());
+        match groups.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => groups.push((start, end)),
+        }
+    }
+
+    let mut output = format!("--- a/{old_path}\n+++ b/{new_path}\n");
+    for (start, end) in groups {
+        let slice = &ops[start..end];
+        let old_count = slice.iter().filter(|v| v.0 != DiffOp::Insert).count();
+        let new_count = slice.iter().filter(|v| v.0 != DiffOp::Delete).count();
+        let (old_start, new_start) = positions[start];
+        let old_start = if old_count == 0 {
+            old_start
+        } else {
+            old_start + 1
+        };
+        let new_start = if new_count == 0 {
+            new_start
+        } else {
+            new_start + 1
+        };
+        output.push_str(&format!(
+            "@@ -{old_start},{old_count} +{new_start},{new_count} @@\n"
+        ));
+        for (op, line) in slice {
+            let prefix = match op {
+                DiffOp::Equal => ' ',
+                DiffOp::Delete => '-',
+                DiffOp::Insert => '+',
+            };
+            output.push(prefix);
+            output.push_str(line);
+            if !line.ends_with('\n') {
+                output.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+    }
+    output
+}
+
+/// Computes a line diff. The common prefix and suffix are trimmed first, and the changed
+/// region between them is diffed with a longest-common-subsequence table unless that would
+/// need more than `DIFF_MAX_CELLS` cells, in which case it is reported as a plain replacement.
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(DiffOp, &'a str)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+
+    let mut ops: Vec<(DiffOp, &str)> = old[..prefix].iter().map(|v| (DiffOp::Equal, *v)).collect();
+    if (a.len() + 1).saturating_mul(b.len() + 1) > DIFF_MAX_CELLS {
+        ops.extend(a.iter().map(|v| (DiffOp::Delete, *v)));
+        ops.extend(b.iter().map(|v| (DiffOp::Insert, *v)));
+    } else {
+        ops.extend(lcs_diff(a, b));
+    }
+    ops.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .map(|v| (DiffOp::Equal, *v)),
+    );
+    ops
+}
+
+fn lcs_diff<'a>(a: &[&'a str], b: &[&'a str]) -> Vec<(DiffOp, &'a str)> {
+    let width = b.len() + 1;
+    let mut table = vec![0usize; (a.len() + 1) * width];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            table[i * width + j] = if a[i] == b[j] {
+                table[(i + 1) * width + j + 1] + 1
+            } else {
+                table[(i + 1) * width + j].max(table[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut ops = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            ops.push((DiffOp::Equal, a[i]));
+            i += 1;
+            j += 1;
+        } else if table[(i + 1) * width + j] >= table[i * width + j + 1] {
+            ops.push((DiffOp::Delete, a[i]));
+            i += 1;
+        } else {
+            ops.push((DiffOp::Insert, b[j]));
+            j += 1;
+        }
+    }
+    ops.extend(a[i..].iter().map(|v| (DiffOp::Delete, *v)));
+    ops.extend(b[j..].iter().map(|v| (DiffOp::Insert, *v)));
+    ops
+}
+
+fn parse_diff_path(value: &str) -> Option<String> {
+    let path = value.split('\t').next().unwrap_or(value).trim();
+    if path == "/dev/null" {
+        return None;
+    }
+    let path = path
+        .strip_prefix("a/")
+        .or_else(|| path.strip_prefix("b/"))
+        .unwrap_or(path);
+    Some(path.to_string())
+}
+
+fn parse_hunk_header(line: &str) -> Result<(usize, usize, usize, usize)> {
+    let err = || anyhow!("Invalid hunk header: '{line}'");
+    let body = line
+        .strip_prefix("@@ ")
+        .and_then(|v| v.split(" @@").next())
+        .ok_or_else(err)?;
+    let (old, new) = body.split_once(' ').ok_or_else(err)?;
+    let parse_range = |value: &str| -> Result<(usize, usize)> {
+        let (start, count) = value.split_once(',').unwrap_or((value, "1"));
+        Ok((
+            start.parse().map_err(|_| err())?,
+            count.parse().map_err(|_| err())?,
+        ))
+    };
+    let (old_start, old_count) = parse_range(old.strip_prefix('-').ok_or_else(err)?)?;
+    let (new_start, new_count) = parse_range(new.strip_prefix('+').ok_or_else(err)?)?;
+    Ok((old_start, old_count, new_start, new_count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_unified_diff() {
+        let diff = "\
+diff --git a/src/a.rs b/src/a.rs
+--- a/src/a.rs
++++ b/src/a.rs
+@@ -1,3 +1,3 @@
+ one
+-two
++TWO
+ three
+--- /dev/null
++++ b/new.txt
+@@ -0,0 +1 @@
++hello
+\\ No newline at end of file
+";
+        let patches = parse_unified_diff(diff).unwrap();
+        assert_eq!(patches.len(), 2);
+        assert_eq!(patches[0].old_path.as_deref(), Some("src/a.rs"));
+        assert_eq!(patches[0].hunks[0].old_start, 1);
+        assert_eq!(patches[0].hunks[0].lines.len(), 4);
+        assert_eq!(patches[1].old_path, None);
+        assert_eq!(patches[1].new_path.as_deref(), Some("new.txt"));
+        assert!(patches[1].hunks[0].new_no_newline);
+    }
+
+    #[test]
+    fn test_apply_file_patch() {
+        let diff = "--- a/f\n+++ b/f\n@@ -2,3 +2,3 @@\n b\n-c\n+C\n d\n";
+        let patch = &parse_unified_diff(diff).unwrap()[0];
+
+        let (output, results) = apply_file_patch("a\nb\nc\nd\ne\n", patch);
+        assert_eq!(output.as_deref(), Some("a\nb\nC\nd\ne\n"));
+        assert_eq!(results, [HunkResult::Applied { line: 2, fuzz: 0 }]);
+
+        let (output, results) = apply_file_patch("x\ny\na\nb\nc\nd\n", patch);
+        assert_eq!(output.as_deref(), Some("x\ny\na\nb\nC\nd\n"));
+        assert_eq!(results, [HunkResult::Applied { line: 4, fuzz: 0 }]);
+
+        let (output, results) = apply_file_patch("a\nB\nc\nD\n", patch);
+        assert_eq!(output.as_deref(), Some("a\nB\nC\nD\n"));
+        assert_eq!(results, [HunkResult::Applied { line: 3, fuzz: 1 }]);
+
+        let (output, results) = apply_file_patch("a\nb\nz\nd\n", patch);
+        assert_eq!(output, None);
+        assert!(matches!(
+            results[0],
+            HunkResult::Rejected { old_start: 2, .. }
+        ));
+    }
+
+    #[test]
+    fn test_apply_file_patch_insertion() {
+        let diff = "--- a/f\n+++ b/f\n@@ -0,0 +1,2 @@\n+x\n+y\n";
+        let patch = &parse_unified_diff(diff).unwrap()[0];
+        let (output, results) = apply_file_patch("a\nb\n", patch);
+        assert_eq!(output.as_deref(), Some("x\ny\na\nb\n"));
+        assert_eq!(results, [HunkResult::Applied { line: 1, fuzz: 0 }]);
+
+        let diff = "--- a/f\n+++ b/f\n@@ -2,0 +3 @@\n+x\n@@ -3,0 +5 @@\n+y\n";
+        let patch = &parse_unified_diff(diff).unwrap()[0];
+        let (output, results) = apply_file_patch("a\nb\nc\nd\n", patch);
+        assert_eq!(output.as_deref(), Some("a\nb\nx\nc\ny\nd\n"));
+        assert_eq!(results[0], HunkResult::Applied { line: 3, fuzz: 0 });
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        assert_eq!(unified_diff("a\nb\n", "a\nb\n", "f", "f"), "");
+
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n";
+        let new = "1\ntwo\n3\n4\n5\n6\n7\n8\n9\n10\n11";
+        let diff = unified_diff(old, new, "f", "f");
+        assert_eq!(
+            diff,
+            "--- a/f\n+++ b/f\n\
+             @@ -1,5 +1,5 @@\n 1\n-2\n+two\n 3\n 4\n 5\n\
+             @@ -8,3 +8,4 @@\n 8\n 9\n 10\n+11\n\\ No newline at end of file\n"
+        );
+
+        let patch = &parse_unified_diff(&diff).unwrap()[0];
+        let (output, _) = apply_file_patch(old, patch);
+        assert_eq!(output.as_deref(), Some(new));
+    }
+
+    #[test]
+    fn test_unified_diff_no_newline_round_trip() {
+        for (old, new) in [
+            ("a", "b\n"),
+            ("a\nb", "a\nc\n"),
+            ("a\n", "b"),
+            ("a\nb", "a\nc"),
+            ("a", "a\n"),
+            ("a\n", "a"),
+        ] {
+            let diff = unified_diff(old, new, "f", "f");
+            let patch = &parse_unified_diff(&diff).unwrap()[0];
+            let (output, _) = apply_file_patch(old, patch);
+            assert_eq!(output.as_deref(), Some(new), "{diff}");
+        }
+
+        let diff = "--- a/f\n+++ b/f\n@@ -1 +1 @@\n-a\n\\ No newline at end of file\n+b\n";
+        let hunk = &parse_unified_diff(diff).unwrap()[0].hunks[0];
+        assert!(hunk.old_no_newline);
+        assert!(!hunk.new_no_newline);
+    }
+
+    #[test]
+    fn test_unified_diff_large_change() {
+        let old: String = (0..3000).map(|i| format!("old {i}\nsame\n")).collect();
+        let new: String = (0..3000).map(|i| format!("new {i}\nsame\n")).collect();
+        let old = format!("head\n{old}tail\n");
+        let new = format!("head\n{new}tail\n");
+        let diff = unified_diff(&old, &new, "f", "f");
+        assert!(diff.starts_with("--- a/f\n+++ b/f\n@@ -1,6002 +1,6002 @@\n head\n-old 0\n"));
+        let removed = diff.lines().skip(3).filter(|v| v.starts_with('-'));
+        assert_eq!(removed.count(), 5999);
+
+        let patch = &parse_unified_diff(&diff).unwrap()[0];
+        let (output, _) = apply_file_patch(&old, patch);
+        assert_eq!(output.as_deref(), Some(new.as_str()));
+    }
+}
@@ -2,6 +2,7 @@ mod abort_signal;
 mod clipboard;
 mod command;
 mod crypto;
+mod diff;
 mod html_to_md;
 mod input;
 mod loader;
@@ -15,6 +16,7 @@ pub use self::abort_signal::*;
 pub use self::clipboard::set_text;
 pub use self::command::*;
 pub use self::crypto::*;
+pub use self::diff::*;
 pub use self::html_to_md::*;
 pub use self::input::*;
 pub use self::loader::*;
@@ -120,7 +122,7 @@ where
             Some((v, score))
         })
         .collect();
-    list.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+    list.sort_unstable_by_key(|v| std::cmp::Reverse(v.1));
     list.into_iter().map(|(v, _)| v).collect()
 }
 